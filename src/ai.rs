@@ -0,0 +1,324 @@
+use bevy::prelude::*;
+
+use crate::asset_loader::{tank_definitions_loaded, TankDefinitionAssets};
+use crate::map::SpawnPoints;
+use crate::map::navigation::NavGrid;
+use crate::network::is_authority;
+use crate::replay::not_replaying;
+use crate::damage::{Health, Wreck};
+use crate::physics::{wrap_angle, Position, Rotation};
+use crate::schedule::ScheduleSet;
+use crate::control::{ControlSource, TankControl};
+use crate::tank::{apply_tank_control, spawn_tank, SpawnTanks, Tank, Team};
+use crate::turret::Turret;
+
+/// Distance at which an AI tank notices an enemy.
+const DETECTION_RANGE: f32 = 600.0;
+
+/// Distance at which an AI tank starts shooting at an enemy.
+const ENGAGE_RANGE: f32 = 300.0;
+
+/// Distance an AI tank tries to keep from the enemy it is engaging.
+const PREFERRED_DISTANCE: f32 = 150.0;
+
+/// Distance at which a patrol point counts as reached.
+const WAYPOINT_RADIUS: f32 = 20.0;
+
+/// Distance at which a point of the planned path counts as reached.
+const PATH_POINT_RADIUS: f32 = 6.0;
+
+/// Distance the destination has to move away from the planned goal before the path is planned again.
+const REPLAN_DISTANCE: f32 = 40.0;
+
+/// Turning radius the AI plans its paths with. Sharper corners are driven by slowing down and turning on the spot.
+const PATH_TURN_RADIUS: f32 = 24.0;
+
+/// Height above the position of the target the AI aims at.
+const AIM_HEIGHT: f32 = 2.0;
+
+/// Maximum difference between the turret yaw and its target for the AI to fire.
+const FIRE_ANGLE_TOLERANCE: f32 = 0.05;
+
+/// The difficulty of the AI tanks.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    /// The difficulty with the given name, as used on the command line.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "easy" => Some(Difficulty::Easy),
+            "normal" => Some(Difficulty::Normal),
+            "hard" => Some(Difficulty::Hard),
+            _ => None,
+        }
+    }
+
+    /// Time in seconds between two decisions of an AI tank.
+    pub fn reaction_time(&self) -> f32 {
+        match self {
+            Difficulty::Easy => 1.2,
+            Difficulty::Normal => 0.6,
+            Difficulty::Hard => 0.25,
+        }
+    }
+
+    /// Maximum aim error in radians.
+    pub fn aim_error(&self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.08,
+            Difficulty::Normal => 0.04,
+            Difficulty::Hard => 0.015,
+        }
+    }
+
+    /// How willing the AI is to attack and stay in a fight, in the range `0.0..=1.0`.
+    pub fn aggression(&self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.3,
+            Difficulty::Normal => 0.6,
+            Difficulty::Hard => 0.9,
+        }
+    }
+}
+
+/// The states of the AI behavior state machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AiState {
+    /// Drive between the patrol points.
+    Patrol,
+    /// Drive towards a detected enemy.
+    Seek,
+    /// Keep distance to the enemy and shoot at it.
+    Engage,
+    /// Drive away from the enemy after taking too much damage.
+    Retreat,
+}
+
+/// Component for tanks controlled by the AI.
+#[derive(Component, Debug)]
+pub struct AiController {
+    pub state: AiState,
+    pub target: Option<Entity>,
+    pub patrol_points: Vec<Vec3>,
+    pub patrol_index: usize,
+    pub decision_timer: f32,
+    pub aim_offset: Vec3,
+    /// The remaining points of the planned path, the first one is driven to next.
+    pub path: Vec<Vec3>,
+    /// The destination the path was planned for and the revision of the navigation grid at that time.
+    planned: Option<(Vec3, u32)>,
+    rng: u32,
+}
+
+impl AiController {
+    pub fn new(patrol_points: Vec<Vec3>, seed: u32) -> Self {
+        AiController {
+            state: AiState::Patrol,
+            target: None,
+            patrol_points,
+            patrol_index: 0,
+            decision_timer: 0.0,
+            aim_offset: Vec3::ZERO,
+            path: Vec::new(),
+            planned: None,
+            rng: seed.max(1),
+        }
+    }
+
+    /// Random number in the range `-1.0..1.0`.
+    /// Uses a xorshift generator so the AI behaves the same for the same seed.
+    fn random(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        (self.rng as f32 / u32::MAX as f32) * 2.0 - 1.0
+    }
+
+    /// The point to drive to on the way to `destination`.
+    /// The path is planned again when the destination moved too far from the planned goal or the navigation grid changed.
+    /// Without a navigation grid or a path to the destination, the tank drives straight at it.
+    fn next_path_point(&mut self, destination: Vec3, position: Vec3, direction: Vec3, grid: Option<&NavGrid>) -> Vec3 {
+        let Some(grid) = grid else {
+            return destination;
+        };
+        let outdated = match self.planned {
+            Some((goal, revision)) => goal.distance(destination) > REPLAN_DISTANCE || revision != grid.revision,
+            None => true,
+        };
+        if outdated {
+            self.path = grid.find_path(position, direction, destination, PATH_TURN_RADIUS).unwrap_or_default();
+            self.planned = Some((destination, grid.revision));
+        }
+        while self.path.len() > 1 && Vec2::new(self.path[0].x - position.x, self.path[0].z - position.z).length() < PATH_POINT_RADIUS {
+            self.path.remove(0);
+        }
+        self.path.first().copied().unwrap_or(destination)
+    }
+}
+
+/// Plugin for AI controlled tanks, with the `difficulty` of all AI tanks.
+#[derive(Default)]
+pub struct AiPlugin {
+    pub difficulty: Difficulty,
+}
+
+impl Plugin for AiPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.difficulty)
+            .add_systems(Update, (
+                spawn_ai_tanks.in_set(SpawnTanks).run_if(resource_exists::<SpawnPoints>().and_then(tank_definitions_loaded).and_then(is_authority).and_then(not_replaying)),
+            ).in_set(ScheduleSet::UpdateWorld))
+            .add_systems(FixedUpdate, (
+                ai_decide,
+                ai_drive,
+            ).chain().before(apply_tank_control).in_set(ScheduleSet::Control));
+    }
+}
+
+/// System to spawn the AI tanks, one at every spawn point of team 1.
+/// This system runs once when both the spawn points and the tank definitions are available.
+/// The tank types alternate between all definitions in the `tanks` folder. Each tank patrols a square around its spawn point.
+fn spawn_ai_tanks(
+    mut commands: Commands,
+    mut spawned: Local<bool>,
+    definitions: Res<TankDefinitionAssets>,
+    spawn_points: Res<SpawnPoints>,
+) {
+    if *spawned {
+        return;
+    }
+    *spawned = true;
+    let types = &definitions.definitions;
+    for (i, spawn_point) in spawn_points.for_team(Team(1)).enumerate() {
+        let position = spawn_point.position;
+        let tank = spawn_tank(&mut commands, types[i % types.len()].clone(), Team(1), position, spawn_point.rotation);
+        let patrol_points = vec![
+            position + Vec3::new(80.0, 0.0, 80.0),
+            position + Vec3::new(-80.0, 0.0, 80.0),
+            position + Vec3::new(-80.0, 0.0, -80.0),
+            position + Vec3::new(80.0, 0.0, -80.0),
+        ];
+        commands.entity(tank).insert((
+            AiController::new(patrol_points, i as u32 + 1),
+            ControlSource::Ai,
+        ));
+    }
+}
+
+/// System to update the state of the AI tanks.
+/// Every AI tank makes a new decision after its reaction time, based on the closest enemy and its own health.
+#[allow(clippy::type_complexity)]
+fn ai_decide(
+    mut ai_query: Query<(Entity, &mut AiController, &Position, &Team, &Health), Without<Wreck>>,
+    enemy_query: Query<(Entity, &Position, &Team), (With<Tank>, Without<Wreck>)>,
+    difficulty: Res<Difficulty>,
+    time: Res<Time>,
+) {
+    for (entity, mut ai, position, team, health) in ai_query.iter_mut() {
+        ai.decision_timer -= time.delta_seconds();
+        if ai.decision_timer > 0.0 {
+            continue;
+        }
+        ai.decision_timer = difficulty.reaction_time();
+
+        let closest_enemy = enemy_query.iter()
+            .filter(|(enemy, _, enemy_team)| *enemy != entity && *enemy_team != team)
+            .map(|(enemy, enemy_position, _)| (enemy, enemy_position.0.distance(position.0)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+
+        let aggression = difficulty.aggression();
+        let retreat_health = 0.5 - 0.4 * aggression;
+        ai.state = match closest_enemy {
+            Some((_, distance)) if health.current / health.max < retreat_health && distance < DETECTION_RANGE => AiState::Retreat,
+            Some((_, distance)) if distance < ENGAGE_RANGE * (0.5 + aggression) => AiState::Engage,
+            Some((_, distance)) if distance < DETECTION_RANGE => AiState::Seek,
+            _ => AiState::Patrol,
+        };
+        ai.target = closest_enemy.map(|(enemy, _)| enemy);
+
+        let error = difficulty.aim_error();
+        ai.aim_offset = Vec3::new(ai.random(), 0.0, ai.random()) * error;
+    }
+}
+
+/// System to turn the state of the AI tanks into tank controls.
+/// The tanks follow a path planned on the navigation grid towards their destination.
+/// Only tanks whose `ControlSource` is the AI are driven, so an AI tank taken over by a player or a replay keeps its controls.
+#[allow(clippy::type_complexity)]
+fn ai_drive(
+    mut ai_query: Query<(&mut AiController, &Position, &Rotation, &mut TankControl, &Children, &ControlSource), Without<Wreck>>,
+    target_query: Query<&Position, With<Tank>>,
+    turret_query: Query<&Turret>,
+    grid: Option<Res<NavGrid>>,
+) {
+    for (mut ai, position, rotation, mut control, children, source) in ai_query.iter_mut() {
+        if *source != ControlSource::Ai {
+            continue;
+        }
+        let target_position = ai.target.and_then(|target| target_query.get(target).ok()).map(|p| p.0);
+        control.fire = false;
+
+        let destination = match (ai.state, target_position) {
+            (AiState::Seek, Some(target)) => Some(target),
+            (AiState::Engage, Some(target)) => {
+                let distance = target.distance(position.0);
+                if distance > PREFERRED_DISTANCE {
+                    Some(target)
+                } else {
+                    None
+                }
+            },
+            (AiState::Retreat, Some(target)) => Some(position.0 + (position.0 - target)),
+            _ => {
+                if ai.patrol_points.is_empty() {
+                    None
+                } else {
+                    let waypoint = ai.patrol_points[ai.patrol_index];
+                    if waypoint.distance(position.0) < WAYPOINT_RADIUS {
+                        ai.patrol_index = (ai.patrol_index + 1) % ai.patrol_points.len();
+                    }
+                    Some(ai.patrol_points[ai.patrol_index])
+                }
+            },
+        };
+
+        match destination {
+            Some(destination) => {
+                let direction = rotation.0.mul_vec3(Vec3::Z);
+                let destination = ai.next_path_point(destination, position.0, direction, grid.as_deref());
+                let local = rotation.0.inverse().mul_vec3(destination - position.0);
+                let angle = local.x.atan2(local.z);
+                control.steer = (angle * 2.0).clamp(-1.0, 1.0);
+                control.throttle = if angle.abs() < 1.2 { 1.0 } else { 0.0 };
+            },
+            None => {
+                ai.path.clear();
+                ai.planned = None;
+                control.steer = 0.0;
+                control.throttle = 0.0;
+            },
+        }
+
+        match target_position {
+            Some(target) if ai.state != AiState::Patrol => {
+                let distance = target.distance(position.0);
+                let aim_offset = ai.aim_offset * distance;
+                control.aim = target + Vec3::Y * AIM_HEIGHT + aim_offset;
+                if ai.state == AiState::Engage {
+                    control.fire = children.iter()
+                        .filter_map(|child| turret_query.get(*child).ok())
+                        .any(|turret| wrap_angle(turret.target_yaw - turret.yaw).abs() < FIRE_ANGLE_TOLERANCE);
+                }
+            },
+            _ => {
+                control.aim = position.0 + rotation.0.mul_vec3(Vec3::new(0.0, 0.0, 100.0));
+            },
+        }
+    }
+}
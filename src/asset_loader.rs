@@ -1,10 +1,18 @@
+use std::any::TypeId;
+
+use bevy::asset::LoadedFolder;
 use bevy::prelude::*;
 
-/// Resource to store 3D assets.
+use crate::map_definition::{MapDefinition, MapDefinitionLoader};
+use crate::tank_definition::{TankDefinition, TankDefinitionLoader};
+
+/// Resource to store the definitions of all tank types.
+/// Every definition in the `tanks` folder is a tank type, identified by its asset path.
+/// `definitions` is empty until the whole folder is loaded and sorted by path afterwards.
 #[derive(Resource, Debug, Default)]
-pub struct SceneAssets {
-    pub tank: Handle<Scene>,
-    pub floor: Handle<Scene>,
+pub struct TankDefinitionAssets {
+    pub folder: Handle<LoadedFolder>,
+    pub definitions: Vec<Handle<TankDefinition>>,
 }
 
 /// Resource to store font assets.
@@ -13,28 +21,96 @@ pub struct FontAssets {
     pub menu_font: Handle<Font>,
 }
 
-pub struct AssetLoaderPlugin;
+/// Plugin for loading the assets of the game.
+/// Without `render_assets` no models and fonts are loaded, for the headless server that has no renderer and no user interface.
+pub struct AssetLoaderPlugin {
+    pub render_assets: bool,
+}
+
+impl Default for AssetLoaderPlugin {
+    fn default() -> Self {
+        AssetLoaderPlugin {
+            render_assets: true,
+        }
+    }
+}
 
 impl Plugin for AssetLoaderPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<SceneAssets>()
+        app.init_asset::<TankDefinition>()
+            .register_asset_loader(TankDefinitionLoader {
+                load_models: self.render_assets,
+            })
+            .init_asset::<MapDefinition>()
+            .register_asset_loader(MapDefinitionLoader {
+                load_models: self.render_assets,
+            })
+            .init_resource::<TankDefinitionAssets>()
             .init_resource::<FontAssets>()
             .add_systems(PreStartup, (
-                load_3d_assets,
+                load_tank_definitions,
+            ))
+            .add_systems(PreUpdate, (
+                collect_tank_definitions,
+            ));
+        if self.render_assets {
+            app.add_systems(PreStartup, (
                 load_font_assets,
             ));
+        }
     }
 }
 
-/// System to load 3D assets from the asset server and store them in the `SceneAssets` resource.
-fn load_3d_assets(
-    mut assets: ResMut<SceneAssets>,
+/// System to start loading the tank definitions in the `tanks` folder.
+/// The definitions are reloaded when the files change.
+fn load_tank_definitions(
+    mut assets: ResMut<TankDefinitionAssets>,
     asset_server: Res<AssetServer>
 ) {
-    *assets = SceneAssets {
-        tank: asset_server.load("models/tank2.glb#Scene0"),
-        floor: asset_server.load("models/floor.glb#Scene0")
+    assets.folder = asset_server.load_folder("tanks");
+}
+
+/// System to store the tank definitions in the `TankDefinitionAssets` resource once the `tanks` folder is loaded.
+/// Files in the folder that are not tank definitions are ignored with a warning.
+fn collect_tank_definitions(
+    mut assets: ResMut<TankDefinitionAssets>,
+    mut events: EventReader<AssetEvent<LoadedFolder>>,
+    folders: Res<Assets<LoadedFolder>>,
+    tank_definitions: Res<Assets<TankDefinition>>,
+    asset_server: Res<AssetServer>,
+) {
+    let loaded = events.read().any(|event| event.is_loaded_with_dependencies(&assets.folder) || event.is_modified(&assets.folder));
+    if !loaded {
+        return;
     }
+    let Some(folder) = folders.get(&assets.folder) else {
+        return;
+    };
+    let mut definitions: Vec<Handle<TankDefinition>> = folder.handles.iter()
+        .filter_map(|handle| {
+            if handle.type_id() != TypeId::of::<TankDefinition>() {
+                warn!("Ignoring {:?} in the tanks folder, it is not a tank definition", asset_server.get_path(handle.id()));
+                return None;
+            }
+            Some(handle.clone().typed::<TankDefinition>())
+        })
+        .collect();
+    definitions.sort_by_key(|definition| asset_server.get_path(definition.id()).map(|path| path.to_string()));
+    if definitions.is_empty() {
+        error!("No tank definitions found in the tanks folder");
+    } else {
+        let names: Vec<&str> = definitions.iter()
+            .filter_map(|definition| tank_definitions.get(definition))
+            .map(|definition| definition.name.as_str())
+            .collect();
+        info!("Loaded tank types: {}", names.join(", "));
+    }
+    assets.definitions = definitions;
+}
+
+/// Run condition that is true once the tank definitions of the `tanks` folder are known.
+pub fn tank_definitions_loaded(assets: Res<TankDefinitionAssets>) -> bool {
+    !assets.definitions.is_empty()
 }
 
 /// System to load font assets from the asset server and store them in the `FontAssets` resource.
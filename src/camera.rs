@@ -1,109 +1,823 @@
 use bevy::prelude::*;
+use bevy::core_pipeline::clear_color::ClearColorConfig;
 use bevy::input::mouse::{MouseWheel, MouseMotion};
+use bevy::render::camera::Viewport;
+use bevy::utils::HashMap;
+use bevy::window::{PrimaryWindow, WindowResized};
 
-use crate::{physics::{Position, Rotation}, tank::Player};
+use crate::collision::{raycast, Collider, WorldShape};
+use crate::control::{ControlSource, LocalPlayers, TankControl};
+use crate::damage::Wreck;
+use crate::input_map::{ActionInput, InputAction};
+use crate::physics::{wrap_angle, Position, Rotation};
+use crate::prop::Prop;
+use crate::tank::{update_model_pos, Player, Tank};
+use crate::terrain::Terrain;
+use crate::turret::{Turret, GUN_OFFSET, TURRET_OFFSET};
 use crate::schedule::ScheduleSet;
 
+/// Radians the camera orbits per pixel of mouse movement in free-look mode.
+const FREE_LOOK_SENSITIVITY: f32 = 0.005;
+
+/// Lowest pitch of the free-look camera, looking up at the tank from below the chase position.
+const MIN_FREE_LOOK_PITCH: f32 = -0.3;
+
+/// Highest pitch of the free-look camera, looking down on the tank from above.
+const MAX_FREE_LOOK_PITCH: f32 = 1.2;
+
+/// Rate at which the camera returns to the chase position after free-look, per second.
+const FREE_LOOK_RETURN_SPEED: f32 = 5.0;
+
+/// Height above the player the camera collision is checked from.
+const CAMERA_PIVOT_HEIGHT: f32 = 5.0;
+
+/// Distance the camera keeps from the terrain and tanks blocking it.
+const CAMERA_MARGIN: f32 = 1.5;
+
+/// Rate at which the camera is pulled in when it is blocked, per second.
+const CAMERA_PULL_IN_SPEED: f32 = 15.0;
+
+/// Rate at which the camera moves back out when it is no longer blocked, per second.
+const CAMERA_RETURN_SPEED: f32 = 3.0;
+
+/// Opacity of props between the camera and the player.
+const OCCLUDING_PROP_ALPHA: f32 = 0.3;
+
+/// Position of the third person camera relative to the player, the distance behind it is set by the zoom.
+const THIRD_PERSON_HEIGHT: f32 = 15.5;
+
+/// Position of the first person camera relative to the player, above the front of the hull.
+const FIRST_PERSON_OFFSET: Vec3 = Vec3::new(0.0, 8.0, 6.0);
+
+/// Point relative to the player the camera looks at.
+const LOOK_OFFSET: Vec3 = Vec3::new(0.0, 5.0, 80.0);
+
+/// Longest time step of the camera spring, so it stays stable when a frame takes long.
+const MAX_SPRING_STEP: f32 = 1.0 / 30.0;
+
+/// Height of the gunner sight above the gun pivot.
+const SIGHT_HEIGHT: f32 = 2.5;
+
+/// Time in seconds the camera stays on the destroyed tank of the player before spectating another tank.
+const DEATH_CAM_DELAY: f32 = 3.0;
+
+/// Speed of the free-fly camera in units per second.
+const FREE_FLY_SPEED: f32 = 80.0;
+
+/// Radians the free-fly camera turns per pixel of mouse movement.
+const FREE_FLY_SENSITIVITY: f32 = 0.004;
+
+/// Distance from the desired position at which the camera jumps instead of following, e.g. when the player spawns.
+const CAMERA_SNAP_DISTANCE: f32 = 300.0;
+
+/// Plugin for the cameras of the local players.
+/// The cameras move in real time, so they keep moving while the game time of a replay is paused or slowed down.
 pub struct CameraPlugin;
 
+/// Resource to store the settings of the camera.
+/// The third person camera zooms between `min_distance` and `max_distance` behind the player in steps of `zoom_step`.
+/// `zoom_speed` and `view_blend_speed` are the rates at which the zoom and the switch between first and third person
+/// are animated. The camera follows the player on a spring with `stiffness`, `damping_ratio` 1.0 is critically damped.
+/// The gunner sight divides the vertical field of view `fov` by the selected one of `magnifications`, the sight turns
+/// by `sight_sensitivity` radians per pixel of mouse movement divided by the magnification.
+#[derive(Resource, Debug)]
+pub struct CameraSettings {
+    pub min_distance: f32,
+    pub max_distance: f32,
+    pub zoom_step: f32,
+    pub zoom_speed: f32,
+    pub view_blend_speed: f32,
+    pub stiffness: f32,
+    pub damping_ratio: f32,
+    pub fov: f32,
+    pub magnifications: Vec<f32>,
+    pub sight_sensitivity: f32,
+    pub min_sight_pitch: f32,
+    pub max_sight_pitch: f32,
+}
+
+impl Default for CameraSettings {
+    fn default() -> Self {
+        CameraSettings {
+            min_distance: 30.0,
+            max_distance: 120.0,
+            zoom_step: 10.0,
+            zoom_speed: 8.0,
+            view_blend_speed: 4.0,
+            stiffness: 80.0,
+            damping_ratio: 1.0,
+            fov: std::f32::consts::FRAC_PI_4,
+            magnifications: vec![2.0, 4.0, 8.0],
+            sight_sensitivity: 0.003,
+            min_sight_pitch: -0.3,
+            max_sight_pitch: 0.5,
+        }
+    }
+}
+
 /// Zoom component for the camera.
-/// The zoom is a f32 value that is used to determine the distance from the camera to the player.
+/// `distance` is the distance from the camera to the player in third person and is animated towards `target`.
+/// `blend` goes from 0.0 in third person to 1.0 in first person and is animated towards `first_person`.
+/// While `sight` is set the camera looks through the gunner sight with the magnification at index `magnification`.
+#[derive(Component)]
+pub struct Zoom {
+    pub distance: f32,
+    pub target: f32,
+    pub first_person: bool,
+    pub blend: f32,
+    pub sight: bool,
+    pub magnification: usize,
+}
+
+impl Zoom {
+    /// Magnification of the view, 1.0 outside of the gunner sight.
+    pub fn magnification(&self, settings: &CameraSettings) -> f32 {
+        if !self.sight {
+            return 1.0;
+        }
+        settings.magnifications.get(self.magnification).copied().unwrap_or(1.0).max(1.0)
+    }
+}
+
+/// SightAim component for the camera.
+/// The direction of the gunner sight in world space, `yaw` around the Y axis and `pitch` above the horizon in radians.
+/// The sight is stabilized, it keeps its direction while the hull turns.
+#[derive(Component)]
+pub struct SightAim {
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+impl SightAim {
+    /// Unit vector the sight looks along.
+    pub fn direction(&self) -> Vec3 {
+        look_direction(self.yaw, self.pitch)
+    }
+}
+
+/// CameraMode component for the camera.
+/// Decides which tank the camera follows, or whether it flies freely.
+#[derive(Component, Debug, Clone, Copy, PartialEq)]
+pub enum CameraMode {
+    /// Follow the tank of the player, which is controlled by the player.
+    Player,
+    /// Follow another tank. The tank of the player is not controlled while spectating.
+    Spectate(Entity),
+    /// Fly freely with the movement actions and the mouse. `yaw` and `pitch` are the direction the camera looks.
+    FreeFly { yaw: f32, pitch: f32 },
+}
+
+/// CameraFocus component for the camera.
+/// The tank the camera follows, `None` while flying freely.
+#[derive(Component, Debug, Default)]
+pub struct CameraFocus(pub Option<Entity>);
+
+/// PlayerCamera component for the camera.
+/// The number of the local player the camera belongs to, every local player has their own camera and part of the window.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerCamera(pub usize);
+
+/// CameraSpring component for the camera.
+/// The spring-damped position and look-at point of the camera, following their desired values behind the player.
 #[derive(Component)]
-pub struct Zoom(pub f32);
+pub struct CameraSpring {
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub look: Vec3,
+    pub look_velocity: Vec3,
+}
 
 /// MousePosition component for the camera.
-/// The mouse position is a Vec2 value that is used to determine the last position of the mouse.
+/// The mouse position is the orbit of the free-look camera around the player, accumulated from the mouse movement.
+/// `x` is the yaw and `y` the pitch in radians relative to the chase position, both are zero when not looking around.
 #[derive(Component)]
 pub struct MousePosition(pub Vec2);
 
+/// CameraCollision component for the camera.
+/// The distance is the smoothed distance of the camera from the player after pulling it in front of blocking geometry.
+#[derive(Component)]
+pub struct CameraCollision {
+    pub distance: f32,
+}
+
+/// Marker component for props between the camera and the player. They are drawn transparent.
+#[derive(Component)]
+pub struct Occluding;
+
+/// Component for the meshes of occluding props, to store their material before they were faded.
+#[derive(Component)]
+struct FadedMaterial(Handle<StandardMaterial>);
+
 impl Plugin for CameraPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, (
+        app.init_resource::<CameraSettings>()
+            .add_systems(Startup, (
             spawn_camera,
         ))
         .add_systems(Update, (
-            update_camera_zoom,
-            zoom_key,
+            update_viewports,
+        ))
+        .add_systems(Update, (
+            (
+                switch_camera_mode,
+                free_fly,
+            ).chain(),
+            (
+                sight_key.after(switch_camera_mode),
+                update_camera_zoom,
+                zoom_key,
+                animate_zoom,
+            ).chain(),
+            update_mouse_position,
+            update_sight_aim,
         ).in_set(ScheduleSet::Input))
         .add_systems(Update, (
-            update_camera,
+            (
+                update_camera_focus,
+                update_camera.after(update_model_pos),
+                update_fov,
+                avoid_camera_collisions,
+                find_occluding_props,
+                fade_occluding_props,
+            ).chain(),
         ).in_set(ScheduleSet::UpdateWorld));
     }
 }
 
-/// Spawn the camera
-fn spawn_camera(mut commands: Commands) {
+/// Spawn a camera for every local player.
+/// With more than one player the user interface is drawn by a separate camera over the whole window.
+fn spawn_camera(
+    mut commands: Commands,
+    local_players: Res<LocalPlayers>,
+) {
+    let split_screen = local_players.sources.len() > 1;
+    for index in 0..local_players.sources.len() {
+        spawn_player_camera(&mut commands, index, split_screen);
+    }
+    if split_screen {
+        commands.spawn((
+            Camera2dBundle {
+                camera: Camera {
+                    order: local_players.sources.len() as isize,
+                    ..default()
+                },
+                camera_2d: Camera2d {
+                    clear_color: ClearColorConfig::None,
+                },
+                ..default()
+            },
+        ));
+    }
+}
+
+/// Spawn the camera of a local player.
+fn spawn_player_camera(commands: &mut Commands, index: usize, split_screen: bool) {
     commands.spawn((
         Camera3dBundle {
+            camera: Camera {
+                order: index as isize,
+                ..default()
+            },
             transform: Transform::from_xyz(-10.5, 10.5, 40.0).looking_at(Vec3::ZERO, Vec3::Y),
             ..default()
         },
-        Zoom(40.0),
+        UiCameraConfig {
+            show_ui: !split_screen,
+        },
+        PlayerCamera(index),
+        CameraFocus::default(),
+        Zoom {
+            distance: 40.0,
+            target: 40.0,
+            first_person: false,
+            blend: 0.0,
+            sight: false,
+            magnification: 0,
+        },
+        SightAim {
+            yaw: 0.0,
+            pitch: 0.0,
+        },
+        CameraMode::Player,
+        CameraSpring {
+            position: Vec3::new(-10.5, 10.5, 40.0),
+            velocity: Vec3::ZERO,
+            look: Vec3::ZERO,
+            look_velocity: Vec3::ZERO,
+        },
         MousePosition(Vec2::ZERO),
+        CameraCollision {
+            distance: 40.0,
+        },
     ));
 }
 
-/// System to update the camera position and rotation to follow the player.
+/// Unit vector looking at `yaw` around the Y axis and `pitch` above the horizon.
+fn look_direction(yaw: f32, pitch: f32) -> Vec3 {
+    Vec3::new(pitch.cos() * yaw.sin(), pitch.sin(), pitch.cos() * yaw.cos())
+}
+
+/// The living tank before or after `current` in a stable order, wrapping around at the ends.
+fn cycle_tank(tanks: &[Entity], current: Option<Entity>, forward: bool) -> Option<Entity> {
+    let index = current.and_then(|current| tanks.iter().position(|tank| *tank == current));
+    let next = match (index, forward) {
+        (Some(index), true) => (index + 1) % tanks.len().max(1),
+        (Some(index), false) => (index + tanks.len() - 1) % tanks.len(),
+        (None, true) => 0,
+        (None, false) => tanks.len().saturating_sub(1),
+    };
+    tanks.get(next).copied()
+}
+
+/// System to split the window between the cameras of the local players.
+/// Two players get the top and bottom half, three or four players a quarter each.
+fn update_viewports(
+    mut camera_query: Query<(&mut Camera, &PlayerCamera)>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    mut resized: EventReader<WindowResized>,
+    new_cameras: Query<(), Added<PlayerCamera>>,
+) {
+    if resized.read().count() == 0 && new_cameras.is_empty() {
+        return;
+    }
+    let Ok(window) = window_query.get_single() else {
+        return;
+    };
+    let count = camera_query.iter().count();
+    if count <= 1 {
+        return;
+    }
+    let (columns, rows) = if count == 2 { (1, 2) } else { (2, 2) };
+    let size = UVec2::new(window.physical_width() / columns, window.physical_height() / rows);
+    for (mut camera, player_camera) in camera_query.iter_mut() {
+        let index = player_camera.0 as u32;
+        camera.viewport = Some(Viewport {
+            physical_position: UVec2::new(index % columns, index / columns) * size,
+            physical_size: size.max(UVec2::ONE),
+            ..default()
+        });
+    }
+}
+
+/// System to switch between following the player, spectating other tanks and the free-fly camera.
+/// The `Spectate` action cycles through the modes, the next and previous tank actions spectate the other tanks.
+/// After the tank of the player is destroyed the camera spectates the next living tank.
+/// While not following the player, the tank of the player is not controlled and the gunner sight is closed.
+/// Every camera is switched with the device of its player.
+fn switch_camera_mode(
+    mut camera_query: Query<(&mut CameraMode, &mut Zoom, &Transform, &PlayerCamera)>,
+    tank_query: Query<Entity, (With<Tank>, Without<Wreck>)>,
+    mut player_query: Query<(Entity, &Player, &mut ControlSource, &mut TankControl, Has<Wreck>)>,
+    local_players: Res<LocalPlayers>,
+    input: ActionInput,
+    time: Res<Time>,
+    mut death_times: Local<HashMap<usize, f32>>,
+) {
+    let mut tanks: Vec<Entity> = tank_query.iter().collect();
+    tanks.sort();
+
+    for (mut mode, mut zoom, transform, player_camera) in camera_query.iter_mut() {
+        let device = local_players.source(player_camera.0);
+        let player = player_query.iter()
+            .find(|(_, player, ..)| player.0 == player_camera.0)
+            .map(|(entity, _, _, _, wreck)| (entity, wreck));
+        let player_destroyed = player.is_some_and(|(_, wreck)| wreck);
+        let death_time = death_times.entry(player_camera.0).or_default();
+        if player_destroyed {
+            *death_time += time.delta_seconds();
+        } else {
+            *death_time = 0.0;
+        }
+
+        let current = match *mode {
+            CameraMode::Player => player.map(|(entity, _)| entity),
+            CameraMode::Spectate(target) => Some(target),
+            CameraMode::FreeFly { .. } => None,
+        };
+        if input.source_just_pressed(device, InputAction::Spectate) {
+            let forward = transform.forward();
+            *mode = match *mode {
+                CameraMode::Player => match cycle_tank(&tanks, current, true) {
+                    Some(target) => CameraMode::Spectate(target),
+                    None => CameraMode::FreeFly { yaw: forward.x.atan2(forward.z), pitch: forward.y.clamp(-1.0, 1.0).asin() },
+                },
+                CameraMode::Spectate(_) => CameraMode::FreeFly { yaw: forward.x.atan2(forward.z), pitch: forward.y.clamp(-1.0, 1.0).asin() },
+                CameraMode::FreeFly { .. } => CameraMode::Player,
+            };
+        } else if input.source_just_pressed(device, InputAction::NextTank) || input.source_just_pressed(device, InputAction::PreviousTank) {
+            let forward = input.source_just_pressed(device, InputAction::NextTank);
+            if let Some(target) = cycle_tank(&tanks, current, forward) {
+                *mode = CameraMode::Spectate(target);
+            }
+        } else if *mode == CameraMode::Player && player_destroyed && *death_time > DEATH_CAM_DELAY {
+            if let Some(target) = cycle_tank(&tanks, current, true) {
+                *mode = CameraMode::Spectate(target);
+            }
+        } else if let CameraMode::Spectate(target) = *mode {
+            if !tanks.contains(&target) {
+                if let Some(next) = cycle_tank(&tanks, None, true) {
+                    *mode = CameraMode::Spectate(next);
+                }
+            }
+        }
+        if *mode != CameraMode::Player {
+            zoom.sight = false;
+        }
+
+        let Some((_, _, mut source, mut control, _)) = player_query.iter_mut().find(|(_, player, ..)| player.0 == player_camera.0) else {
+            continue;
+        };
+        match (*mode == CameraMode::Player, *source) {
+            (true, ControlSource::Idle) => *source = device,
+            (false, ControlSource::KeyboardMouse | ControlSource::Keyboard | ControlSource::Gamepad(_)) => {
+                *source = ControlSource::Idle;
+                *control = TankControl {
+                    aim: control.aim,
+                    ..default()
+                };
+            },
+            _ => {},
+        }
+    }
+}
+
+/// System to move the free-fly cameras.
+/// The mouse turns the camera of the player using it, the movement actions move it along the view direction and sideways.
+fn free_fly(
+    mut camera_query: Query<(&mut Transform, &mut CameraMode, &PlayerCamera)>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    local_players: Res<LocalPlayers>,
+    input: ActionInput,
+    time: Res<Time<Real>>,
+) {
+    let delta: Vec2 = mouse_motion.read().map(|motion| motion.delta).sum();
+    for (mut transform, mut mode, player_camera) in camera_query.iter_mut() {
+        let CameraMode::FreeFly { ref mut yaw, ref mut pitch } = *mode else {
+            continue;
+        };
+        let device = local_players.source(player_camera.0);
+        if device == ControlSource::KeyboardMouse {
+            *yaw = wrap_angle(*yaw - delta.x * FREE_FLY_SENSITIVITY);
+            *pitch = (*pitch - delta.y * FREE_FLY_SENSITIVITY).clamp(-1.5, 1.5);
+        }
+        let forward = look_direction(*yaw, *pitch);
+        let left = Vec3::Y.cross(forward).normalize_or_zero();
+        let axis = |positive: InputAction, negative: InputAction| {
+            input.source_pressed(device, positive) as i32 as f32 - input.source_pressed(device, negative) as i32 as f32
+        };
+        let movement = forward * axis(InputAction::Forward, InputAction::Reverse)
+            + left * axis(InputAction::TurnLeft, InputAction::TurnRight);
+        transform.translation += movement.normalize_or_zero() * FREE_FLY_SPEED * time.delta_seconds();
+        transform.look_to(forward, Vec3::Y);
+    }
+}
+
+/// System to set the tank every camera follows from its mode.
+fn update_camera_focus(
+    mut camera_query: Query<(&CameraMode, &PlayerCamera, &mut CameraFocus)>,
+    player_query: Query<(Entity, &Player)>,
+) {
+    for (mode, player_camera, mut focus) in camera_query.iter_mut() {
+        focus.0 = match *mode {
+            CameraMode::Player => player_query.iter()
+                .find(|(_, player)| player.0 == player_camera.0)
+                .map(|(entity, _)| entity),
+            CameraMode::Spectate(target) => Some(target),
+            CameraMode::FreeFly { .. } => None,
+        };
+    }
+}
+
+/// System to update the camera position and rotation to follow the player, or the tank spectated instead.
+/// Follows the interpolated model transform of the player so the camera does not jitter between physics ticks.
+/// The chase position behind the hull is orbited around the player by the free-look angles and blended with the
+/// first person position. The camera and its look-at point follow on springs, the first person camera is attached
+/// to the hull, so the springs are faded out with the blend.
+/// The gunner sight is attached to the gun of the turret and looks along the sight aim.
+#[allow(clippy::type_complexity)]
 fn update_camera(
-    query: Query<(&Position, &Rotation), With<Player>>, 
-    mut camera_query: Query<(&mut Transform, &Zoom), With<Camera>>,
+    query: Query<(&Transform, Option<&Children>), (With<Tank>, Without<Camera>)>, 
+    mut camera_query: Query<(&mut Transform, &mut CameraSpring, &Zoom, &MousePosition, &SightAim, &CameraFocus), With<Camera>>,
+    turret_query: Query<&Turret>,
+    settings: Res<CameraSettings>,
+    time: Res<Time<Real>>,
+) {
+    let dt = time.delta_seconds().min(MAX_SPRING_STEP);
+    let stiffness = settings.stiffness;
+    let damping = 2.0 * settings.damping_ratio * stiffness.sqrt();
+    for (mut transform, mut spring, zoom, mouse_position, sight_aim, focus) in camera_query.iter_mut() {
+        let Some((player_transform, children)) = focus.0.and_then(|entity| query.get(entity).ok()) else {
+            continue;
+        };
+        let position = player_transform.translation;
+        let orbit = Quat::from_euler(EulerRot::YXZ, mouse_position.0.x, mouse_position.0.y, 0.0);
+        let rotation = player_transform.rotation * orbit;
+        let blend = zoom.blend * zoom.blend * (3.0 - 2.0 * zoom.blend);
+        let third_person = Vec3::new(0.0, THIRD_PERSON_HEIGHT, -zoom.distance);
+        let desired = position + rotation.mul_vec3(third_person.lerp(FIRST_PERSON_OFFSET, blend));
+        let desired_look = position + rotation.mul_vec3(LOOK_OFFSET);
+        if spring.position.distance(desired) > CAMERA_SNAP_DISTANCE {
+            spring.position = desired;
+            spring.look = desired_look;
+            spring.velocity = Vec3::ZERO;
+            spring.look_velocity = Vec3::ZERO;
+        }
+
+        let acceleration = (desired - spring.position) * stiffness - spring.velocity * damping;
+        spring.velocity += acceleration * dt;
+        let velocity = spring.velocity;
+        spring.position += velocity * dt;
+        let look_acceleration = (desired_look - spring.look) * stiffness - spring.look_velocity * damping;
+        spring.look_velocity += look_acceleration * dt;
+        let look_velocity = spring.look_velocity;
+        spring.look += look_velocity * dt;
+
+        if zoom.sight {
+            let turret_yaw = children.into_iter().flatten()
+                .find_map(|child| turret_query.get(*child).ok())
+                .map_or(0.0, |turret| turret.yaw);
+            let turret_rotation = player_transform.rotation * Quat::from_rotation_y(turret_yaw);
+            transform.translation = position + player_transform.rotation.mul_vec3(TURRET_OFFSET)
+                + turret_rotation.mul_vec3(GUN_OFFSET) + Vec3::Y * SIGHT_HEIGHT;
+            transform.look_to(sight_aim.direction(), Vec3::Y);
+            continue;
+        }
+        transform.translation = spring.position.lerp(desired, blend);
+        transform.look_at(spring.look.lerp(desired_look, blend), Vec3::Y);
+    }
+}
+
+/// System to narrow the field of view of the camera to the magnification of the gunner sight.
+fn update_fov(
+    mut camera_query: Query<(&mut Projection, &Zoom), With<Camera>>,
+    settings: Res<CameraSettings>,
+    time: Res<Time<Real>>,
 ) {
-    for (position, rotation) in query.iter() {
-        for (mut transform, zoom) in camera_query.iter_mut() {
-            transform.translation = position.0 + rotation.0.mul_vec3(Vec3::new(-0.0, 15.5, -zoom.0));
-            transform.look_at(position.0 + (rotation.0.mul_vec3(Vec3::new(0.0, 5.0, (-10.0 * zoom.0).max(80.0)))), Vec3::Y);
+    let t = 1.0 - (-settings.zoom_speed * time.delta_seconds()).exp();
+    for (mut projection, zoom) in camera_query.iter_mut() {
+        if let Projection::Perspective(ref mut perspective) = *projection {
+            let fov = settings.fov / zoom.magnification(&settings);
+            perspective.fov += (fov - perspective.fov) * t;
         }
     }
 }
 
-// fn update_mouse_position(
-//     mut mouse_position: Query<&mut MousePosition, With<Camera>>,
-//     mut new_mouse_motion: ResMut<Events<MouseMotion>>,
-// ) {
-//     for ev in new_mouse_motion.drain() {
-//         for mut mp in mouse_position.iter_mut() {
-//             mp.0 += ev.delta;
-//         }
-//     }
-// }
+/// System to pull the camera towards the player when the terrain or another tank is between them.
+/// The camera moves in quickly and back out slowly, so it does not pop when passing behind small hills.
+/// Props do not block the camera, they are faded instead.
+fn avoid_camera_collisions(
+    query: Query<&Transform, (With<Tank>, Without<Camera>)>,
+    mut camera_query: Query<(&mut Transform, &mut CameraCollision, &CameraFocus), With<Camera>>,
+    tank_query: Query<(Entity, &Collider, &Position, &Rotation), With<Tank>>,
+    terrain: Option<Res<Terrain>>,
+    time: Res<Time<Real>>,
+) {
+    for (mut transform, mut collision, focus) in camera_query.iter_mut() {
+        let Some((player, player_transform)) = focus.0.and_then(|entity| query.get(entity).ok().map(|transform| (entity, transform))) else {
+            continue;
+        };
+        let pivot = player_transform.translation + Vec3::Y * CAMERA_PIVOT_HEIGHT;
+        let offset = transform.translation - pivot;
+        let desired = offset.length();
+        let Some(direction) = offset.try_normalize() else {
+            continue;
+        };
+        let terrain_hit = terrain.as_deref().and_then(|terrain| terrain.raycast(pivot, direction, desired));
+        let tank_hits = tank_query.iter()
+            .filter(|(entity, ..)| *entity != player)
+            .filter_map(|(_, collider, position, rotation)| {
+                raycast(&WorldShape::new(collider, position.0, rotation.0), pivot, direction, desired)
+            });
+        let allowed = terrain_hit.into_iter().chain(tank_hits)
+            .map(|hit| (hit.distance - CAMERA_MARGIN).max(0.0))
+            .fold(desired, f32::min);
+
+        let speed = if allowed < collision.distance { CAMERA_PULL_IN_SPEED } else { CAMERA_RETURN_SPEED };
+        let t = 1.0 - (-speed * time.delta_seconds()).exp();
+        collision.distance += (allowed - collision.distance) * t;
+        collision.distance = collision.distance.min(desired);
+        transform.translation = pivot + direction * collision.distance;
+    }
+}
+
+/// System to mark the props between a camera and the tank it follows as occluding.
+/// In split-screen a prop is faded for all players when it occludes the view of one of them.
+#[allow(clippy::type_complexity)]
+fn find_occluding_props(
+    mut commands: Commands,
+    query: Query<&Transform, (With<Tank>, Without<Camera>)>,
+    camera_query: Query<(&Transform, &CameraFocus), With<Camera>>,
+    prop_query: Query<(Entity, Option<&Collider>, Option<&Position>, Option<&Rotation>, Option<&Occluding>), With<Prop>>,
+) {
+    let views: Vec<(Vec3, Vec3, f32)> = camera_query.iter()
+        .filter_map(|(camera_transform, focus)| {
+            let player_transform = query.get(focus.0?).ok()?;
+            let pivot = player_transform.translation + Vec3::Y * CAMERA_PIVOT_HEIGHT;
+            let offset = camera_transform.translation - pivot;
+            Some((pivot, offset.try_normalize()?, offset.length()))
+        })
+        .collect();
+    for (entity, collider, position, rotation, occluding) in prop_query.iter() {
+        let blocks = match (collider, position, rotation) {
+            (Some(collider), Some(position), Some(rotation)) => {
+                let shape = WorldShape::new(collider, position.0, rotation.0);
+                views.iter().any(|(pivot, direction, distance)| raycast(&shape, *pivot, *direction, *distance).is_some())
+            },
+            _ => false,
+        };
+        match (blocks, occluding.is_some()) {
+            (true, false) => {
+                commands.entity(entity).insert(Occluding);
+            },
+            (false, true) => {
+                commands.entity(entity).remove::<Occluding>();
+            },
+            _ => {},
+        }
+    }
+}
+
+/// System to draw occluding props transparent and to restore them once they no longer occlude the player.
+/// The meshes of a faded prop get a transparent copy of their material, so other entities using it are not affected.
+fn fade_occluding_props(
+    mut commands: Commands,
+    occluding_query: Query<Entity, Added<Occluding>>,
+    mut removed: RemovedComponents<Occluding>,
+    children_query: Query<&Children>,
+    mut mesh_query: Query<(&mut Handle<StandardMaterial>, Option<&FadedMaterial>)>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    for prop in occluding_query.iter() {
+        for entity in std::iter::once(prop).chain(children_query.iter_descendants(prop)) {
+            let Ok((mut material, faded)) = mesh_query.get_mut(entity) else {
+                continue;
+            };
+            if faded.is_some() {
+                continue;
+            }
+            let Some(mut transparent) = materials.get(material.as_ref()).cloned() else {
+                continue;
+            };
+            transparent.base_color.set_a(OCCLUDING_PROP_ALPHA);
+            transparent.alpha_mode = AlphaMode::Blend;
+            commands.entity(entity).insert(FadedMaterial(material.clone()));
+            *material = materials.add(transparent);
+        }
+    }
+    for prop in removed.read() {
+        for entity in std::iter::once(prop).chain(children_query.iter_descendants(prop)) {
+            let Ok((mut material, Some(faded))) = mesh_query.get_mut(entity) else {
+                continue;
+            };
+            *material = faded.0.clone();
+            commands.entity(entity).remove::<FadedMaterial>();
+        }
+    }
+}
+
+/// System to orbit the camera around the player while the `FreeLook` action is held.
+/// The pitch is clamped so the camera does not flip over the tank and only dips a little below the chase position,
+/// `avoid_camera_collisions` keeps it from going into the terrain.
+/// After releasing the action the camera smoothly returns to the chase position.
+fn update_mouse_position(
+    mut mouse_position: Query<(&mut MousePosition, &Zoom, &PlayerCamera), With<Camera>>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    local_players: Res<LocalPlayers>,
+    input: ActionInput,
+    time: Res<Time<Real>>,
+) {
+    let delta: Vec2 = mouse_motion.read().map(|motion| motion.delta).sum();
+    for (mut mp, zoom, player_camera) in mouse_position.iter_mut() {
+        let device = local_players.source(player_camera.0);
+        let free_look = device == ControlSource::KeyboardMouse && input.source_pressed(device, InputAction::FreeLook);
+        if free_look && !zoom.sight {
+            mp.0.x = wrap_angle(mp.0.x - delta.x * FREE_LOOK_SENSITIVITY);
+            mp.0.y = (mp.0.y + delta.y * FREE_LOOK_SENSITIVITY).clamp(MIN_FREE_LOOK_PITCH, MAX_FREE_LOOK_PITCH);
+        } else {
+            mp.0 *= (-FREE_LOOK_RETURN_SPEED * time.delta_seconds()).exp();
+        }
+    }
+}
 
 /// System to update the camera zoom based on the mouse wheel input.
+/// Zooming in past the closest distance switches to first person, zooming out of first person switches back.
+/// Only the camera of the player using keyboard and mouse is zoomed.
 fn update_camera_zoom(
-    mut zoom: Query<&mut Zoom, With<Camera>>,
+    mut zoom: Query<(&mut Zoom, &PlayerCamera), With<Camera>>,
     mut scroll_evr: EventReader<MouseWheel>,
+    local_players: Res<LocalPlayers>,
+    settings: Res<CameraSettings>,
 ) {
     use bevy::input::mouse::MouseScrollUnit;
     for ev in scroll_evr.read(){
         if let MouseScrollUnit::Line = ev.unit {
-            for mut z in zoom.iter_mut() {
-                if z.0 == 30.0 && ev.y > 0.0 {
-                    z.0 = -30.0;
+            for (mut z, player_camera) in zoom.iter_mut() {
+                if local_players.source(player_camera.0) != ControlSource::KeyboardMouse {
+                    continue;
+                }
+                if z.sight {
+                    let last = settings.magnifications.len().saturating_sub(1);
+                    if ev.y > 0.0 {
+                        z.magnification = (z.magnification + 1).min(last);
+                    } else if ev.y < 0.0 {
+                        z.magnification = z.magnification.saturating_sub(1);
+                    }
                     continue;
                 }
-                if z.0 == -30.0 && ev.y < 0.0 {
-                    z.0 = 30.0;
+                if z.first_person {
+                    if ev.y < 0.0 {
+                        z.first_person = false;
+                        z.target = settings.min_distance;
+                    }
                     continue;
                 }
-                z.0 += ev.y * -10.0;
+                if z.target <= settings.min_distance && ev.y > 0.0 {
+                    z.first_person = true;
+                    continue;
+                }
+                z.target = (z.target - ev.y * settings.zoom_step).clamp(settings.min_distance, settings.max_distance);
             }
         }
     }
 }
 
-/// System to update the camera zoom based on the keyboard input
+/// System to update the camera zoom based on the `ToggleView` action
 /// Switch the zoom between third person and first person
 fn zoom_key (
+    mut zoom: Query<(&mut Zoom, &PlayerCamera), With<Camera>>,
+    local_players: Res<LocalPlayers>,
+    input: ActionInput,
+) {
+    for (mut z, player_camera) in zoom.iter_mut() {
+        if input.source_just_pressed(local_players.source(player_camera.0), InputAction::ToggleView) {
+            z.first_person = !z.first_person;
+        }
+    }
+}
+
+/// System to animate the camera distance towards the zoom target and blend between first and third person.
+fn animate_zoom(
     mut zoom: Query<&mut Zoom, With<Camera>>,
-    keyboard_input: Res<Input<KeyCode>>,
+    settings: Res<CameraSettings>,
+    time: Res<Time<Real>>,
 ) {
+    let t = 1.0 - (-settings.zoom_speed * time.delta_seconds()).exp();
+    let blend_step = settings.view_blend_speed * time.delta_seconds();
     for mut z in zoom.iter_mut() {
-        if keyboard_input.just_pressed(KeyCode::ShiftLeft) {
-            if z.0 <= -30.0 {
-                z.0 = 50.0;
-            } else if z.0 >= 30.0 {
-                z.0 = -40.0;
-            }
+        z.distance += (z.target - z.distance) * t;
+        let blend_target = if z.first_person { 1.0 } else { 0.0 };
+        z.blend += (blend_target - z.blend).clamp(-blend_step, blend_step);
+    }
+}
+
+/// System to switch the gunner sight on and off with the `Sight` action.
+/// The sight starts looking where the camera looked, so the view does not jump.
+fn sight_key(
+    mut camera_query: Query<(&mut Zoom, &mut SightAim, &Transform, &CameraMode, &PlayerCamera), With<Camera>>,
+    local_players: Res<LocalPlayers>,
+    input: ActionInput,
+) {
+    for (mut zoom, mut sight_aim, transform, mode, player_camera) in camera_query.iter_mut() {
+        if *mode != CameraMode::Player || !input.source_just_pressed(local_players.source(player_camera.0), InputAction::Sight) {
+            continue;
+        }
+        zoom.sight = !zoom.sight;
+        if zoom.sight {
+            let forward = transform.forward();
+            sight_aim.yaw = forward.x.atan2(forward.z);
+            sight_aim.pitch = forward.y.clamp(-1.0, 1.0).asin();
         }
     }
 }
 
+/// System to turn the gunner sight with the mouse.
+/// The sensitivity is divided by the magnification, so the sight moves the same distance on screen at every zoom level.
+fn update_sight_aim(
+    mut camera_query: Query<(&mut SightAim, &Zoom, &Transform, &CameraFocus, &PlayerCamera), With<Camera>>,
+    tank_query: Query<&TankControl>,
+    mut mouse_motion: EventReader<MouseMotion>,
+    local_players: Res<LocalPlayers>,
+    settings: Res<CameraSettings>,
+) {
+    let delta: Vec2 = mouse_motion.read().map(|motion| motion.delta).sum();
+    for (mut sight_aim, zoom, transform, focus, player_camera) in camera_query.iter_mut() {
+        if !zoom.sight {
+            continue;
+        }
+        if local_players.source(player_camera.0) != ControlSource::KeyboardMouse {
+            // Without a mouse the sight follows the aim point of the tank.
+            let Some(control) = focus.0.and_then(|entity| tank_query.get(entity).ok()) else {
+                continue;
+            };
+            let Some(direction) = (control.aim - transform.translation).try_normalize() else {
+                continue;
+            };
+            sight_aim.yaw = direction.x.atan2(direction.z);
+            sight_aim.pitch = direction.y.asin().clamp(settings.min_sight_pitch, settings.max_sight_pitch);
+            continue;
+        }
+        let sensitivity = settings.sight_sensitivity / zoom.magnification(&settings);
+        sight_aim.yaw = wrap_angle(sight_aim.yaw - delta.x * sensitivity);
+        sight_aim.pitch = (sight_aim.pitch - delta.y * sensitivity).clamp(settings.min_sight_pitch, settings.max_sight_pitch);
+    }
+}
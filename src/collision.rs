@@ -0,0 +1,727 @@
+use bevy::prelude::*;
+
+use crate::physics::{follow_terrain, Mass, Position, Rotation, Velocity};
+use crate::schedule::ScheduleSet;
+
+/// Coefficient of restitution used for all collisions.
+/// 0.0 is a perfectly inelastic collision, 1.0 is a perfectly elastic one.
+const RESTITUTION: f32 = 0.2;
+
+/// Number of iterations used to find the point on a capsule closest to a box.
+const CAPSULE_BOX_ITERATIONS: usize = 4;
+
+/// Shape of a collider in the local space of its entity.
+#[derive(Clone, Copy, Debug)]
+pub enum ColliderShape {
+    /// Oriented box with the given half extents.
+    Cuboid { half_extents: Vec3 },
+    /// Sphere with the given radius.
+    Sphere { radius: f32 },
+    /// Capsule along the local Y axis. `half_height` is the distance from the center to the center of each cap.
+    Capsule { half_height: f32, radius: f32 },
+}
+
+/// Component to store the collision shape of an entity.
+/// The shape is placed at `offset` relative to the `Position` of the entity and rotated by its `Rotation`.
+/// Entities with a collider but without `Mass` and `Velocity` are static and never moved by collisions.
+#[derive(Component, Clone, Copy, Debug)]
+pub struct Collider {
+    pub shape: ColliderShape,
+    pub offset: Vec3,
+}
+
+impl Collider {
+    pub fn cuboid(half_extents: Vec3) -> Self {
+        Collider {
+            shape: ColliderShape::Cuboid { half_extents },
+            offset: Vec3::ZERO,
+        }
+    }
+
+    pub fn sphere(radius: f32) -> Self {
+        Collider {
+            shape: ColliderShape::Sphere { radius },
+            offset: Vec3::ZERO,
+        }
+    }
+
+    pub fn capsule(half_height: f32, radius: f32) -> Self {
+        Collider {
+            shape: ColliderShape::Capsule { half_height, radius },
+            offset: Vec3::ZERO,
+        }
+    }
+
+    pub fn with_offset(mut self, offset: Vec3) -> Self {
+        self.offset = offset;
+        self
+    }
+}
+
+impl Default for Collider {
+    fn default() -> Self {
+        Collider::sphere(1.0)
+    }
+}
+
+/// Event sent for every pair of colliders that touch in a physics tick.
+/// The normal points from `a` to `b`.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct CollisionEvent {
+    pub a: Entity,
+    pub b: Entity,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub impulse: f32,
+}
+
+/// Plugin for collision detection and response.
+pub struct CollisionPlugin;
+
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<CollisionEvent>()
+            .add_systems(FixedUpdate, (
+                resolve_collisions.after(follow_terrain),
+            ).in_set(ScheduleSet::Physics));
+    }
+}
+
+/// Point of contact between two colliders. The normal points from the first to the second collider.
+#[derive(Debug, Clone, Copy)]
+pub struct Contact {
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub depth: f32,
+}
+
+impl Contact {
+    fn flipped(self) -> Self {
+        Contact {
+            normal: -self.normal,
+            ..self
+        }
+    }
+}
+
+/// Collider transformed into world space.
+#[derive(Debug, Clone, Copy)]
+pub enum WorldShape {
+    Cuboid { center: Vec3, axes: [Vec3; 3], half_extents: Vec3 },
+    Sphere { center: Vec3, radius: f32 },
+    Capsule { start: Vec3, end: Vec3, radius: f32 },
+}
+
+impl WorldShape {
+    pub fn new(collider: &Collider, position: Vec3, rotation: Quat) -> Self {
+        let center = position + rotation.mul_vec3(collider.offset);
+        match collider.shape {
+            ColliderShape::Cuboid { half_extents } => WorldShape::Cuboid {
+                center,
+                axes: [
+                    rotation.mul_vec3(Vec3::X),
+                    rotation.mul_vec3(Vec3::Y),
+                    rotation.mul_vec3(Vec3::Z),
+                ],
+                half_extents,
+            },
+            ColliderShape::Sphere { radius } => WorldShape::Sphere { center, radius },
+            ColliderShape::Capsule { half_height, radius } => {
+                let axis = rotation.mul_vec3(Vec3::Y) * half_height;
+                WorldShape::Capsule { start: center - axis, end: center + axis, radius }
+            },
+        }
+    }
+
+    /// Axis aligned bounding box of the shape as `(min, max)`.
+    pub fn aabb(&self) -> (Vec3, Vec3) {
+        match *self {
+            WorldShape::Cuboid { center, axes, half_extents } => {
+                let extent = axes[0].abs() * half_extents.x
+                    + axes[1].abs() * half_extents.y
+                    + axes[2].abs() * half_extents.z;
+                (center - extent, center + extent)
+            },
+            WorldShape::Sphere { center, radius } => (center - Vec3::splat(radius), center + Vec3::splat(radius)),
+            WorldShape::Capsule { start, end, radius } => (
+                start.min(end) - Vec3::splat(radius),
+                start.max(end) + Vec3::splat(radius),
+            ),
+        }
+    }
+}
+
+/// Narrow phase: computes the contact between two shapes, if they intersect.
+pub fn contact(a: &WorldShape, b: &WorldShape) -> Option<Contact> {
+    match (*a, *b) {
+        (WorldShape::Sphere { center: ca, radius: ra }, WorldShape::Sphere { center: cb, radius: rb }) => {
+            sphere_sphere(ca, ra, cb, rb)
+        },
+        (WorldShape::Sphere { center, radius }, WorldShape::Capsule { start, end, radius: rb }) => {
+            let closest = closest_point_on_segment(start, end, center);
+            sphere_sphere(center, radius, closest, rb)
+        },
+        (WorldShape::Capsule { .. }, WorldShape::Sphere { .. }) => contact(b, a).map(Contact::flipped),
+        (WorldShape::Capsule { start: sa, end: ea, radius: ra }, WorldShape::Capsule { start: sb, end: eb, radius: rb }) => {
+            let (pa, pb) = closest_points_between_segments(sa, ea, sb, eb);
+            sphere_sphere(pa, ra, pb, rb)
+        },
+        (WorldShape::Cuboid { center, axes, half_extents }, WorldShape::Sphere { center: sphere_center, radius }) => {
+            cuboid_sphere(center, axes, half_extents, sphere_center, radius)
+        },
+        (WorldShape::Sphere { .. }, WorldShape::Cuboid { .. }) => contact(b, a).map(Contact::flipped),
+        (WorldShape::Cuboid { center, axes, half_extents }, WorldShape::Capsule { start, end, radius }) => {
+            let mut t_point = closest_point_on_segment(start, end, center);
+            for _ in 0..CAPSULE_BOX_ITERATIONS {
+                let on_box = closest_point_on_cuboid(center, axes, half_extents, t_point);
+                t_point = closest_point_on_segment(start, end, on_box);
+            }
+            cuboid_sphere(center, axes, half_extents, t_point, radius)
+        },
+        (WorldShape::Capsule { .. }, WorldShape::Cuboid { .. }) => contact(b, a).map(Contact::flipped),
+        (WorldShape::Cuboid { center: ca, axes: aa, half_extents: ha }, WorldShape::Cuboid { center: cb, axes: ab, half_extents: hb }) => {
+            cuboid_cuboid(ca, aa, ha, cb, ab, hb)
+        },
+    }
+}
+
+fn sphere_sphere(ca: Vec3, ra: f32, cb: Vec3, rb: f32) -> Option<Contact> {
+    let delta = cb - ca;
+    let distance = delta.length();
+    let depth = ra + rb - distance;
+    if depth <= 0.0 {
+        return None;
+    }
+    let normal = if distance > f32::EPSILON { delta / distance } else { Vec3::X };
+    Some(Contact {
+        point: ca + normal * (ra - depth * 0.5),
+        normal,
+        depth,
+    })
+}
+
+fn cuboid_sphere(center: Vec3, axes: [Vec3; 3], half_extents: Vec3, sphere_center: Vec3, radius: f32) -> Option<Contact> {
+    let local = sphere_center - center;
+    let local = Vec3::new(local.dot(axes[0]), local.dot(axes[1]), local.dot(axes[2]));
+    let clamped = local.clamp(-half_extents, half_extents);
+
+    if clamped != local {
+        // Sphere center is outside of the box
+        let closest = center + axes[0] * clamped.x + axes[1] * clamped.y + axes[2] * clamped.z;
+        let delta = sphere_center - closest;
+        let distance = delta.length();
+        if distance >= radius {
+            return None;
+        }
+        return Some(Contact {
+            point: closest,
+            normal: delta / distance,
+            depth: radius - distance,
+        });
+    }
+
+    // Sphere center is inside of the box, push it out through the closest face
+    let distance_to_face = half_extents - local.abs();
+    let axis = if distance_to_face.x < distance_to_face.y && distance_to_face.x < distance_to_face.z {
+        0
+    } else if distance_to_face.y < distance_to_face.z {
+        1
+    } else {
+        2
+    };
+    let sign = if local[axis] < 0.0 { -1.0 } else { 1.0 };
+    let normal = axes[axis] * sign;
+    Some(Contact {
+        point: sphere_center + normal * distance_to_face[axis],
+        normal,
+        depth: distance_to_face[axis] + radius,
+    })
+}
+
+/// Separating axis test between two oriented boxes.
+fn cuboid_cuboid(ca: Vec3, aa: [Vec3; 3], ha: Vec3, cb: Vec3, ab: [Vec3; 3], hb: Vec3) -> Option<Contact> {
+    let delta = cb - ca;
+    let mut best_depth = f32::MAX;
+    let mut best_axis = Vec3::X;
+
+    let mut test_axis = |axis: Vec3| -> bool {
+        let length = axis.length();
+        if length < 1e-6 {
+            // Degenerate cross product of parallel edges
+            return true;
+        }
+        let axis = axis / length;
+        let projection_a = aa[0].dot(axis).abs() * ha.x + aa[1].dot(axis).abs() * ha.y + aa[2].dot(axis).abs() * ha.z;
+        let projection_b = ab[0].dot(axis).abs() * hb.x + ab[1].dot(axis).abs() * hb.y + ab[2].dot(axis).abs() * hb.z;
+        let distance = delta.dot(axis);
+        let depth = projection_a + projection_b - distance.abs();
+        if depth <= 0.0 {
+            return false;
+        }
+        if depth < best_depth {
+            best_depth = depth;
+            best_axis = if distance < 0.0 { -axis } else { axis };
+        }
+        true
+    };
+
+    for axis in aa.iter().chain(ab.iter()) {
+        if !test_axis(*axis) {
+            return None;
+        }
+    }
+    for axis_a in aa.iter() {
+        for axis_b in ab.iter() {
+            if !test_axis(axis_a.cross(*axis_b)) {
+                return None;
+            }
+        }
+    }
+
+    let point_on_a = closest_point_on_cuboid(ca, aa, ha, cb);
+    let point_on_b = closest_point_on_cuboid(cb, ab, hb, ca);
+    Some(Contact {
+        point: (point_on_a + point_on_b) * 0.5,
+        normal: best_axis,
+        depth: best_depth,
+    })
+}
+
+fn closest_point_on_cuboid(center: Vec3, axes: [Vec3; 3], half_extents: Vec3, point: Vec3) -> Vec3 {
+    let delta = point - center;
+    let mut closest = center;
+    for i in 0..3 {
+        closest += axes[i] * delta.dot(axes[i]).clamp(-half_extents[i], half_extents[i]);
+    }
+    closest
+}
+
+fn closest_point_on_segment(start: Vec3, end: Vec3, point: Vec3) -> Vec3 {
+    let segment = end - start;
+    let length_squared = segment.length_squared();
+    if length_squared < f32::EPSILON {
+        return start;
+    }
+    let t = ((point - start).dot(segment) / length_squared).clamp(0.0, 1.0);
+    start + segment * t
+}
+
+/// Closest points between the segments `sa..ea` and `sb..eb`.
+fn closest_points_between_segments(sa: Vec3, ea: Vec3, sb: Vec3, eb: Vec3) -> (Vec3, Vec3) {
+    let da = ea - sa;
+    let db = eb - sb;
+    let r = sa - sb;
+    let a = da.length_squared();
+    let e = db.length_squared();
+    let f = db.dot(r);
+
+    if a < f32::EPSILON && e < f32::EPSILON {
+        return (sa, sb);
+    }
+    let (s, t) = if a < f32::EPSILON {
+        (0.0, (f / e).clamp(0.0, 1.0))
+    } else {
+        let c = da.dot(r);
+        if e < f32::EPSILON {
+            ((-c / a).clamp(0.0, 1.0), 0.0)
+        } else {
+            let b = da.dot(db);
+            let denominator = a * e - b * b;
+            let mut s = if denominator > f32::EPSILON {
+                ((b * f - c * e) / denominator).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let mut t = (b * s + f) / e;
+            if t < 0.0 {
+                t = 0.0;
+                s = (-c / a).clamp(0.0, 1.0);
+            } else if t > 1.0 {
+                t = 1.0;
+                s = ((b - c) / a).clamp(0.0, 1.0);
+            }
+            (s, t)
+        }
+    };
+    (sa + da * s, sb + db * t)
+}
+
+/// Result of a ray cast against a shape.
+#[derive(Debug, Clone, Copy)]
+pub struct RayHit {
+    pub distance: f32,
+    pub point: Vec3,
+    pub normal: Vec3,
+}
+
+/// Casts a ray with a normalized `direction` against a shape.
+/// Returns the first point where the ray enters the shape within `max_distance`.
+/// Rays starting inside of the shape do not hit it.
+pub fn raycast(shape: &WorldShape, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<RayHit> {
+    let hit = match *shape {
+        WorldShape::Sphere { center, radius } => raycast_sphere(center, radius, origin, direction),
+        WorldShape::Cuboid { center, axes, half_extents } => raycast_cuboid(center, axes, half_extents, origin, direction),
+        WorldShape::Capsule { start, end, radius } => raycast_capsule(start, end, radius, origin, direction),
+    };
+    hit.filter(|hit| hit.distance >= 0.0 && hit.distance <= max_distance)
+}
+
+fn raycast_sphere(center: Vec3, radius: f32, origin: Vec3, direction: Vec3) -> Option<RayHit> {
+    let offset = origin - center;
+    let b = offset.dot(direction);
+    let c = offset.length_squared() - radius * radius;
+    let discriminant = b * b - c;
+    if c < 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    let distance = -b - discriminant.sqrt();
+    let point = origin + direction * distance;
+    Some(RayHit {
+        distance,
+        point,
+        normal: (point - center) / radius,
+    })
+}
+
+/// Slab test in the local space of the box.
+fn raycast_cuboid(center: Vec3, axes: [Vec3; 3], half_extents: Vec3, origin: Vec3, direction: Vec3) -> Option<RayHit> {
+    let offset = origin - center;
+    let mut t_enter = f32::MIN;
+    let mut t_exit = f32::MAX;
+    let mut normal = Vec3::ZERO;
+
+    for i in 0..3 {
+        let local_origin = offset.dot(axes[i]);
+        let local_direction = direction.dot(axes[i]);
+        if local_direction.abs() < f32::EPSILON {
+            if local_origin.abs() > half_extents[i] {
+                return None;
+            }
+            continue;
+        }
+        let t1 = (-half_extents[i] - local_origin) / local_direction;
+        let t2 = (half_extents[i] - local_origin) / local_direction;
+        let (near, far) = if t1 < t2 { (t1, t2) } else { (t2, t1) };
+        if near > t_enter {
+            t_enter = near;
+            normal = if local_direction > 0.0 { -axes[i] } else { axes[i] };
+        }
+        t_exit = t_exit.min(far);
+        if t_enter > t_exit {
+            return None;
+        }
+    }
+    if t_enter < 0.0 {
+        return None;
+    }
+    Some(RayHit {
+        distance: t_enter,
+        point: origin + direction * t_enter,
+        normal,
+    })
+}
+
+fn raycast_capsule(start: Vec3, end: Vec3, radius: f32, origin: Vec3, direction: Vec3) -> Option<RayHit> {
+    let axis = end - start;
+    let offset = origin - start;
+    let axis_length_squared = axis.length_squared();
+    let axis_direction = axis.dot(direction);
+    let axis_offset = axis.dot(offset);
+
+    // Hit on the cylindrical part of the capsule
+    let a = axis_length_squared - axis_direction * axis_direction;
+    if a > f32::EPSILON {
+        let b = axis_length_squared * direction.dot(offset) - axis_offset * axis_direction;
+        let c = axis_length_squared * offset.length_squared() - axis_offset * axis_offset - radius * radius * axis_length_squared;
+        let discriminant = b * b - a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let distance = (-b - discriminant.sqrt()) / a;
+        let height = axis_offset + distance * axis_direction;
+        if height > 0.0 && height < axis_length_squared {
+            if c < 0.0 {
+                return None;
+            }
+            let point = origin + direction * distance;
+            return Some(RayHit {
+                distance,
+                point,
+                normal: (point - closest_point_on_segment(start, end, point)).normalize_or_zero(),
+            });
+        }
+    }
+
+    // Hit on one of the caps
+    [start, end].into_iter()
+        .filter_map(|cap| raycast_sphere(cap, radius, origin, direction))
+        .min_by(|a, b| a.distance.total_cmp(&b.distance))
+}
+
+/// Entry of the broad phase. Stores a copy of the physics state of a collider for the current tick.
+struct Body {
+    entity: Entity,
+    shape: WorldShape,
+    min: Vec3,
+    max: Vec3,
+    inverse_mass: f32,
+    velocity: Vec3,
+    correction: Vec3,
+}
+
+/// Broad phase: sweep and prune along the X axis.
+/// Returns all pairs of bodies whose bounding boxes overlap.
+fn broad_phase(bodies: &mut [Body]) -> Vec<(usize, usize)> {
+    bodies.sort_by(|a, b| a.min.x.total_cmp(&b.min.x));
+    let mut pairs = Vec::new();
+    for i in 0..bodies.len() {
+        for j in (i + 1)..bodies.len() {
+            if bodies[j].min.x > bodies[i].max.x {
+                break;
+            }
+            let overlap = bodies[i].min.y <= bodies[j].max.y && bodies[j].min.y <= bodies[i].max.y
+                && bodies[i].min.z <= bodies[j].max.z && bodies[j].min.z <= bodies[i].max.z;
+            let both_static = bodies[i].inverse_mass == 0.0 && bodies[j].inverse_mass == 0.0;
+            if overlap && !both_static {
+                pairs.push((i, j));
+            }
+        }
+    }
+    pairs
+}
+
+/// System to detect and resolve collisions between all entities with a collider.
+/// Overlapping bodies are pushed apart and receive an impulse weighted by their mass,
+/// so heavy entities push lighter ones. A `CollisionEvent` is sent for every contact.
+#[allow(clippy::type_complexity)]
+pub fn resolve_collisions (
+    mut query: Query<(Entity, &Collider, &mut Position, &Rotation, Option<&Mass>, Option<&mut Velocity>)>,
+    mut collision_events: EventWriter<CollisionEvent>,
+) {
+    let mut bodies: Vec<Body> = query.iter()
+        .map(|(entity, collider, position, rotation, mass, velocity)| {
+            let shape = WorldShape::new(collider, position.0, rotation.0);
+            let (min, max) = shape.aabb();
+            let inverse_mass = match (mass, velocity) {
+                (Some(mass), Some(_)) if mass.0 > 0.0 => 1.0 / mass.0,
+                _ => 0.0,
+            };
+            Body {
+                entity,
+                shape,
+                min,
+                max,
+                inverse_mass,
+                velocity: velocity.map_or(Vec3::ZERO, |v| v.0),
+                correction: Vec3::ZERO,
+            }
+        })
+        .collect();
+
+    for (i, j) in broad_phase(&mut bodies) {
+        let Some(contact) = contact(&bodies[i].shape, &bodies[j].shape) else {
+            continue;
+        };
+        let inverse_mass_i = bodies[i].inverse_mass;
+        let inverse_mass_j = bodies[j].inverse_mass;
+        let total_inverse_mass = inverse_mass_i + inverse_mass_j;
+        if total_inverse_mass <= 0.0 {
+            continue;
+        }
+
+        // Positional correction to separate the bodies
+        let correction = contact.normal * contact.depth / total_inverse_mass;
+        bodies[i].correction -= correction * inverse_mass_i;
+        bodies[j].correction += correction * inverse_mass_j;
+
+        // Impulse along the contact normal if the bodies move towards each other
+        let relative_velocity = (bodies[j].velocity - bodies[i].velocity).dot(contact.normal);
+        let mut impulse = 0.0;
+        if relative_velocity < 0.0 {
+            impulse = -(1.0 + RESTITUTION) * relative_velocity / total_inverse_mass;
+            bodies[i].velocity -= contact.normal * impulse * inverse_mass_i;
+            bodies[j].velocity += contact.normal * impulse * inverse_mass_j;
+        }
+
+        collision_events.send(CollisionEvent {
+            a: bodies[i].entity,
+            b: bodies[j].entity,
+            point: contact.point,
+            normal: contact.normal,
+            impulse,
+        });
+    }
+
+    for body in bodies.iter().filter(|body| body.inverse_mass > 0.0) {
+        if let Ok((_, _, mut position, _, _, Some(mut velocity))) = query.get_mut(body.entity) {
+            position.0 += body.correction;
+            velocity.0 = body.velocity;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-4;
+
+    fn sphere(center: Vec3, radius: f32) -> WorldShape {
+        WorldShape::new(&Collider::sphere(radius), center, Quat::IDENTITY)
+    }
+
+    fn cuboid(center: Vec3, half_extents: Vec3, rotation: Quat) -> WorldShape {
+        WorldShape::new(&Collider::cuboid(half_extents), center, rotation)
+    }
+
+    fn capsule(center: Vec3, half_height: f32, radius: f32, rotation: Quat) -> WorldShape {
+        WorldShape::new(&Collider::capsule(half_height, radius), center, rotation)
+    }
+
+    #[test]
+    fn overlapping_spheres_touch() {
+        let hit = contact(&sphere(Vec3::ZERO, 1.0), &sphere(Vec3::new(1.5, 0.0, 0.0), 1.0)).unwrap();
+        assert!((hit.depth - 0.5).abs() < EPSILON);
+        assert!(hit.normal.abs_diff_eq(Vec3::X, EPSILON));
+        assert!(hit.point.abs_diff_eq(Vec3::new(0.75, 0.0, 0.0), EPSILON));
+    }
+
+    #[test]
+    fn separated_spheres_do_not_touch() {
+        assert!(contact(&sphere(Vec3::ZERO, 1.0), &sphere(Vec3::new(2.5, 0.0, 0.0), 1.0)).is_none());
+    }
+
+    #[test]
+    fn swapped_shapes_flip_the_normal() {
+        let a = cuboid(Vec3::ZERO, Vec3::ONE, Quat::IDENTITY);
+        let b = sphere(Vec3::new(0.0, 1.5, 0.0), 1.0);
+        let forward = contact(&a, &b).unwrap();
+        let backward = contact(&b, &a).unwrap();
+        assert!(forward.normal.abs_diff_eq(Vec3::Y, EPSILON));
+        assert!(backward.normal.abs_diff_eq(-Vec3::Y, EPSILON));
+        assert!((forward.depth - backward.depth).abs() < EPSILON);
+    }
+
+    #[test]
+    fn sphere_inside_of_box_is_pushed_out_through_the_closest_face() {
+        let hit = contact(
+            &cuboid(Vec3::ZERO, Vec3::new(2.0, 1.0, 2.0), Quat::IDENTITY),
+            &sphere(Vec3::new(0.0, -0.8, 0.0), 0.5),
+        ).unwrap();
+        assert!(hit.normal.abs_diff_eq(-Vec3::Y, EPSILON));
+        assert!((hit.depth - 0.7).abs() < EPSILON);
+    }
+
+    #[test]
+    fn boxes_overlapping_on_one_axis_are_separated_along_it() {
+        let hit = contact(
+            &cuboid(Vec3::ZERO, Vec3::ONE, Quat::IDENTITY),
+            &cuboid(Vec3::new(1.8, 0.5, 0.0), Vec3::ONE, Quat::IDENTITY),
+        ).unwrap();
+        assert!(hit.normal.abs_diff_eq(Vec3::X, EPSILON));
+        assert!((hit.depth - 0.2).abs() < EPSILON);
+    }
+
+    #[test]
+    fn rotated_box_next_to_a_corner_does_not_touch() {
+        // The bounding boxes overlap, but the face of the rotated box separates them.
+        let rotated = Quat::from_rotation_y(std::f32::consts::FRAC_PI_4);
+        assert!(contact(
+            &cuboid(Vec3::ZERO, Vec3::ONE, Quat::IDENTITY),
+            &cuboid(Vec3::new(2.3, 0.0, 2.3), Vec3::ONE, rotated),
+        ).is_none());
+    }
+
+    #[test]
+    fn rotated_boxes_touch_along_the_face_normal() {
+        let rotated = Quat::from_rotation_y(std::f32::consts::FRAC_PI_4);
+        let hit = contact(
+            &cuboid(Vec3::ZERO, Vec3::ONE, rotated),
+            &cuboid(Vec3::new(2.0, 0.0, 0.0), Vec3::ONE, Quat::IDENTITY),
+        ).unwrap();
+        assert!(hit.normal.abs_diff_eq(Vec3::X, EPSILON));
+        assert!((hit.depth - (std::f32::consts::SQRT_2 - 1.0)).abs() < EPSILON);
+    }
+
+    #[test]
+    fn parallel_capsules_touch_along_their_sides() {
+        let hit = contact(
+            &capsule(Vec3::ZERO, 2.0, 0.5, Quat::IDENTITY),
+            &capsule(Vec3::new(0.8, 1.0, 0.0), 2.0, 0.5, Quat::IDENTITY),
+        ).unwrap();
+        assert!(hit.normal.abs_diff_eq(Vec3::X, EPSILON));
+        assert!((hit.depth - 0.2).abs() < EPSILON);
+    }
+
+    #[test]
+    fn sphere_touches_the_cap_of_a_capsule() {
+        let hit = contact(
+            &sphere(Vec3::new(0.0, 3.0, 0.0), 0.5),
+            &capsule(Vec3::ZERO, 2.0, 1.0, Quat::IDENTITY),
+        ).unwrap();
+        assert!(hit.normal.abs_diff_eq(-Vec3::Y, EPSILON));
+        assert!((hit.depth - 0.5).abs() < EPSILON);
+        assert!(contact(
+            &sphere(Vec3::new(0.0, 3.6, 0.0), 0.5),
+            &capsule(Vec3::ZERO, 2.0, 1.0, Quat::IDENTITY),
+        ).is_none());
+    }
+
+    #[test]
+    fn lying_capsule_touches_a_box_below() {
+        let lying = Quat::from_rotation_z(std::f32::consts::FRAC_PI_2);
+        let hit = contact(
+            &cuboid(Vec3::ZERO, Vec3::new(5.0, 1.0, 5.0), Quat::IDENTITY),
+            &capsule(Vec3::new(0.0, 1.4, 0.0), 2.0, 0.5, lying),
+        ).unwrap();
+        assert!(hit.normal.abs_diff_eq(Vec3::Y, EPSILON));
+        assert!((hit.depth - 0.1).abs() < EPSILON);
+    }
+
+    #[test]
+    fn ray_hits_the_front_of_a_sphere() {
+        let hit = raycast(&sphere(Vec3::new(0.0, 0.0, 10.0), 2.0), Vec3::ZERO, Vec3::Z, 100.0).unwrap();
+        assert!((hit.distance - 8.0).abs() < EPSILON);
+        assert!(hit.normal.abs_diff_eq(-Vec3::Z, EPSILON));
+    }
+
+    #[test]
+    fn ray_hits_the_face_of_a_rotated_box() {
+        let rotation = Quat::from_rotation_y(std::f32::consts::FRAC_PI_2);
+        let shape = cuboid(Vec3::new(10.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 3.0), rotation);
+        let hit = raycast(&shape, Vec3::ZERO, Vec3::X, 100.0).unwrap();
+        assert!((hit.distance - 7.0).abs() < EPSILON);
+        assert!(hit.normal.abs_diff_eq(-Vec3::X, EPSILON));
+        assert!(hit.point.abs_diff_eq(Vec3::new(7.0, 0.0, 0.0), EPSILON));
+    }
+
+    #[test]
+    fn ray_hits_the_side_and_the_cap_of_a_capsule() {
+        let shape = capsule(Vec3::new(0.0, 0.0, 10.0), 2.0, 1.0, Quat::IDENTITY);
+        let side = raycast(&shape, Vec3::new(0.0, 1.0, 0.0), Vec3::Z, 100.0).unwrap();
+        assert!((side.distance - 9.0).abs() < EPSILON);
+        assert!(side.normal.abs_diff_eq(-Vec3::Z, EPSILON));
+
+        let cap = raycast(&shape, Vec3::new(0.0, 10.0, 10.0), -Vec3::Y, 100.0).unwrap();
+        assert!((cap.distance - 7.0).abs() < EPSILON);
+        assert!(cap.normal.abs_diff_eq(Vec3::Y, EPSILON));
+    }
+
+    #[test]
+    fn ray_misses_beyond_max_distance_and_from_inside() {
+        let shape = cuboid(Vec3::new(0.0, 0.0, 10.0), Vec3::ONE, Quat::IDENTITY);
+        assert!(raycast(&shape, Vec3::ZERO, Vec3::Z, 8.0).is_none());
+        assert!(raycast(&shape, Vec3::new(0.0, 0.0, 10.0), Vec3::Z, 100.0).is_none());
+        assert!(raycast(&sphere(Vec3::ZERO, 1.0), Vec3::ZERO, Vec3::Z, 100.0).is_none());
+        assert!(raycast(&capsule(Vec3::ZERO, 1.0, 1.0, Quat::IDENTITY), Vec3::ZERO, Vec3::Z, 100.0).is_none());
+    }
+
+    #[test]
+    fn ray_passing_beside_a_shape_misses() {
+        let direction = Vec3::Z;
+        let origin = Vec3::new(3.0, 0.0, 0.0);
+        assert!(raycast(&sphere(Vec3::new(0.0, 0.0, 10.0), 2.0), origin, direction, 100.0).is_none());
+        assert!(raycast(&cuboid(Vec3::new(0.0, 0.0, 10.0), Vec3::splat(2.0), Quat::IDENTITY), origin, direction, 100.0).is_none());
+        assert!(raycast(&capsule(Vec3::new(0.0, 0.0, 10.0), 2.0, 2.0, Quat::IDENTITY), origin, direction, 100.0).is_none());
+    }
+}
@@ -0,0 +1,404 @@
+use bevy::prelude::*;
+use bevy::window::PrimaryWindow;
+use serde::{Deserialize, Serialize};
+
+use crate::camera::{PlayerCamera, Zoom};
+use crate::input_map::{ActionInput, InputAction, InputMap};
+use crate::physics::{Position, Rotation};
+use crate::schedule::ScheduleSet;
+use crate::tank::{apply_tank_control, Player};
+use crate::terrain::Terrain;
+
+/// Maximum distance from the camera at which the mouse cursor is projected onto the terrain.
+const MAX_AIM_DISTANCE: f32 = 3000.0;
+
+/// Distance ahead of the hull tanks driven with only the keyboard aim at.
+const KEYBOARD_AIM_DISTANCE: f32 = 200.0;
+
+/// Highest number of local players in split-screen.
+pub const MAX_LOCAL_PLAYERS: usize = 4;
+
+/// Component to store what the driver of a tank wants it to do in the current physics tick.
+/// Written by the producer of the `ControlSource` of the tank and turned into forces by `apply_tank_control`.
+/// `throttle` and `steer` are in the range `-1.0..=1.0`, a positive steer turns left.
+/// `aim` is the point in world space the turret is aimed at.
+#[derive(Component, Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct TankControl {
+    pub throttle: f32,
+    pub steer: f32,
+    pub aim: Vec3,
+    pub fire: bool,
+}
+
+/// Component to store what writes the `TankControl` of a tank.
+/// Every tank has exactly one source, each source has its own system producing the controls.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlSource {
+    /// Driven with the keyboard and aimed with the mouse.
+    KeyboardMouse,
+    /// Driven with the second keyboard layout, the turret aims ahead of the hull.
+    Keyboard,
+    /// Driven and aimed with a gamepad.
+    Gamepad(Gamepad),
+    /// Driven by an `AiController`.
+    Ai,
+    /// Driven by a `ScriptedControl` sequence.
+    Scripted,
+    /// Not driven at all, e.g. while the player is spectating. The controls are released.
+    Idle,
+    /// Driven by the controls received over the network, from a client on the server or from the server on a client.
+    Network,
+    /// Driven by the controls recorded in a replay.
+    Replay,
+}
+
+/// A single step of a scripted control sequence. The controls are held for `duration` seconds.
+/// The `aim` of the controls is relative to the hull, so a sequence drives every tank the same way.
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptedStep {
+    pub duration: f32,
+    pub control: TankControl,
+}
+
+/// Component for tanks driven by a fixed sequence of controls, e.g. for demos and tests.
+/// After the last step the controls are released, or the sequence starts again if `looping` is set.
+#[derive(Component, Debug, Clone)]
+pub struct ScriptedControl {
+    pub steps: Vec<ScriptedStep>,
+    pub looping: bool,
+    pub elapsed: f32,
+}
+
+impl ScriptedControl {
+    pub fn new(steps: Vec<ScriptedStep>, looping: bool) -> Self {
+        ScriptedControl {
+            steps,
+            looping,
+            elapsed: 0.0,
+        }
+    }
+
+    /// A looping sequence showing off driving, turning and shooting, for the tanks of the local players with `--demo`.
+    pub fn demo() -> Self {
+        let ahead = Vec3::new(0.0, 0.0, KEYBOARD_AIM_DISTANCE);
+        let step = |duration, throttle, steer, aim, fire| ScriptedStep {
+            duration,
+            control: TankControl {
+                throttle,
+                steer,
+                aim,
+                fire,
+            },
+        };
+        ScriptedControl::new(vec![
+            step(4.0, 1.0, 0.0, ahead, false),
+            step(2.5, 1.0, 1.0, ahead, false),
+            step(1.0, 0.0, 0.0, Vec3::new(-KEYBOARD_AIM_DISTANCE, 0.0, 0.0), true),
+            step(3.0, 1.0, -0.5, ahead, true),
+            step(2.0, -1.0, 0.0, Vec3::new(KEYBOARD_AIM_DISTANCE, 0.0, KEYBOARD_AIM_DISTANCE), false),
+        ], true)
+    }
+
+    /// The controls at the current point of the sequence.
+    pub fn current(&self) -> Option<TankControl> {
+        let total: f32 = self.steps.iter().map(|step| step.duration).sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut time = if self.looping { self.elapsed % total } else { self.elapsed };
+        for step in self.steps.iter() {
+            if time < step.duration {
+                return Some(step.control);
+            }
+            time -= step.duration;
+        }
+        None
+    }
+}
+
+/// Resource to store the settings for gamepad controls.
+/// Stick deflections below `deadzone` are ignored. The right stick turns the aim point
+/// around the tank with `aim_speed` radians per second and moves it away or closer with `aim_distance_speed` units per second.
+#[derive(Resource, Debug)]
+pub struct GamepadControlSettings {
+    pub deadzone: f32,
+    pub aim_speed: f32,
+    pub aim_distance_speed: f32,
+    pub min_aim_distance: f32,
+    pub max_aim_distance: f32,
+}
+
+impl Default for GamepadControlSettings {
+    fn default() -> Self {
+        GamepadControlSettings {
+            deadzone: 0.15,
+            aim_speed: 1.5,
+            aim_distance_speed: 150.0,
+            min_aim_distance: 30.0,
+            max_aim_distance: 600.0,
+        }
+    }
+}
+
+/// Resource to store the devices of the local players, indexed by the number of the `Player`.
+/// The first player uses keyboard and mouse or a gamepad, the others get a gamepad each when enough are connected
+/// and share the keyboard with the second layout otherwise. A dedicated server has no local players.
+/// With `demo` the tanks of the local players are driven by `ScriptedControl::demo` instead of their devices.
+#[derive(Resource, Debug, Clone)]
+pub struct LocalPlayers {
+    pub sources: Vec<ControlSource>,
+    pub demo: bool,
+}
+
+impl LocalPlayers {
+    pub fn new(count: usize, demo: bool) -> Self {
+        LocalPlayers {
+            sources: (0..count.min(MAX_LOCAL_PLAYERS))
+                .map(|index| if index == 0 { ControlSource::KeyboardMouse } else { ControlSource::Keyboard })
+                .collect(),
+            demo,
+        }
+    }
+
+    /// The device of a local player.
+    pub fn source(&self, index: usize) -> ControlSource {
+        self.sources.get(index).copied().unwrap_or(ControlSource::Idle)
+    }
+}
+
+/// Plugin for the producers of tank controls.
+/// `local_players` is the number of players on this machine, each gets their own tank and camera.
+/// With `demo` their tanks drive a scripted demo sequence.
+pub struct ControlPlugin {
+    pub local_players: usize,
+    pub demo: bool,
+}
+
+impl Default for ControlPlugin {
+    fn default() -> Self {
+        ControlPlugin {
+            local_players: 1,
+            demo: false,
+        }
+    }
+}
+
+impl Plugin for ControlPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<GamepadControlSettings>()
+            .insert_resource(LocalPlayers::new(self.local_players, self.demo))
+            .add_systems(Update, (
+                switch_player_control_source,
+                mouse_aim_control,
+            ).chain().in_set(ScheduleSet::Input))
+            .add_systems(FixedUpdate, (
+                keyboard_control,
+                gamepad_control,
+                scripted_control,
+            ).before(apply_tank_control).in_set(ScheduleSet::Control));
+    }
+}
+
+/// System to produce the controls of keyboard driven tanks from the actions of the `InputMap`.
+/// Tanks driven with the second keyboard layout aim ahead of their hull.
+fn keyboard_control(
+    mut query: Query<(&mut TankControl, &ControlSource, &Position, &Rotation)>,
+    input: ActionInput,
+) {
+    for (mut control, source, position, rotation) in query.iter_mut() {
+        if !matches!(*source, ControlSource::KeyboardMouse | ControlSource::Keyboard) {
+            continue;
+        }
+        control.throttle = 0.0;
+        control.steer = 0.0;
+        if input.source_pressed(*source, InputAction::Forward) {
+            control.throttle += 1.0;
+        }
+        if input.source_pressed(*source, InputAction::Reverse) {
+            control.throttle -= 1.0;
+        }
+        if input.source_pressed(*source, InputAction::TurnLeft) {
+            control.steer += 1.0;
+        }
+        if input.source_pressed(*source, InputAction::TurnRight) {
+            control.steer -= 1.0;
+        }
+        control.fire = input.source_pressed(*source, InputAction::Fire);
+        if *source == ControlSource::Keyboard {
+            control.aim = position.0 + rotation.0.mul_vec3(Vec3::Z * KEYBOARD_AIM_DISTANCE);
+        }
+    }
+}
+
+/// System to aim keyboard driven tanks at the point on the terrain below the mouse cursor.
+/// Through the gunner sight they aim at the point in the center of the sight instead.
+/// The cursor is projected through the camera of the player using keyboard and mouse.
+fn mouse_aim_control(
+    mut query: Query<(&mut TankControl, &ControlSource)>,
+    window_query: Query<&Window, With<PrimaryWindow>>,
+    camera_query: Query<(&Camera, &GlobalTransform, &Zoom, &PlayerCamera)>,
+    local_players: Res<LocalPlayers>,
+    terrain: Option<Res<Terrain>>,
+) {
+    let Ok(window) = window_query.get_single() else {
+        return;
+    };
+    for (camera, camera_transform, zoom, player_camera) in camera_query.iter() {
+        if local_players.source(player_camera.0) != ControlSource::KeyboardMouse {
+            continue;
+        }
+        let ray = if zoom.sight {
+            Some(Ray {
+                origin: camera_transform.translation(),
+                direction: camera_transform.forward(),
+            })
+        } else {
+            let viewport_origin = camera.logical_viewport_rect().map_or(Vec2::ZERO, |rect| rect.min);
+            window.cursor_position().and_then(|cursor| camera.viewport_to_world(camera_transform, cursor - viewport_origin))
+        };
+        let Some(ray) = ray else {
+            continue;
+        };
+        let point = match terrain.as_deref() {
+            Some(terrain) => terrain.raycast(ray.origin, ray.direction, MAX_AIM_DISTANCE).map(|hit| hit.point),
+            None => ray.intersect_plane(Vec3::ZERO, Vec3::Y).map(|distance| ray.get_point(distance)),
+        };
+        let Some(point) = point else {
+            continue;
+        };
+        for (mut control, source) in query.iter_mut() {
+            if *source == ControlSource::KeyboardMouse {
+                control.aim = point;
+            }
+        }
+    }
+}
+
+/// Read a stick of a gamepad with a radial deadzone.
+/// The deflection outside of the deadzone is rescaled to the range `0.0..=1.0`.
+pub fn read_stick(axes: &Axis<GamepadAxis>, gamepad: Gamepad, x: GamepadAxisType, y: GamepadAxisType, deadzone: f32) -> Vec2 {
+    let stick = Vec2::new(
+        axes.get(GamepadAxis::new(gamepad, x)).unwrap_or(0.0),
+        axes.get(GamepadAxis::new(gamepad, y)).unwrap_or(0.0),
+    );
+    let length = stick.length();
+    if length <= deadzone {
+        return Vec2::ZERO;
+    }
+    stick / length * ((length - deadzone) / (1.0 - deadzone)).min(1.0)
+}
+
+/// System to produce the controls of gamepad driven tanks.
+/// The left stick controls throttle and steering, the right stick moves the aim point around the tank.
+/// Firing uses the gamepad buttons bound in the `InputMap`.
+fn gamepad_control(
+    mut query: Query<(&mut TankControl, &ControlSource, &Position)>,
+    axes: Res<Axis<GamepadAxis>>,
+    input: ActionInput,
+    settings: Res<GamepadControlSettings>,
+    time: Res<Time>,
+) {
+    for (mut control, source, position) in query.iter_mut() {
+        let ControlSource::Gamepad(gamepad) = *source else {
+            continue;
+        };
+        let left_stick = read_stick(&axes, gamepad, GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY, settings.deadzone);
+        let right_stick = read_stick(&axes, gamepad, GamepadAxisType::RightStickX, GamepadAxisType::RightStickY, settings.deadzone);
+        control.throttle = left_stick.y;
+        control.steer = -left_stick.x;
+
+        let mut offset = control.aim - position.0;
+        offset.y = 0.0;
+        let distance = (offset.length() + right_stick.y * settings.aim_distance_speed * time.delta_seconds())
+            .clamp(settings.min_aim_distance, settings.max_aim_distance);
+        let direction = Quat::from_rotation_y(-right_stick.x * settings.aim_speed * time.delta_seconds())
+            .mul_vec3(offset.try_normalize().unwrap_or(Vec3::Z));
+        control.aim = position.0 + direction * distance;
+
+        control.fire = input.gamepad_pressed(gamepad, InputAction::Fire);
+    }
+}
+
+/// System to assign the devices of the local players and pass them on to their tanks.
+/// The first player switches to the device that was used last: pressing a button or moving a stick on a gamepad
+/// no other player uses switches to that gamepad, pressing a key of the normal layout or a mouse button switches
+/// back to keyboard and mouse. The other players get a free gamepad when one is connected and use the second
+/// keyboard layout while they have none. Tanks of spectating players keep their released controls.
+#[allow(clippy::too_many_arguments)]
+fn switch_player_control_source(
+    mut query: Query<(&Player, &mut ControlSource)>,
+    mut local_players: ResMut<LocalPlayers>,
+    gamepads: Res<Gamepads>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    input_map: Res<InputMap>,
+    settings: Res<GamepadControlSettings>,
+) {
+    if local_players.sources.is_empty() {
+        return;
+    }
+    let shared_keyboard = local_players.sources.contains(&ControlSource::Keyboard);
+    for index in 1..local_players.sources.len() {
+        let source = local_players.sources[index];
+        let new_source = match source {
+            ControlSource::Gamepad(gamepad) if !gamepads.contains(gamepad) => ControlSource::Keyboard,
+            ControlSource::Keyboard => gamepads.iter()
+                .find(|gamepad| !local_players.sources.contains(&ControlSource::Gamepad(*gamepad)))
+                .map_or(ControlSource::Keyboard, ControlSource::Gamepad),
+            source => source,
+        };
+        local_players.sources[index] = new_source;
+    }
+
+    let used_gamepad = gamepads.iter()
+        .filter(|gamepad| !local_players.sources[1..].contains(&ControlSource::Gamepad(*gamepad)))
+        .find(|gamepad| {
+            gamepad_buttons.get_just_pressed().any(|button| button.gamepad == *gamepad)
+                || read_stick(&axes, *gamepad, GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY, settings.deadzone) != Vec2::ZERO
+                || read_stick(&axes, *gamepad, GamepadAxisType::RightStickX, GamepadAxisType::RightStickY, settings.deadzone) != Vec2::ZERO
+        });
+    let used_keyboard = keyboard_input.get_just_pressed().any(|key| !shared_keyboard || !input_map.is_secondary_key(*key))
+        || mouse_input.get_just_pressed().next().is_some();
+    let first = local_players.sources[0];
+    local_players.sources[0] = match (used_keyboard, used_gamepad) {
+        (true, _) => ControlSource::KeyboardMouse,
+        (false, Some(gamepad)) => ControlSource::Gamepad(gamepad),
+        (false, None) => match first {
+            ControlSource::Gamepad(gamepad) if !gamepads.contains(gamepad) => ControlSource::KeyboardMouse,
+            first => first,
+        },
+    };
+
+    for (player, mut source) in query.iter_mut() {
+        let new_source = local_players.source(player.0);
+        if *source != new_source && matches!(*source, ControlSource::KeyboardMouse | ControlSource::Keyboard | ControlSource::Gamepad(_)) {
+            *source = new_source;
+        }
+    }
+}
+
+/// System to produce the controls of scripted tanks.
+/// The aim of the current step is turned from the space of the hull into world space.
+fn scripted_control(
+    mut query: Query<(&mut TankControl, &mut ScriptedControl, &ControlSource, &Position, &Rotation)>,
+    time: Res<Time>,
+) {
+    for (mut control, mut script, source, position, rotation) in query.iter_mut() {
+        if *source != ControlSource::Scripted {
+            continue;
+        }
+        *control = match script.current() {
+            Some(step) => TankControl {
+                aim: position.0 + rotation.0.mul_vec3(step.aim),
+                ..step
+            },
+            None => TankControl {
+                aim: control.aim,
+                ..Default::default()
+            },
+        };
+        script.elapsed += time.delta_seconds();
+    }
+}
@@ -0,0 +1,253 @@
+use bevy::prelude::*;
+
+use crate::collision::{resolve_collisions, CollisionEvent};
+use crate::network::is_authority;
+use crate::physics::{Rotation, Velocity};
+use crate::projectile::{detect_projectile_hits, ProjectileHitEvent};
+use crate::schedule::ScheduleSet;
+
+/// Angle in radians between the projectile path and the armor normal above which a projectile always ricochets.
+const RICOCHET_ANGLE: f32 = 1.22;
+
+/// Fraction of the damage dealt by a projectile that hits but does not penetrate the armor.
+const NON_PENETRATING_DAMAGE: f32 = 0.05;
+
+/// Collision impulse below which ramming does not deal damage.
+const RAM_IMPULSE_THRESHOLD: f32 = 1500.0;
+
+/// Damage dealt per unit of collision impulse above the threshold when ramming.
+const RAM_DAMAGE_PER_IMPULSE: f32 = 0.5;
+
+/// Component to store the hit points of an entity.
+#[derive(Component, Debug)]
+pub struct Health {
+    pub current: f32,
+    pub max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Health {
+            current: max,
+            max,
+        }
+    }
+}
+
+/// Component to store the armor thickness of a tank on each side of the hull.
+#[derive(Component, Debug, Clone, Copy)]
+pub struct Armor {
+    pub front: f32,
+    pub side: f32,
+    pub rear: f32,
+}
+
+/// Side of the hull that was hit by a projectile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArmorFace {
+    Front,
+    Side,
+    Rear,
+}
+
+/// Result of a projectile hitting armor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HitOutcome {
+    /// The projectile went through the armor and dealt full damage.
+    Penetration,
+    /// The projectile hit the armor at a flat angle and bounced off without damage.
+    Ricochet,
+    /// The armor was too thick and only a small part of the damage was dealt.
+    NoPenetration,
+}
+
+/// Marker component for destroyed tanks and props.
+/// Wrecks do not react to input and their engines do not apply any force.
+#[derive(Component)]
+pub struct Wreck;
+
+/// Event sent every time a projectile hits or a tank rams an entity with health.
+/// For ramming, `shooter` is the ramming entity.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct DamageEvent {
+    pub target: Entity,
+    pub shooter: Entity,
+    pub face: ArmorFace,
+    pub outcome: HitOutcome,
+    pub damage: f32,
+    pub point: Vec3,
+}
+
+/// Event sent when an entity is destroyed.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct DestroyedEvent {
+    pub entity: Entity,
+    pub destroyed_by: Entity,
+}
+
+/// Plugin for health, armor and damage.
+/// Damage is only applied by the server or the offline game, clients take the health and wrecks over from the server.
+pub struct DamagePlugin;
+
+impl Plugin for DamagePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DamageEvent>()
+            .add_event::<DestroyedEvent>()
+            .add_systems(FixedUpdate, (
+                apply_projectile_damage.after(detect_projectile_hits),
+                apply_ram_damage.after(resolve_collisions),
+                destroy_entities,
+            ).chain().run_if(is_authority).in_set(ScheduleSet::Physics))
+            .add_systems(FixedUpdate, (
+                log_damage.after(destroy_entities),
+            ).in_set(ScheduleSet::Physics));
+    }
+}
+
+/// Determine which side of the hull a normal in the local space of the tank points to.
+pub fn armor_face(local_normal: Vec3) -> ArmorFace {
+    if local_normal.z.abs() >= local_normal.x.abs() {
+        if local_normal.z > 0.0 {
+            ArmorFace::Front
+        } else {
+            ArmorFace::Rear
+        }
+    } else {
+        ArmorFace::Side
+    }
+}
+
+/// Compute the outcome of a projectile with `penetration` hitting `thickness` armor.
+/// `impact_angle` is the angle between the projectile path and the armor normal in radians.
+/// The effective thickness grows the flatter the projectile hits the armor.
+pub fn hit_outcome(penetration: f32, thickness: f32, impact_angle: f32) -> HitOutcome {
+    if impact_angle >= RICOCHET_ANGLE {
+        return HitOutcome::Ricochet;
+    }
+    let effective_thickness = thickness / impact_angle.cos();
+    if penetration >= effective_thickness {
+        HitOutcome::Penetration
+    } else {
+        HitOutcome::NoPenetration
+    }
+}
+
+/// System to apply the damage of projectile hits to the health of the hit entity.
+/// The damage depends on the side of the hull that was hit and the impact angle.
+/// Entities without armor, like props, are always penetrated.
+fn apply_projectile_damage(
+    mut hit_events: EventReader<ProjectileHitEvent>,
+    mut target_query: Query<(&mut Health, Option<&Armor>, &Rotation), Without<Wreck>>,
+    mut damage_events: EventWriter<DamageEvent>,
+) {
+    for hit in hit_events.read() {
+        let Some(target) = hit.target else {
+            continue;
+        };
+        let Ok((mut health, armor, rotation)) = target_query.get_mut(target) else {
+            continue;
+        };
+        let Some(direction) = hit.velocity.try_normalize() else {
+            continue;
+        };
+
+        let face = armor_face(rotation.0.inverse().mul_vec3(hit.normal));
+        let outcome = match armor {
+            Some(armor) => {
+                let thickness = match face {
+                    ArmorFace::Front => armor.front,
+                    ArmorFace::Side => armor.side,
+                    ArmorFace::Rear => armor.rear,
+                };
+                let impact_angle = (-direction).angle_between(hit.normal);
+                hit_outcome(hit.penetration, thickness, impact_angle)
+            },
+            None => HitOutcome::Penetration,
+        };
+        let damage = match outcome {
+            HitOutcome::Penetration => hit.damage,
+            HitOutcome::NoPenetration => hit.damage * NON_PENETRATING_DAMAGE,
+            HitOutcome::Ricochet => 0.0,
+        };
+        health.current = (health.current - damage).max(0.0);
+
+        damage_events.send(DamageEvent {
+            target,
+            shooter: hit.shooter,
+            face,
+            outcome,
+            damage,
+            point: hit.point,
+        });
+    }
+}
+
+/// System to apply damage to static entities with health, like props, that are rammed by a moving entity.
+/// Only hard hits deal damage, so heavy and fast tanks can break through obstacles.
+#[allow(clippy::type_complexity)]
+fn apply_ram_damage(
+    mut collision_events: EventReader<CollisionEvent>,
+    mut target_query: Query<(&mut Health, &Rotation), (Without<Velocity>, Without<Wreck>)>,
+    rammer_query: Query<(), With<Velocity>>,
+    mut damage_events: EventWriter<DamageEvent>,
+) {
+    for collision in collision_events.read() {
+        if collision.impulse <= RAM_IMPULSE_THRESHOLD {
+            continue;
+        }
+        for (target, rammer, normal) in [(collision.a, collision.b, collision.normal), (collision.b, collision.a, -collision.normal)] {
+            if !rammer_query.contains(rammer) {
+                continue;
+            }
+            let Ok((mut health, rotation)) = target_query.get_mut(target) else {
+                continue;
+            };
+            let damage = (collision.impulse - RAM_IMPULSE_THRESHOLD) * RAM_DAMAGE_PER_IMPULSE;
+            health.current = (health.current - damage).max(0.0);
+            damage_events.send(DamageEvent {
+                target,
+                shooter: rammer,
+                face: armor_face(rotation.0.inverse().mul_vec3(normal)),
+                outcome: HitOutcome::Penetration,
+                damage,
+                point: collision.point,
+            });
+        }
+    }
+}
+
+/// System to turn tanks and props without health left into wrecks.
+pub fn destroy_entities(
+    mut commands: Commands,
+    mut damage_events: EventReader<DamageEvent>,
+    query: Query<&Health, Without<Wreck>>,
+    mut destroyed_events: EventWriter<DestroyedEvent>,
+) {
+    let mut destroyed = Vec::new();
+    for damage in damage_events.read() {
+        let Ok(health) = query.get(damage.target) else {
+            continue;
+        };
+        if health.current <= 0.0 && !destroyed.contains(&damage.target) {
+            destroyed.push(damage.target);
+            commands.entity(damage.target).insert(Wreck);
+            destroyed_events.send(DestroyedEvent {
+                entity: damage.target,
+                destroyed_by: damage.shooter,
+            });
+        }
+    }
+}
+
+/// System to log the hits and destroyed entities of the current physics tick.
+fn log_damage(
+    mut damage_events: EventReader<DamageEvent>,
+    mut destroyed_events: EventReader<DestroyedEvent>,
+) {
+    for damage in damage_events.read() {
+        debug!("{:?} hit {:?} on the {:?} armor at {}: {:?} for {:.0} damage", damage.shooter, damage.target, damage.face, damage.point, damage.outcome, damage.damage);
+    }
+    for destroyed in destroyed_events.read() {
+        info!("{:?} was destroyed by {:?}", destroyed.entity, destroyed.destroyed_by);
+    }
+}
@@ -0,0 +1,459 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::control::ControlSource;
+
+/// Path of the user config file the input map is loaded from and saved to.
+pub const INPUT_CONFIG_PATH: &str = "config/input.ron";
+
+/// The actions the player can bind keys and buttons to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum InputAction {
+    Forward,
+    Reverse,
+    TurnLeft,
+    TurnRight,
+    Fire,
+    ToggleView,
+    FreeLook,
+    Sight,
+    Spectate,
+    NextTank,
+    PreviousTank,
+    Pause,
+    ReplayPause,
+    ReplayFaster,
+    ReplaySlower,
+    ReplayForward,
+    ReplayBack,
+    MenuUp,
+    MenuDown,
+    MenuSelect,
+}
+
+impl InputAction {
+    /// All actions in the order they are shown in the menu.
+    pub const ALL: [InputAction; 20] = [
+        InputAction::Forward,
+        InputAction::Reverse,
+        InputAction::TurnLeft,
+        InputAction::TurnRight,
+        InputAction::Fire,
+        InputAction::ToggleView,
+        InputAction::FreeLook,
+        InputAction::Sight,
+        InputAction::Spectate,
+        InputAction::NextTank,
+        InputAction::PreviousTank,
+        InputAction::Pause,
+        InputAction::ReplayPause,
+        InputAction::ReplayFaster,
+        InputAction::ReplaySlower,
+        InputAction::ReplayForward,
+        InputAction::ReplayBack,
+        InputAction::MenuUp,
+        InputAction::MenuDown,
+        InputAction::MenuSelect,
+    ];
+
+    /// Name of the action shown in the menu.
+    pub fn label(&self) -> &'static str {
+        match self {
+            InputAction::Forward => "Vorwärts",
+            InputAction::Reverse => "Rückwärts",
+            InputAction::TurnLeft => "Links",
+            InputAction::TurnRight => "Rechts",
+            InputAction::Fire => "Feuern",
+            InputAction::ToggleView => "Ansicht wechseln",
+            InputAction::FreeLook => "Umsehen",
+            InputAction::Sight => "Visier",
+            InputAction::Spectate => "Zuschauer",
+            InputAction::NextTank => "Nächster Panzer",
+            InputAction::PreviousTank => "Vorheriger Panzer",
+            InputAction::Pause => "Pause",
+            InputAction::ReplayPause => "Wiedergabe anhalten",
+            InputAction::ReplayFaster => "Wiedergabe schneller",
+            InputAction::ReplaySlower => "Wiedergabe langsamer",
+            InputAction::ReplayForward => "Vorspulen",
+            InputAction::ReplayBack => "Zurückspulen",
+            InputAction::MenuUp => "Menü hoch",
+            InputAction::MenuDown => "Menü runter",
+            InputAction::MenuSelect => "Menü auswählen",
+        }
+    }
+
+    /// The bindings of the action if the user did not change them.
+    /// No gamepad button is used by two actions, so the menu and replay controls do not trigger each other.
+    pub fn default_bindings(&self) -> Vec<Binding> {
+        match self {
+            InputAction::Forward => vec![Binding::Key(KeyCode::W)],
+            InputAction::Reverse => vec![Binding::Key(KeyCode::S)],
+            InputAction::TurnLeft => vec![Binding::Key(KeyCode::A)],
+            InputAction::TurnRight => vec![Binding::Key(KeyCode::D)],
+            InputAction::Fire => vec![
+                Binding::Mouse(MouseButton::Left),
+                Binding::Key(KeyCode::Space),
+                Binding::Gamepad(GamepadButtonType::RightTrigger2),
+                Binding::Gamepad(GamepadButtonType::RightTrigger),
+            ],
+            InputAction::ToggleView => vec![
+                Binding::Key(KeyCode::ShiftLeft),
+                Binding::Gamepad(GamepadButtonType::North),
+            ],
+            InputAction::FreeLook => vec![Binding::Mouse(MouseButton::Right)],
+            InputAction::Sight => vec![
+                Binding::Key(KeyCode::Q),
+                Binding::Gamepad(GamepadButtonType::LeftTrigger2),
+            ],
+            InputAction::Spectate => vec![
+                Binding::Key(KeyCode::F3),
+                Binding::Gamepad(GamepadButtonType::Select),
+            ],
+            InputAction::NextTank => vec![
+                Binding::Key(KeyCode::BracketRight),
+                Binding::Gamepad(GamepadButtonType::DPadRight),
+            ],
+            InputAction::PreviousTank => vec![
+                Binding::Key(KeyCode::BracketLeft),
+                Binding::Gamepad(GamepadButtonType::DPadLeft),
+            ],
+            InputAction::Pause => vec![
+                Binding::Key(KeyCode::Escape),
+                Binding::Gamepad(GamepadButtonType::Start),
+            ],
+            InputAction::ReplayPause => vec![
+                Binding::Key(KeyCode::P),
+                Binding::Gamepad(GamepadButtonType::West),
+            ],
+            InputAction::ReplayFaster => vec![
+                Binding::Key(KeyCode::NumpadAdd),
+                Binding::Gamepad(GamepadButtonType::RightThumb),
+            ],
+            InputAction::ReplaySlower => vec![
+                Binding::Key(KeyCode::NumpadSubtract),
+                Binding::Gamepad(GamepadButtonType::LeftThumb),
+            ],
+            InputAction::ReplayForward => vec![
+                Binding::Key(KeyCode::PageUp),
+                Binding::Gamepad(GamepadButtonType::East),
+            ],
+            InputAction::ReplayBack => vec![
+                Binding::Key(KeyCode::PageDown),
+                Binding::Gamepad(GamepadButtonType::LeftTrigger),
+            ],
+            InputAction::MenuUp => vec![Binding::Gamepad(GamepadButtonType::DPadUp)],
+            InputAction::MenuDown => vec![Binding::Gamepad(GamepadButtonType::DPadDown)],
+            InputAction::MenuSelect => vec![Binding::Gamepad(GamepadButtonType::South)],
+        }
+    }
+
+    /// Whether the action is used by a second player sharing the keyboard and can be bound in the second keyboard layout.
+    pub fn has_secondary(&self) -> bool {
+        !self.default_secondary_bindings().is_empty()
+    }
+
+    /// The bindings of the action in the second keyboard layout, for a second player sharing the keyboard.
+    pub fn default_secondary_bindings(&self) -> Vec<Binding> {
+        match self {
+            InputAction::Forward => vec![Binding::Key(KeyCode::Up)],
+            InputAction::Reverse => vec![Binding::Key(KeyCode::Down)],
+            InputAction::TurnLeft => vec![Binding::Key(KeyCode::Left)],
+            InputAction::TurnRight => vec![Binding::Key(KeyCode::Right)],
+            InputAction::Fire => vec![Binding::Key(KeyCode::ControlRight)],
+            InputAction::ToggleView => vec![Binding::Key(KeyCode::ShiftRight)],
+            InputAction::Sight => vec![Binding::Key(KeyCode::Numpad0)],
+            InputAction::Spectate => vec![Binding::Key(KeyCode::Numpad5)],
+            InputAction::NextTank => vec![Binding::Key(KeyCode::Numpad6)],
+            InputAction::PreviousTank => vec![Binding::Key(KeyCode::Numpad4)],
+            InputAction::FreeLook | InputAction::Pause | InputAction::ReplayPause | InputAction::ReplayFaster
+                | InputAction::ReplaySlower | InputAction::ReplayForward | InputAction::ReplayBack
+                | InputAction::MenuUp | InputAction::MenuDown | InputAction::MenuSelect => vec![],
+        }
+    }
+}
+
+/// The two sets of bindings of the `InputMap`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// The bindings of keyboard, mouse and gamepads.
+    Primary,
+    /// The second keyboard layout, for a second player sharing the keyboard. Only keys can be bound in it.
+    Secondary,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 2] = [Layout::Primary, Layout::Secondary];
+}
+
+/// A key or button bound to an action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// A button on any gamepad.
+    Gamepad(GamepadButtonType),
+}
+
+impl Binding {
+    /// Whether the binding is on a gamepad rather than keyboard or mouse.
+    pub fn is_gamepad(&self) -> bool {
+        matches!(self, Binding::Gamepad(_))
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Binding::Key(key) => write!(f, "{:?}", key),
+            Binding::Mouse(button) => write!(f, "Maus {:?}", button),
+            Binding::Gamepad(button) => write!(f, "Pad {:?}", button),
+        }
+    }
+}
+
+/// Errors that can occur while loading or saving the input map.
+#[derive(Debug, Error)]
+pub enum InputMapError {
+    #[error("Could not access input config: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not parse input config: {0}")]
+    Parse(#[from] ron::error::SpannedError),
+    #[error("Could not write input config: {0}")]
+    Serialize(#[from] ron::Error),
+}
+
+/// Resource to store which keys and buttons trigger which action.
+/// An action can have several bindings, a binding should only be used by one action.
+/// `secondary` is the keyboard layout of a second player sharing the keyboard in split-screen.
+#[derive(Resource, Debug, Clone, Serialize, Deserialize)]
+pub struct InputMap {
+    pub bindings: BTreeMap<InputAction, Vec<Binding>>,
+    #[serde(default)]
+    pub secondary: BTreeMap<InputAction, Vec<Binding>>,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        InputMap {
+            bindings: InputAction::ALL.iter()
+                .map(|action| (*action, action.default_bindings()))
+                .collect(),
+            secondary: InputAction::ALL.iter()
+                .map(|action| (*action, action.default_secondary_bindings()))
+                .collect(),
+        }
+    }
+}
+
+impl InputMap {
+    /// Load the input map from a config file.
+    /// Actions missing in the file get their default bindings.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, InputMapError> {
+        let text = fs::read_to_string(path)?;
+        let mut map: InputMap = ron::from_str(&text)?;
+        for action in InputAction::ALL {
+            map.bindings.entry(action).or_insert_with(|| action.default_bindings());
+            map.secondary.entry(action).or_insert_with(|| action.default_secondary_bindings());
+        }
+        Ok(map)
+    }
+
+    /// Save the input map to a config file, creating the directory if needed.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), InputMapError> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let text = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        fs::write(path, text)?;
+        Ok(())
+    }
+
+    /// The bindings of an action.
+    pub fn get(&self, action: InputAction) -> &[Binding] {
+        self.get_in(Layout::Primary, action)
+    }
+
+    /// The bindings of an action in the second keyboard layout.
+    pub fn get_secondary(&self, action: InputAction) -> &[Binding] {
+        self.get_in(Layout::Secondary, action)
+    }
+
+    /// The bindings of an action in a layout.
+    pub fn get_in(&self, layout: Layout, action: InputAction) -> &[Binding] {
+        self.layout(layout).get(&action).map(|bindings| bindings.as_slice()).unwrap_or(&[])
+    }
+
+    fn layout(&self, layout: Layout) -> &BTreeMap<InputAction, Vec<Binding>> {
+        match layout {
+            Layout::Primary => &self.bindings,
+            Layout::Secondary => &self.secondary,
+        }
+    }
+
+    /// Whether a key is used by the second keyboard layout.
+    pub fn is_secondary_key(&self, key: KeyCode) -> bool {
+        self.secondary.values().flatten().any(|binding| *binding == Binding::Key(key))
+    }
+
+    /// The other actions that already use the binding, in both layouts.
+    /// Both layouts are used on the same keyboard in split-screen, so a key can only be in one of them.
+    pub fn conflicts(&self, action: InputAction, layout: Layout, binding: Binding) -> Vec<(InputAction, Layout)> {
+        Layout::ALL.into_iter()
+            .flat_map(|other_layout| self.layout(other_layout).iter()
+                .filter(move |(other, bindings)| (**other, other_layout) != (action, layout) && bindings.contains(&binding))
+                .map(move |(other, _)| (*other, other_layout)))
+            .collect()
+    }
+
+    /// Replace the bindings of an action in a layout on the same kind of device as the new binding.
+    /// Rebinding a key keeps the gamepad bindings of the action and the other way around.
+    pub fn rebind(&mut self, action: InputAction, layout: Layout, binding: Binding) {
+        let bindings = match layout {
+            Layout::Primary => &mut self.bindings,
+            Layout::Secondary => &mut self.secondary,
+        };
+        let bindings = bindings.entry(action).or_default();
+        bindings.retain(|other| other.is_gamepad() != binding.is_gamepad());
+        bindings.push(binding);
+    }
+}
+
+/// System parameter to check the state of actions instead of single keys and buttons.
+#[derive(SystemParam)]
+pub struct ActionInput<'w> {
+    map: Res<'w, InputMap>,
+    keyboard: Res<'w, Input<KeyCode>>,
+    mouse: Res<'w, Input<MouseButton>>,
+    gamepad: Res<'w, Input<GamepadButton>>,
+}
+
+impl<'w> ActionInput<'w> {
+    /// Whether a key or button of the action was pressed this frame on any device.
+    pub fn just_pressed(&self, action: InputAction) -> bool {
+        self.map.get(action).iter().any(|binding| match *binding {
+            Binding::Key(key) => self.keyboard.just_pressed(key),
+            Binding::Mouse(button) => self.mouse.just_pressed(button),
+            Binding::Gamepad(button_type) => self.gamepad.get_just_pressed().any(|button| button.button_type == button_type),
+        })
+    }
+
+    /// Whether a key or button of the action is held on the device of a control source.
+    /// Keyboard and mouse use the normal layout, keyboard only the second layout.
+    pub fn source_pressed(&self, source: ControlSource, action: InputAction) -> bool {
+        match source {
+            ControlSource::KeyboardMouse => self.keyboard_pressed(action),
+            ControlSource::Keyboard => self.map.get_secondary(action).iter().any(|binding| match *binding {
+                Binding::Key(key) => self.keyboard.pressed(key),
+                _ => false,
+            }),
+            ControlSource::Gamepad(gamepad) => self.gamepad_pressed(gamepad, action),
+            _ => false,
+        }
+    }
+
+    /// Whether a key or button of the action was pressed this frame on the device of a control source.
+    pub fn source_just_pressed(&self, source: ControlSource, action: InputAction) -> bool {
+        match source {
+            ControlSource::KeyboardMouse => self.map.get(action).iter().any(|binding| match *binding {
+                Binding::Key(key) => self.keyboard.just_pressed(key),
+                Binding::Mouse(button) => self.mouse.just_pressed(button),
+                Binding::Gamepad(_) => false,
+            }),
+            ControlSource::Keyboard => self.map.get_secondary(action).iter().any(|binding| match *binding {
+                Binding::Key(key) => self.keyboard.just_pressed(key),
+                _ => false,
+            }),
+            ControlSource::Gamepad(gamepad) => self.map.get(action).iter().any(|binding| match *binding {
+                Binding::Gamepad(button_type) => self.gamepad.just_pressed(GamepadButton::new(gamepad, button_type)),
+                _ => false,
+            }),
+            _ => false,
+        }
+    }
+
+    /// Whether a key or mouse button of the action is held.
+    pub fn keyboard_pressed(&self, action: InputAction) -> bool {
+        self.map.get(action).iter().any(|binding| match *binding {
+            Binding::Key(key) => self.keyboard.pressed(key),
+            Binding::Mouse(button) => self.mouse.pressed(button),
+            Binding::Gamepad(_) => false,
+        })
+    }
+
+    /// Whether a button of the action is held on the given gamepad.
+    pub fn gamepad_pressed(&self, gamepad: Gamepad, action: InputAction) -> bool {
+        self.map.get(action).iter().any(|binding| match *binding {
+            Binding::Gamepad(button_type) => self.gamepad.pressed(GamepadButton::new(gamepad, button_type)),
+            _ => false,
+        })
+    }
+}
+
+/// Plugin for the rebindable input map.
+pub struct InputMapPlugin;
+
+impl Plugin for InputMapPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<InputMap>()
+            .add_systems(PreStartup, (
+                load_input_map,
+            ));
+    }
+}
+
+/// System to load the input map from the user config file.
+/// The default bindings are used if the file does not exist or can not be read.
+fn load_input_map(
+    mut input_map: ResMut<InputMap>,
+) {
+    if !Path::new(INPUT_CONFIG_PATH).exists() {
+        return;
+    }
+    match InputMap::load(INPUT_CONFIG_PATH) {
+        Ok(map) => *input_map = map,
+        Err(error) => warn!("{}, using default bindings", error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_bindings_do_not_conflict() {
+        let map = InputMap::default();
+        for layout in Layout::ALL {
+            for action in InputAction::ALL {
+                for binding in map.get_in(layout, action) {
+                    assert_eq!(map.conflicts(action, layout, *binding), vec![], "{:?} of {:?}", binding, action);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn keys_of_the_second_layout_conflict_with_the_first() {
+        let map = InputMap::default();
+        let up = Binding::Key(KeyCode::Up);
+        assert_eq!(map.conflicts(InputAction::Fire, Layout::Primary, up), vec![(InputAction::Forward, Layout::Secondary)]);
+        assert_eq!(map.conflicts(InputAction::Forward, Layout::Secondary, up), vec![]);
+        assert_eq!(map.conflicts(InputAction::Reverse, Layout::Secondary, Binding::Key(KeyCode::W)), vec![(InputAction::Forward, Layout::Primary)]);
+    }
+
+    #[test]
+    fn rebinding_a_key_keeps_the_gamepad_bindings() {
+        let mut map = InputMap::default();
+        map.rebind(InputAction::ToggleView, Layout::Primary, Binding::Key(KeyCode::V));
+        assert_eq!(map.get(InputAction::ToggleView), &[Binding::Gamepad(GamepadButtonType::North), Binding::Key(KeyCode::V)]);
+        map.rebind(InputAction::ToggleView, Layout::Secondary, Binding::Key(KeyCode::Numpad1));
+        assert_eq!(map.get_secondary(InputAction::ToggleView), &[Binding::Key(KeyCode::Numpad1)]);
+    }
+}
@@ -1,7 +1,24 @@
+use std::net::ToSocketAddrs;
+use std::str::FromStr;
+use std::time::Duration;
+
 use bevy::{prelude::*, window::PresentMode};
+use bevy::app::ScheduleRunnerPlugin;
+use bevy::input::InputPlugin;
+use bevy::log::LogPlugin;
+use bevy::render::texture::ImagePlugin;
+use thiserror::Error;
 
 mod map;
-use map::MapPlugin;
+use map::{MapPlugin, MapSource};
+use map::generator::MapGeneratorSettings;
+
+mod map_definition;
+
+mod terrain;
+
+mod prop;
+use prop::PropPlugin;
 
 mod camera;
 use camera::CameraPlugin;
@@ -9,12 +26,35 @@ use camera::CameraPlugin;
 mod physics;
 use physics::PhysicsPlugin;
 
+mod collision;
+use collision::CollisionPlugin;
+
 mod tank;
 use tank::TankPlugin;
 
+mod tank_definition;
+
+mod control;
+use control::ControlPlugin;
+
+mod turret;
+use turret::TurretPlugin;
+
+mod projectile;
+use projectile::ProjectilePlugin;
+
+mod damage;
+use damage::DamagePlugin;
+
+mod ai;
+use ai::{AiPlugin, Difficulty};
+
 mod ui;
 use ui::UIPlugin;
 
+mod input_map;
+use input_map::InputMapPlugin;
+
 mod asset_loader;
 use asset_loader::AssetLoaderPlugin;
 
@@ -24,7 +64,201 @@ use menu::MenuPlugin;
 pub mod schedule;
 use schedule::SchedulePlugin;
 
-fn main() {
+mod network;
+use network::{NetworkMode, NetworkPlugin, DEFAULT_PORT};
+use network::link::LinkConditions;
+
+mod replay;
+use replay::{Replay, ReplayError, ReplayMode, ReplayPlugin};
+
+/// An invalid option on the command line.
+#[derive(Error)]
+enum OptionsError {
+    #[error("{flag} needs a value")]
+    MissingValue { flag: &'static str },
+    #[error("Invalid value {value} for {flag}")]
+    InvalidValue { flag: &'static str, value: String },
+    #[error("Could not resolve server address {0}")]
+    UnknownServer(String),
+    #[error("{path}: {error}")]
+    Replay { path: String, error: ReplayError },
+}
+
+// `main` shows the `Debug` representation of an error, so show the message instead.
+impl std::fmt::Debug for OptionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self)
+    }
+}
+
+/// The options of the game, read from the command line once at startup.
+struct Options {
+    /// `--headless` runs without window, renderer, user interface and local players.
+    headless: bool,
+    /// `--seed <seed>` plays on a generated map, `--map <name>` on a map file, otherwise the default map is used.
+    map: MapSource,
+    /// `--server [port]` hosts a match, `--connect <address>` joins one, otherwise the game is offline.
+    /// `--latency <ms>`, `--jitter <ms>` and `--loss <percent>` simulate a bad connection.
+    network: NetworkPlugin,
+    /// `--record <file>` records the match into a replay file, `--replay <file>` plays a replay file.
+    /// A replay is played on the map it was recorded on and offline.
+    replay: ReplayMode,
+    /// The number of local players in split-screen, set with `--players <n>`.
+    local_players: usize,
+    /// `--demo` drives the local player tanks with a scripted demo sequence.
+    demo: bool,
+    /// The difficulty of the AI tanks, set with `--difficulty <easy|normal|hard>`.
+    difficulty: Difficulty,
+}
+
+impl Options {
+    /// Read the options from the command line arguments, without the name of the program.
+    fn parse(args: &[String]) -> Result<Self, OptionsError> {
+        let flag = |flag: &str| args.iter().any(|arg| arg == flag);
+        let value = |flag: &'static str| match args.iter().position(|arg| arg == flag) {
+            Some(i) => args.get(i + 1).map(Some).ok_or(OptionsError::MissingValue { flag }),
+            None => Ok(None),
+        };
+
+        let mut map = match (parse("--seed", value("--seed")?)?, value("--map")?) {
+            (Some(seed), _) => MapSource::Generated(MapGeneratorSettings {
+                seed,
+                ..Default::default()
+            }),
+            (None, Some(name)) => MapSource::File(name.clone()),
+            (None, None) => MapSource::File("default".to_string()),
+        };
+
+        let milliseconds = |flag| -> Result<Duration, OptionsError> {
+            Ok(Duration::from_millis(parse(flag, value(flag)?)?.unwrap_or(0)))
+        };
+        let conditions = LinkConditions {
+            latency: milliseconds("--latency")?,
+            jitter: milliseconds("--jitter")?,
+            loss: parse::<f32>("--loss", value("--loss")?)?.unwrap_or(0.0) / 100.0,
+        };
+        let mode = if let Some(address) = value("--connect")? {
+            let address = if address.contains(':') { address.clone() } else { format!("{}:{}", address, DEFAULT_PORT) };
+            let server = address.to_socket_addrs().ok()
+                .and_then(|mut addresses| addresses.next())
+                .ok_or(OptionsError::UnknownServer(address))?;
+            NetworkMode::Client { server }
+        } else if flag("--server") {
+            // The port is optional, so a following flag is not taken for it.
+            let port = args.iter().position(|arg| arg == "--server")
+                .and_then(|i| args.get(i + 1))
+                .filter(|port| !port.starts_with("--"));
+            NetworkMode::Server { port: parse("--server", port)?.unwrap_or(DEFAULT_PORT) }
+        } else {
+            NetworkMode::Offline
+        };
+        let mut network = NetworkPlugin {
+            mode,
+            conditions,
+        };
+
+        let replay = if let Some(path) = value("--replay")? {
+            let replay = Replay::load(path).map_err(|error| OptionsError::Replay {
+                path: path.clone(),
+                error,
+            })?;
+            map = replay.map.clone();
+            network = NetworkPlugin::default();
+            ReplayMode::Play(replay)
+        } else if let Some(path) = value("--record")? {
+            ReplayMode::Record {
+                path: path.into(),
+                map: map.clone(),
+            }
+        } else {
+            ReplayMode::Off
+        };
+
+        let difficulty = match value("--difficulty")? {
+            Some(name) => Difficulty::from_name(name).ok_or_else(|| OptionsError::InvalidValue {
+                flag: "--difficulty",
+                value: name.clone(),
+            })?,
+            None => Difficulty::default(),
+        };
+
+        Ok(Options {
+            headless: flag("--headless"),
+            map,
+            network,
+            replay,
+            local_players: parse("--players", value("--players")?)?.unwrap_or(1).max(1),
+            demo: flag("--demo"),
+            difficulty,
+        })
+    }
+}
+
+/// Parse the value of `flag`, if it was given.
+fn parse<T: FromStr>(flag: &'static str, value: Option<&String>) -> Result<Option<T>, OptionsError> {
+    value.map(|value| value.parse().map_err(|_| OptionsError::InvalidValue {
+        flag,
+        value: value.clone(),
+    })).transpose()
+}
+
+/// Run the game with `--headless` without window, renderer, user interface and local players,
+/// as dedicated server or for automated tests. The app is updated at the tick rate of the physics.
+fn run_headless(options: Options) {
+    let physics = PhysicsPlugin::default();
+    let tick = Duration::from_secs_f64(1.0 / physics.tick_rate);
+    App::new()
+        .add_plugins((
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(tick)),
+            LogPlugin::default(),
+            TransformPlugin,
+            HierarchyPlugin,
+            InputPlugin,
+            AssetPlugin::default(),
+            ImagePlugin::default(),
+            AssetLoaderPlugin {
+                render_assets: false,
+            },
+            InputMapPlugin,
+            MapPlugin {
+                source: options.map,
+            },
+            SchedulePlugin,
+        ))
+        .add_plugins((
+            physics,
+            CollisionPlugin,
+            TankPlugin,
+            ControlPlugin {
+                local_players: 0,
+                demo: false,
+            },
+            TurretPlugin,
+            ProjectilePlugin,
+            DamagePlugin,
+            AiPlugin {
+                difficulty: options.difficulty,
+            },
+            PropPlugin,
+            options.network,
+            ReplayPlugin {
+                mode: options.replay,
+            },
+        ))
+        // The map, tanks and projectiles still create meshes, materials and scenes, they are just never drawn.
+        .init_asset::<Mesh>()
+        .init_asset::<StandardMaterial>()
+        .init_asset::<Scene>()
+        .run();
+}
+
+fn main() -> Result<(), OptionsError> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let options = Options::parse(&args)?;
+    if options.headless {
+        run_headless(options);
+        return Ok(());
+    }
     App::new()
         .add_plugins((DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
@@ -38,14 +272,77 @@ fn main() {
             }),
             bevy_framepace::FramepacePlugin,
             bevy_egui::EguiPlugin,
-            AssetLoaderPlugin,
-            MapPlugin,
+            AssetLoaderPlugin::default(),
+            InputMapPlugin,
+            MapPlugin {
+                source: options.map,
+            },
             CameraPlugin,
-            TankPlugin,
             UIPlugin,
-            PhysicsPlugin,
             MenuPlugin,
             SchedulePlugin,
         ))
+        .add_plugins((
+            PhysicsPlugin::default(),
+            CollisionPlugin,
+            TankPlugin,
+            ControlPlugin {
+                local_players: options.local_players,
+                demo: options.demo,
+            },
+            TurretPlugin,
+            ProjectilePlugin,
+            DamagePlugin,
+            AiPlugin {
+                difficulty: options.difficulty,
+            },
+            PropPlugin,
+            options.network,
+            ReplayPlugin {
+                mode: options.replay,
+            },
+        ))
         .run();
-}
\ No newline at end of file
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &str) -> Result<Options, OptionsError> {
+        let args: Vec<String> = args.split_whitespace().map(String::from).collect();
+        Options::parse(&args)
+    }
+
+    #[test]
+    fn defaults_without_arguments() {
+        let options = parse_args("").unwrap();
+        assert!(!options.headless);
+        assert!(matches!(options.map, MapSource::File(name) if name == "default"));
+        assert!(matches!(options.network.mode, NetworkMode::Offline));
+        assert!(matches!(options.replay, ReplayMode::Off));
+        assert_eq!(options.local_players, 1);
+        assert_eq!(options.difficulty, Difficulty::Normal);
+    }
+
+    #[test]
+    fn server_port_is_optional() {
+        let options = parse_args("--headless --server").unwrap();
+        assert!(matches!(options.network.mode, NetworkMode::Server { port: DEFAULT_PORT }));
+        let options = parse_args("--server --latency 40").unwrap();
+        assert!(matches!(options.network.mode, NetworkMode::Server { port: DEFAULT_PORT }));
+        assert_eq!(options.network.conditions.latency, Duration::from_millis(40));
+        let options = parse_args("--server 4000 --seed 7").unwrap();
+        assert!(matches!(options.network.mode, NetworkMode::Server { port: 4000 }));
+        assert!(matches!(options.map, MapSource::Generated(settings) if settings.seed == 7));
+    }
+
+    #[test]
+    fn invalid_options_are_errors() {
+        assert!(matches!(parse_args("--players two"), Err(OptionsError::InvalidValue { flag: "--players", .. })));
+        assert!(matches!(parse_args("--difficulty impossible"), Err(OptionsError::InvalidValue { flag: "--difficulty", .. })));
+        assert!(matches!(parse_args("--map"), Err(OptionsError::MissingValue { flag: "--map" })));
+        assert!(matches!(parse_args("--replay missing.replay"), Err(OptionsError::Replay { .. })));
+    }
+}
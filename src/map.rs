@@ -1,36 +1,402 @@
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::asset_loader::SceneAssets;
+use crate::collision::{resolve_collisions, Collider};
+use crate::damage::{DestroyedEvent, Health, Wreck};
+use crate::map_definition::{BoundsMode, MapDefinition, TerrainSource};
+use crate::network::is_authority;
+use crate::physics::{Grounded, Position, Rotation, Velocity};
+use crate::prop::{Prop, PropId};
+use crate::schedule::ScheduleSet;
+use crate::tank::{Tank, Team};
+use crate::terrain::Terrain;
 
-pub struct MapPlugin;
+pub mod generator;
+use generator::{generate_map, MapGeneratorSettings};
+
+pub mod navigation;
+use navigation::{build_nav_grid, update_nav_grid};
+
+/// Where the map is taken from.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MapSource {
+    /// The map `assets/maps/<name>.map.ron`. The map is respawned when the file changes.
+    File(String),
+    /// A map created by the procedural map generator.
+    Generated(MapGeneratorSettings),
+}
+
+/// Plugin for the map. Loads or generates the map and spawns it once it is ready.
+pub struct MapPlugin {
+    pub source: MapSource,
+}
+
+impl Default for MapPlugin {
+    fn default() -> Self {
+        MapPlugin {
+            source: MapSource::File("default".to_string()),
+        }
+    }
+}
+
+/// Resource to store the source and handle of the loaded map.
+#[derive(Resource, Debug)]
+pub struct CurrentMap {
+    pub source: MapSource,
+    pub handle: Handle<MapDefinition>,
+}
+
+/// Marker component for entities spawned from the map definition.
+/// They are despawned when the map is reloaded.
+#[derive(Component)]
+pub struct MapEntity;
+
+/// A point where a tank is spawned, placed on the terrain.
+#[derive(Debug, Clone, Copy)]
+pub struct SpawnPoint {
+    pub team: Team,
+    pub position: Vec3,
+    pub rotation: Quat,
+}
+
+/// Resource to store the spawn points of the loaded map.
+/// Inserted when the map is spawned for the first time, so tanks can be spawned once it is added.
+#[derive(Resource, Debug, Clone, Default)]
+pub struct SpawnPoints(pub Vec<SpawnPoint>);
+
+impl SpawnPoints {
+    /// The spawn points of a team.
+    pub fn for_team(&self, team: Team) -> impl Iterator<Item = &SpawnPoint> {
+        self.0.iter().filter(move |spawn_point| spawn_point.team == team)
+    }
+}
+
+/// Resource to store the bounds of the loaded map.
+/// `half_extent` is the distance from the center to the edges of the playable area,
+/// `outer_half_extent` the distance to the edges of the map, which are always solid walls.
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct MapBounds {
+    pub half_extent: f32,
+    pub outer_half_extent: f32,
+    pub mode: BoundsMode,
+}
+
+impl MapBounds {
+    /// Whether a position is inside the playable area.
+    pub fn contains(&self, position: Vec3) -> bool {
+        position.x.abs() <= self.half_extent && position.z.abs() <= self.half_extent
+    }
+}
+
+/// Component for tanks outside of the playable area of a map with a countdown.
+/// The tank is destroyed when `remaining` reaches zero.
+#[derive(Component, Debug)]
+pub struct OutOfBounds {
+    pub remaining: f32,
+}
 
 impl Plugin for MapPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(ClearColor(Color::rgb(0.8, 0.8, 1.0)))
-            .insert_resource(AmbientLight {
-                color: Color::rgb(0.8, 0.8, 0.8),
-                brightness: 0.75,
+        app.insert_resource(CurrentMap {
+                source: self.source.clone(),
+                handle: Handle::default(),
             })
-            .add_systems(Startup, (
-                spawn_floor,
-            ));
+            .add_systems(PreStartup, (
+                load_map,
+            ))
+            .add_systems(Update, (
+                spawn_map.before(ScheduleSet::CheckMenu),
+            ))
+            .add_systems(Update, (
+                draw_bounds.run_if(resource_exists::<GizmoConfig>()),
+                (build_nav_grid, update_nav_grid).chain(),
+            ).in_set(ScheduleSet::UpdateWorld))
+            .add_systems(FixedUpdate, (
+                enforce_bounds,
+                count_down_out_of_bounds.run_if(is_authority),
+            ).chain().after(resolve_collisions).in_set(ScheduleSet::Physics));
     }
 }
 
-/// System to spawn the floor. The floor is a 40x40 grid of tiles centered at the origin.
-fn spawn_floor (
+/// System to start loading the map definition of the current map, or to generate it.
+fn load_map(
+    mut current_map: ResMut<CurrentMap>,
+    asset_server: Res<AssetServer>,
+    mut definitions: ResMut<Assets<MapDefinition>>,
+) {
+    current_map.handle = match current_map.source {
+        MapSource::File(ref name) => asset_server.load(format!("maps/{}.map.ron", name)),
+        MapSource::Generated(ref settings) => definitions.add(generate_map(settings)),
+    };
+}
+
+/// System to spawn the map when its definition is loaded or modified, or when a generated map is added.
+/// Builds the terrain, places the props, sets the lighting and sky and stores the spawn points.
+#[allow(clippy::too_many_arguments)]
+fn spawn_map(
     mut commands: Commands,
-    assets: Res<SceneAssets>,
+    mut events: EventReader<AssetEvent<MapDefinition>>,
+    current_map: Res<CurrentMap>,
+    asset_server: Res<AssetServer>,
+    definitions: Res<Assets<MapDefinition>>,
+    images: Res<Assets<Image>>,
+    map_entities: Query<Entity, With<MapEntity>>,
+    spawn_points: Option<ResMut<SpawnPoints>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-    for i in -20..=20 {
-        for j in -20..=20 {
+    let changed = events.read().any(|event| match event {
+        AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => *id == current_map.handle.id(),
+        AssetEvent::Added { id } => *id == current_map.handle.id() && asset_server.get_path(*id).is_none(),
+        _ => false,
+    });
+    if !changed {
+        return;
+    }
+    let Some(definition) = definitions.get(&current_map.handle) else {
+        return;
+    };
+    for entity in map_entities.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+
+    let resolution = definition.terrain.resolution.max(2);
+    let spacing = definition.size / (resolution - 1) as f32;
+    let terrain = match definition.terrain.source {
+        TerrainSource::Flat => Terrain::flat(resolution, spacing),
+        TerrainSource::Noise { seed, amplitude } => Terrain::from_noise(resolution, spacing, amplitude, seed),
+        TerrainSource::Image { ref path, amplitude } => {
+            match definition.heightmap.as_ref().and_then(|heightmap| images.get(heightmap)) {
+                Some(image) => Terrain::from_image(image, definition.size, amplitude),
+                None => {
+                    error!("Heightmap {} of map {} is not loaded, using flat terrain", path, definition.name);
+                    Terrain::flat(resolution, spacing)
+                },
+            }
+        },
+        TerrainSource::Heights { ref heights } => Terrain {
+            size: resolution,
+            spacing,
+            heights: heights.clone(),
+        },
+    };
+
+    commands.spawn((
+        MapEntity,
+        PbrBundle {
+            mesh: meshes.add(terrain.mesh()),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgb(0.35, 0.5, 0.25),
+                perceptual_roughness: 0.9,
+                ..default()
+            }),
+            ..default()
+        },
+    ));
+
+    let mut prop_scenes = definition.prop_scenes.iter();
+    for (index, prop) in definition.props.iter().enumerate() {
+        let [x, y, z] = prop.position;
+        let position = Vec3::new(x, terrain.height_at(x, z) + y, z);
+        let [yaw, pitch, roll] = prop.rotation;
+        let rotation = Quat::from_euler(EulerRot::YXZ, yaw, pitch, roll);
+        let transform = Transform::from_translation(position)
+            .with_rotation(rotation)
+            .with_scale(Vec3::splat(prop.scale));
+        let mut entity = if prop.model.is_empty() {
+            let mesh = match (prop.half_extents, prop.capsule) {
+                (None, Some([half_height, radius])) => Mesh::from(shape::Capsule {
+                    radius,
+                    depth: half_height * 2.0,
+                    ..default()
+                }),
+                (half_extents, _) => {
+                    let [hx, hy, hz] = half_extents.unwrap_or([1.0, 1.0, 1.0]);
+                    Mesh::from(shape::Box::new(hx * 2.0, hy * 2.0, hz * 2.0))
+                },
+            };
             commands.spawn((
+                MapEntity,
+                Prop,
+                PropId(index as u32),
+                PbrBundle {
+                    mesh: meshes.add(mesh),
+                    material: materials.add(StandardMaterial {
+                        base_color: Color::from(prop.color),
+                        perceptual_roughness: 0.8,
+                        ..default()
+                    }),
+                    transform,
+                    ..default()
+                },
+            ))
+        } else {
+            commands.spawn((
+                MapEntity,
+                Prop,
+                PropId(index as u32),
                 SceneBundle {
-                    scene: assets.floor.clone(),
-                    transform: Transform::from_xyz(40.0 * i as f32, 0.0, 40.0 * j as f32),
-                    ..Default::default()
+                    scene: prop_scenes.next().cloned().unwrap_or_default(),
+                    transform,
+                    ..default()
                 },
+            ))
+        };
+        let collider = match (prop.half_extents, prop.capsule) {
+            (Some(half_extents), _) => Some(Collider::cuboid(Vec3::from(half_extents) * prop.scale)),
+            (None, Some([half_height, radius])) => Some(Collider::capsule(half_height * prop.scale, radius * prop.scale)),
+            (None, None) => None,
+        };
+        if let Some(collider) = collider {
+            entity.insert((
+                collider,
+                Position(position),
+                Rotation(rotation),
             ));
         }
+        if let Some(health) = prop.health {
+            entity.insert(Health::new(health));
+        }
     }
-}
\ No newline at end of file
+
+    let lighting = definition.lighting;
+    let sun_direction = Vec3::from(lighting.sun_direction).normalize();
+    let up = if sun_direction.cross(Vec3::Y).length_squared() < 1e-6 { Vec3::Z } else { Vec3::Y };
+    commands.spawn((
+        MapEntity,
+        DirectionalLightBundle {
+            directional_light: DirectionalLight {
+                color: Color::from(lighting.sun_color),
+                illuminance: lighting.sun_illuminance,
+                shadows_enabled: true,
+                ..default()
+            },
+            transform: Transform::IDENTITY.looking_to(sun_direction, up),
+            ..default()
+        },
+    ));
+    commands.insert_resource(AmbientLight {
+        color: Color::from(lighting.ambient_color),
+        brightness: lighting.ambient_brightness,
+    });
+    commands.insert_resource(ClearColor(Color::from(definition.sky_color)));
+
+    let new_spawn_points: Vec<SpawnPoint> = definition.spawn_points.iter()
+        .map(|spawn_point| {
+            let [x, z] = spawn_point.position;
+            SpawnPoint {
+                team: Team(spawn_point.team),
+                position: Vec3::new(x, terrain.height_at(x, z), z),
+                rotation: Quat::from_rotation_y(spawn_point.heading),
+            }
+        })
+        .collect();
+    match spawn_points {
+        Some(mut spawn_points) => spawn_points.0 = new_spawn_points,
+        None => commands.insert_resource(SpawnPoints(new_spawn_points)),
+    }
+    commands.insert_resource(match definition.bounds {
+        Some(bounds) => MapBounds {
+            half_extent: bounds.size / 2.0,
+            outer_half_extent: definition.size / 2.0,
+            mode: bounds.mode,
+        },
+        None => MapBounds {
+            half_extent: definition.size / 2.0,
+            outer_half_extent: definition.size / 2.0,
+            mode: BoundsMode::Wall,
+        },
+    });
+    commands.insert_resource(terrain);
+}
+
+/// System to keep grounded entities inside the walls of the map.
+/// The walls are at the edges of the playable area, or at the edges of the map if the bounds have a countdown.
+/// Entities are pushed back inside and lose the part of their velocity going through the wall.
+pub fn enforce_bounds(
+    mut query: Query<(&mut Position, &mut Velocity), With<Grounded>>,
+    bounds: Option<Res<MapBounds>>,
+) {
+    let Some(bounds) = bounds else {
+        return;
+    };
+    let limit = match bounds.mode {
+        BoundsMode::Wall => bounds.half_extent,
+        BoundsMode::Countdown { .. } => bounds.outer_half_extent,
+    };
+    for (mut position, mut velocity) in query.iter_mut() {
+        if position.0.x.abs() > limit {
+            position.0.x = position.0.x.clamp(-limit, limit);
+            if velocity.0.x * position.0.x > 0.0 {
+                velocity.0.x = 0.0;
+            }
+        }
+        if position.0.z.abs() > limit {
+            position.0.z = position.0.z.clamp(-limit, limit);
+            if velocity.0.z * position.0.z > 0.0 {
+                velocity.0.z = 0.0;
+            }
+        }
+    }
+}
+
+/// System to count down the time tanks spend outside of the playable area.
+/// Tanks returning in time are safe again, the others are destroyed.
+#[allow(clippy::type_complexity)]
+fn count_down_out_of_bounds(
+    mut commands: Commands,
+    mut query: Query<(Entity, &Position, Option<&mut OutOfBounds>, Option<&mut Health>), (With<Tank>, Without<Wreck>)>,
+    bounds: Option<Res<MapBounds>>,
+    mut destroyed_events: EventWriter<DestroyedEvent>,
+    time: Res<Time>,
+) {
+    let Some(bounds) = bounds else {
+        return;
+    };
+    let BoundsMode::Countdown { seconds } = bounds.mode else {
+        return;
+    };
+    for (entity, position, out_of_bounds, health) in query.iter_mut() {
+        match (bounds.contains(position.0), out_of_bounds) {
+            (true, Some(_)) => {
+                commands.entity(entity).remove::<OutOfBounds>();
+            },
+            (false, None) => {
+                commands.entity(entity).insert(OutOfBounds { remaining: seconds });
+            },
+            (false, Some(mut out_of_bounds)) => {
+                out_of_bounds.remaining -= time.delta_seconds();
+                if out_of_bounds.remaining <= 0.0 {
+                    if let Some(mut health) = health {
+                        health.current = 0.0;
+                    }
+                    commands.entity(entity).remove::<OutOfBounds>().insert(Wreck);
+                    destroyed_events.send(DestroyedEvent {
+                        entity,
+                        destroyed_by: entity,
+                    });
+                }
+            },
+            (true, None) => {},
+        }
+    }
+}
+
+/// System to draw the edges of the playable area on the terrain.
+fn draw_bounds(
+    mut gizmos: Gizmos,
+    bounds: Option<Res<MapBounds>>,
+    terrain: Option<Res<Terrain>>,
+) {
+    let (Some(bounds), Some(terrain)) = (bounds, terrain) else {
+        return;
+    };
+    let h = bounds.half_extent;
+    let corners = [Vec2::new(-h, -h), Vec2::new(h, -h), Vec2::new(h, h), Vec2::new(-h, h), Vec2::new(-h, -h)];
+    let points = corners.windows(2).flat_map(|edge| {
+        (0..64).map(move |i| edge[0].lerp(edge[1], i as f32 / 64.0))
+    })
+    .chain(std::iter::once(corners[0]))
+    .map(|point| Vec3::new(point.x, terrain.height_at(point.x, point.y) + 1.0, point.y));
+    gizmos.linestrip(points, Color::RED);
+}
@@ -0,0 +1,451 @@
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::map_definition::{
+    BoundsDefinition, BoundsMode, LightingDefinition, MapDefinition, PropDefinition, SpawnPointDefinition,
+    TerrainDefinition, TerrainSource,
+};
+use crate::terrain::{Terrain, MAX_DRIVABLE_SLOPE};
+
+/// Distance between two grid points of generated terrains.
+const GRID_SPACING: f32 = 12.8;
+
+/// Height of the terrain at the highest hilliness.
+const MAX_AMPLITUDE: f32 = 60.0;
+
+/// Number of spawn points in every spawn zone.
+const SPAWNS_PER_ZONE: usize = 3;
+
+/// Distance between the spawn points of a zone.
+const SPAWN_SPACING: f32 = 25.0;
+
+/// Radius around the center of a spawn zone that is flat and free of obstacles.
+const ZONE_RADIUS: f32 = 50.0;
+
+/// Distance a tank needs to keep from obstacles to drive past them.
+const TANK_CLEARANCE: f32 = 5.0;
+
+/// Health of generated buildings. Rocks can not be destroyed.
+const BUILDING_HEALTH: f32 = 1500.0;
+
+/// Time in seconds a tank can stay outside of the playable area of a generated map.
+const OUT_OF_BOUNDS_TIME: f32 = 10.0;
+
+/// Radius of the corridors carved between spawn zones that can not reach each other.
+const CORRIDOR_RADIUS: f32 = 2.0 * GRID_SPACING;
+
+/// Number of corridors carved per spawn zone before the spawn zones are leveled to connect them.
+const MAX_CORRIDORS_PER_ZONE: usize = 4;
+
+/// Parameters of the procedural map generator.
+/// `obstacle_density` is the number of obstacles per 100 x 100 area, `hilliness` is in the range `0.0..=1.0`.
+/// The number of `spawn_zones` is rounded up to an even number, the zones alternate between team 0 and 1.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct MapGeneratorSettings {
+    pub seed: u64,
+    pub size: f32,
+    pub obstacle_density: f32,
+    pub hilliness: f32,
+    pub spawn_zones: usize,
+}
+
+impl Default for MapGeneratorSettings {
+    fn default() -> Self {
+        MapGeneratorSettings {
+            seed: 0,
+            size: 1638.4,
+            obstacle_density: 0.5,
+            hilliness: 0.5,
+            spawn_zones: 2,
+        }
+    }
+}
+
+/// Random number generator of the map generator.
+/// Uses splitmix64 so the same seed always produces the same map on every platform.
+struct MapRng(u64);
+
+impl MapRng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// Random number in the range `0.0..1.0`.
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Random number in the range `min..max`.
+    fn range(&mut self, min: f32, max: f32) -> f32 {
+        min + (max - min) * self.next_f32()
+    }
+}
+
+/// A spawn zone of the generated map.
+struct SpawnZone {
+    team: u8,
+    center: Vec2,
+}
+
+/// An obstacle of the generated map with its yaw and half extents.
+/// Obstacles with `health` can be destroyed.
+struct Obstacle {
+    position: Vec2,
+    yaw: f32,
+    half_extents: Vec3,
+    color: [f32; 3],
+    health: Option<f32>,
+}
+
+impl Obstacle {
+    /// Half size of the area around the obstacle a tank can not drive through, aligned to the world axes.
+    fn blocked_extents(&self) -> Vec2 {
+        let (sin, cos) = self.yaw.sin_cos();
+        Vec2::new(
+            cos.abs() * self.half_extents.x + sin.abs() * self.half_extents.z,
+            sin.abs() * self.half_extents.x + cos.abs() * self.half_extents.z,
+        ) + Vec2::splat(TANK_CLEARANCE)
+    }
+
+    fn blocks(&self, point: Vec2) -> bool {
+        let extents = self.blocked_extents();
+        let offset = (point - self.position).abs();
+        offset.x <= extents.x && offset.y <= extents.y
+    }
+}
+
+/// Generate a map from the settings.
+/// The terrain is generated from noise and flattened around the spawn zones, then rocks and buildings are placed.
+/// Corridors are carved until every spawn point can be reached from the first one, so all spawns are mutually reachable.
+pub fn generate_map(settings: &MapGeneratorSettings) -> MapDefinition {
+    let mut rng = MapRng(settings.seed);
+    let size = settings.size.max(GRID_SPACING * 2.0);
+    let resolution = (size / GRID_SPACING).round() as usize + 1;
+    let spacing = size / (resolution - 1) as f32;
+    let amplitude = settings.hilliness.clamp(0.0, 1.0) * MAX_AMPLITUDE;
+    let mut terrain = Terrain::from_noise(resolution, spacing, amplitude, rng.next_u64() as u32);
+
+    let zone_count = settings.spawn_zones.max(2).div_ceil(2) * 2;
+    let start_angle = rng.range(0.0, TAU);
+    let zones: Vec<SpawnZone> = (0..zone_count)
+        .map(|i| {
+            let angle = start_angle + i as f32 * TAU / zone_count as f32;
+            SpawnZone {
+                team: (i % 2) as u8,
+                center: Vec2::new(angle.sin(), angle.cos()) * size * 0.35,
+            }
+        })
+        .collect();
+    for zone in zones.iter() {
+        let height = terrain.height_at(zone.center.x, zone.center.y);
+        flatten(&mut terrain, zone.center, height);
+    }
+
+    let spawn_points: Vec<SpawnPointDefinition> = zones.iter()
+        .flat_map(|zone| {
+            let heading = (-zone.center.x).atan2(-zone.center.y);
+            let side = Vec2::new(zone.center.y, -zone.center.x).normalize_or_zero();
+            (0..SPAWNS_PER_ZONE).map(move |i| {
+                let offset = (i as f32 - (SPAWNS_PER_ZONE - 1) as f32 / 2.0) * SPAWN_SPACING;
+                let position = zone.center + side * offset;
+                SpawnPointDefinition {
+                    team: zone.team,
+                    position: position.to_array(),
+                    heading,
+                }
+            })
+        })
+        .collect();
+
+    let obstacle_count = (settings.obstacle_density.max(0.0) * (size / 100.0).powi(2)).round() as usize;
+    let mut obstacles = Vec::with_capacity(obstacle_count);
+    for _ in 0..obstacle_count {
+        let half_size = size / 2.0 - 20.0;
+        let position = Vec2::new(rng.range(-half_size, half_size), rng.range(-half_size, half_size));
+        let is_building = rng.next_f32() < 0.3;
+        let obstacle = if is_building {
+            Obstacle {
+                position,
+                yaw: rng.range(0.0, TAU),
+                half_extents: Vec3::new(rng.range(8.0, 15.0), rng.range(6.0, 12.0), rng.range(8.0, 15.0)),
+                color: [0.7, 0.62, 0.5],
+                health: Some(BUILDING_HEALTH),
+            }
+        } else {
+            Obstacle {
+                position,
+                yaw: rng.range(0.0, TAU),
+                half_extents: Vec3::new(rng.range(3.0, 8.0), rng.range(2.0, 6.0), rng.range(3.0, 8.0)),
+                color: [0.45, 0.45, 0.42],
+                health: None,
+            }
+        };
+        let blocked = obstacle.blocked_extents().length();
+        if zones.iter().all(|zone| zone.center.distance(position) > ZONE_RADIUS + blocked) {
+            obstacles.push(obstacle);
+        }
+    }
+
+    let spawn_positions: Vec<Vec2> = spawn_points.iter().map(|spawn_point| Vec2::from(spawn_point.position)).collect();
+    connect_spawn_points(&mut terrain, &mut obstacles, &zones, &spawn_positions, zone_count * MAX_CORRIDORS_PER_ZONE);
+
+    let props = obstacles.iter()
+        .map(|obstacle| PropDefinition {
+            model: String::new(),
+            position: [obstacle.position.x, obstacle.half_extents.y, obstacle.position.y],
+            rotation: [obstacle.yaw, 0.0, 0.0],
+            scale: 1.0,
+            half_extents: Some(obstacle.half_extents.to_array()),
+            capsule: None,
+            color: obstacle.color,
+            health: obstacle.health,
+        })
+        .collect();
+
+    MapDefinition {
+        name: format!("Generiert {}", settings.seed),
+        size,
+        terrain: TerrainDefinition {
+            resolution,
+            source: TerrainSource::Heights { heights: terrain.heights },
+        },
+        props,
+        spawn_points,
+        lighting: LightingDefinition {
+            ambient_color: [0.8, 0.8, 0.8],
+            ambient_brightness: 0.75,
+            sun_color: [1.0, 0.95, 0.85],
+            sun_illuminance: 8000.0,
+            sun_direction: [-0.4, -1.0, 0.3],
+        },
+        sky_color: [0.8, 0.8, 1.0],
+        bounds: Some(BoundsDefinition {
+            size: size * 0.9,
+            mode: BoundsMode::Countdown { seconds: OUT_OF_BOUNDS_TIME },
+        }),
+        prop_scenes: Vec::new(),
+        heightmap: None,
+    }
+}
+
+/// World position of a grid point of the terrain.
+fn cell_position(terrain: &Terrain, i: usize, j: usize) -> Vec2 {
+    Vec2::new(i as f32, j as f32) * terrain.spacing - Vec2::splat(terrain.half_extent())
+}
+
+/// Index of the grid point closest to a world position.
+fn cell_index(terrain: &Terrain, position: Vec2) -> usize {
+    let max = terrain.size as f32 - 1.0;
+    let i = ((position.x + terrain.half_extent()) / terrain.spacing).round().clamp(0.0, max) as usize;
+    let j = ((position.y + terrain.half_extent()) / terrain.spacing).round().clamp(0.0, max) as usize;
+    j * terrain.size + i
+}
+
+/// Flatten the terrain around a spawn zone to `height`, blending into the surrounding terrain.
+fn flatten(terrain: &mut Terrain, center: Vec2, height: f32) {
+    for j in 0..terrain.size {
+        for i in 0..terrain.size {
+            let distance = cell_position(terrain, i, j).distance(center);
+            let blend = ((distance - ZONE_RADIUS) / ZONE_RADIUS).clamp(0.0, 1.0);
+            let index = j * terrain.size + i;
+            terrain.heights[index] = height + (terrain.heights[index] - height) * blend;
+        }
+    }
+}
+
+/// The grid points a tank can reach from `start` without driving up too steep slopes or through obstacles.
+fn reachable_cells(terrain: &Terrain, obstacles: &[Obstacle], start: Vec2) -> Vec<bool> {
+    let size = terrain.size;
+    let blocked: Vec<bool> = (0..size * size)
+        .map(|index| {
+            let position = cell_position(terrain, index % size, index / size);
+            obstacles.iter().any(|obstacle| obstacle.blocks(position))
+        })
+        .collect();
+
+    let mut reachable = vec![false; size * size];
+    let start = cell_index(terrain, start);
+    let mut queue = VecDeque::from([start]);
+    reachable[start] = true;
+    while let Some(index) = queue.pop_front() {
+        let (i, j) = ((index % size) as isize, (index / size) as isize);
+        for (di, dj) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+            let (ni, nj) = (i + di, j + dj);
+            if ni < 0 || nj < 0 || ni >= size as isize || nj >= size as isize {
+                continue;
+            }
+            let neighbor = nj as usize * size + ni as usize;
+            if reachable[neighbor] || blocked[neighbor] {
+                continue;
+            }
+            let distance = ((di * di + dj * dj) as f32).sqrt() * terrain.spacing;
+            let climb = (terrain.heights[neighbor] - terrain.heights[index]).abs();
+            if climb / distance > MAX_DRIVABLE_SLOPE {
+                continue;
+            }
+            reachable[neighbor] = true;
+            queue.push_back(neighbor);
+        }
+    }
+    reachable
+}
+
+/// Carve corridors from the first spawn point to the spawn points that can not be reached from it
+/// and remove the obstacles in them, until a pass finds every spawn point reachable.
+/// A corridor can cut off a spawn point that was connected before. If that still happens after `max_corridors`
+/// corridors, the spawn zones are leveled instead.
+fn connect_spawn_points(terrain: &mut Terrain, obstacles: &mut Vec<Obstacle>, zones: &[SpawnZone], spawn_points: &[Vec2], max_corridors: usize) {
+    let start = spawn_points[0];
+    for corridors in 0.. {
+        let reachable = reachable_cells(terrain, obstacles, start);
+        let Some(&target) = spawn_points.iter().find(|position| !reachable[cell_index(terrain, **position)]) else {
+            return;
+        };
+        if corridors == max_corridors {
+            debug!("Leveling the spawn zones to connect the spawn point at {}", target);
+            level_spawn_zones(terrain, obstacles, zones);
+            return;
+        }
+        let corridor = carve_corridor(terrain, start, target);
+        obstacles.retain(|obstacle| !corridor.iter().any(|point| obstacle.blocks(*point)));
+    }
+}
+
+/// Flatten all spawn zones to the height of the first one and connect them with corridors free of obstacles.
+/// The corridors are flat as well, so they can be driven through where they cross each other.
+fn level_spawn_zones(terrain: &mut Terrain, obstacles: &mut Vec<Obstacle>, zones: &[SpawnZone]) {
+    let height = terrain.height_at(zones[0].center.x, zones[0].center.y);
+    for zone in zones.iter() {
+        flatten(terrain, zone.center, height);
+    }
+    for zone in zones.iter().skip(1) {
+        let corridor = carve_corridor(terrain, zones[0].center, zone.center);
+        obstacles.retain(|obstacle| !corridor.iter().any(|point| obstacle.blocks(*point)));
+    }
+}
+
+/// Carve a straight corridor with an even slope from `from` to `to` into the terrain.
+/// Returns the positions of the grid points in the corridor.
+fn carve_corridor(terrain: &mut Terrain, from: Vec2, to: Vec2) -> Vec<Vec2> {
+    let from_height = terrain.height_at(from.x, from.y);
+    let to_height = terrain.height_at(to.x, to.y);
+    let direction = to - from;
+    let length_squared = direction.length_squared().max(f32::EPSILON);
+    let mut corridor = Vec::new();
+    for j in 0..terrain.size {
+        for i in 0..terrain.size {
+            let position = cell_position(terrain, i, j);
+            let t = ((position - from).dot(direction) / length_squared).clamp(0.0, 1.0);
+            if position.distance(from + direction * t) > CORRIDOR_RADIUS {
+                continue;
+            }
+            terrain.heights[j * terrain.size + i] = from_height + (to_height - from_height) * t;
+            corridor.push(position);
+        }
+    }
+    corridor
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Settings that make it hard to reach the other zones: steep hills and so many obstacles
+    /// that most seeds need corridors, some of them several passes.
+    fn rough_settings(seed: u64) -> MapGeneratorSettings {
+        MapGeneratorSettings {
+            seed,
+            size: 800.0,
+            obstacle_density: 15.0,
+            hilliness: 1.0,
+            spawn_zones: 4,
+        }
+    }
+
+    /// Every spawn point can be reached from the first one on the terrain and past the props of the map.
+    fn assert_spawns_reachable(definition: &MapDefinition) {
+        let TerrainSource::Heights { ref heights } = definition.terrain.source else {
+            panic!("Generated terrain has no heights");
+        };
+        let resolution = definition.terrain.resolution;
+        let terrain = Terrain {
+            size: resolution,
+            spacing: definition.size / (resolution - 1) as f32,
+            heights: heights.clone(),
+        };
+        let obstacles: Vec<Obstacle> = definition.props.iter()
+            .map(|prop| Obstacle {
+                position: Vec2::new(prop.position[0], prop.position[2]),
+                yaw: prop.rotation[0],
+                half_extents: Vec3::from(prop.half_extents.unwrap()),
+                color: prop.color,
+                health: prop.health,
+            })
+            .collect();
+        let spawn_points: Vec<Vec2> = definition.spawn_points.iter().map(|spawn_point| Vec2::from(spawn_point.position)).collect();
+        assert_reachable(&terrain, &obstacles, &spawn_points);
+    }
+
+    fn assert_reachable(terrain: &Terrain, obstacles: &[Obstacle], spawn_points: &[Vec2]) {
+        let reachable = reachable_cells(terrain, obstacles, spawn_points[0]);
+        for position in spawn_points.iter() {
+            assert!(reachable[cell_index(terrain, *position)], "spawn point at {} is not reachable", position);
+        }
+    }
+
+    #[test]
+    fn all_spawn_points_are_reachable() {
+        for seed in 0..16 {
+            assert_spawns_reachable(&generate_map(&rough_settings(seed)));
+        }
+        assert_spawns_reachable(&generate_map(&MapGeneratorSettings::default()));
+    }
+
+    #[test]
+    fn leveling_connects_spawn_zones_behind_a_wall() {
+        let mut terrain = Terrain::from_noise(65, GRID_SPACING, MAX_AMPLITUDE, 3);
+        let zones = [
+            SpawnZone { team: 0, center: Vec2::new(-250.0, 0.0) },
+            SpawnZone { team: 1, center: Vec2::new(250.0, 0.0) },
+        ];
+        for zone in zones.iter() {
+            let height = terrain.height_at(zone.center.x, zone.center.y);
+            flatten(&mut terrain, zone.center, height);
+        }
+        let spawn_points: Vec<Vec2> = zones.iter()
+            .flat_map(|zone| [-SPAWN_SPACING, 0.0, SPAWN_SPACING].map(|offset| zone.center + Vec2::new(0.0, offset)))
+            .collect();
+        // Rocks across the whole map between the zones.
+        let mut obstacles: Vec<Obstacle> = (-20..=20)
+            .map(|i| Obstacle {
+                position: Vec2::new(0.0, i as f32 * 20.0),
+                yaw: 0.0,
+                half_extents: Vec3::new(5.0, 5.0, 10.0),
+                color: [0.45, 0.45, 0.42],
+                health: None,
+            })
+            .collect();
+        let reachable = reachable_cells(&terrain, &obstacles, spawn_points[0]);
+        assert!(!reachable[cell_index(&terrain, spawn_points[3])]);
+
+        connect_spawn_points(&mut terrain, &mut obstacles, &zones, &spawn_points, 0);
+        assert_reachable(&terrain, &obstacles, &spawn_points);
+    }
+
+    #[test]
+    fn same_seed_generates_the_same_map() {
+        let first = generate_map(&rough_settings(7));
+        let second = generate_map(&rough_settings(7));
+        assert_eq!(format!("{:?}", first), format!("{:?}", second));
+
+        let other = generate_map(&rough_settings(8));
+        assert_ne!(format!("{:?}", first), format!("{:?}", other));
+    }
+}
@@ -0,0 +1,514 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::f32::consts::FRAC_PI_4;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::collision::{Collider, ColliderShape};
+use crate::map::MapBounds;
+use crate::map_definition::BoundsMode;
+use crate::physics::{Position, Rotation};
+use crate::prop::{Prop, Rubble};
+use crate::terrain::{Terrain, MAX_DRIVABLE_SLOPE};
+
+/// Length of each side of a cell of the navigation grid.
+const NAV_CELL_SIZE: f32 = 8.0;
+
+/// Distance a tank keeps from obstacles. Obstacles block all cells within this distance.
+const NAV_CLEARANCE: f32 = 5.0;
+
+/// Cost factor for driving through cells covered by rubble.
+const RUBBLE_COST: f32 = 3.0;
+
+/// Cost factor for driving through cells outside of the playable area.
+const OUTSIDE_COST: f32 = 10.0;
+
+/// Highest number of straight steps required between two turns, limits the size of the search.
+const MAX_TURN_COOLDOWN: usize = 8;
+
+/// The eight directions between neighboring cells, counterclockwise starting at +x.
+/// Neighbors in the list are 45 degrees apart.
+const DIRECTIONS: [(isize, isize); 8] = [(1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1), (0, -1), (1, -1)];
+
+/// Resource to store the navigation grid of the loaded map.
+/// The grid is a square of `size` x `size` cells of `NAV_CELL_SIZE` covering the drivable area, centered at the origin.
+/// Cells are blocked by obstacles, moves between cells are blocked by slopes steeper than `MAX_DRIVABLE_SLOPE`.
+/// Cells with rubble or outside of the playable area can be driven through, but paths avoid them.
+/// Destroyed obstacles are removed from the grid without rebuilding it, `revision` is increased whenever the grid changes so planned paths can be checked again.
+#[derive(Resource, Debug, Clone)]
+pub struct NavGrid {
+    pub size: usize,
+    pub cell_size: f32,
+    pub revision: u32,
+    heights: Vec<f32>,
+    /// Number of obstacles blocking each cell.
+    blocking: Vec<u16>,
+    /// Number of rubble piles covering each cell.
+    rubble: Vec<u16>,
+    /// Cells outside of the playable area.
+    outside: Vec<bool>,
+    /// The cells blocked by each obstacle, to remove them when the obstacle is destroyed.
+    obstacles: HashMap<Entity, Vec<usize>>,
+    /// The cells covered by each rubble pile.
+    rubble_piles: HashMap<Entity, Vec<usize>>,
+}
+
+/// Entry of the open list of the A* search, ordered so the `BinaryHeap` returns the lowest estimate first.
+#[derive(Debug, Clone, Copy)]
+struct OpenState {
+    estimate: f32,
+    state: usize,
+}
+
+impl PartialEq for OpenState {
+    fn eq(&self, other: &Self) -> bool {
+        self.estimate == other.estimate
+    }
+}
+
+impl Eq for OpenState {}
+
+impl PartialOrd for OpenState {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OpenState {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.estimate.total_cmp(&self.estimate)
+    }
+}
+
+impl NavGrid {
+    /// Empty navigation grid on the terrain, covering the area within `half_extent` of the origin.
+    pub fn new(terrain: &Terrain, half_extent: f32) -> Self {
+        let size = ((half_extent * 2.0 / NAV_CELL_SIZE).ceil() as usize).max(1);
+        let mut grid = NavGrid {
+            size,
+            cell_size: NAV_CELL_SIZE,
+            revision: 0,
+            heights: Vec::with_capacity(size * size),
+            blocking: vec![0; size * size],
+            rubble: vec![0; size * size],
+            outside: vec![false; size * size],
+            obstacles: HashMap::default(),
+            rubble_piles: HashMap::default(),
+        };
+        for j in 0..size {
+            for i in 0..size {
+                let center = grid.cell_center_2d(i, j);
+                grid.heights.push(terrain.height_at(center.x, center.y));
+            }
+        }
+        grid
+    }
+
+    /// Distance from the center of the grid to its edges.
+    pub fn half_extent(&self) -> f32 {
+        self.size as f32 * self.cell_size / 2.0
+    }
+
+    /// The cell containing a position, `None` if it is outside of the grid.
+    pub fn cell_at(&self, position: Vec3) -> Option<(usize, usize)> {
+        let i = ((position.x + self.half_extent()) / self.cell_size).floor();
+        let j = ((position.z + self.half_extent()) / self.cell_size).floor();
+        if i < 0.0 || j < 0.0 || i >= self.size as f32 || j >= self.size as f32 {
+            return None;
+        }
+        Some((i as usize, j as usize))
+    }
+
+    /// Center of a cell on the terrain.
+    pub fn cell_center(&self, i: usize, j: usize) -> Vec3 {
+        let center = self.cell_center_2d(i, j);
+        Vec3::new(center.x, self.heights[j * self.size + i], center.y)
+    }
+
+    fn cell_center_2d(&self, i: usize, j: usize) -> Vec2 {
+        Vec2::new(
+            (i as f32 + 0.5) * self.cell_size - self.half_extent(),
+            (j as f32 + 0.5) * self.cell_size - self.half_extent(),
+        )
+    }
+
+    /// The cell containing a position, positions outside of the grid use the closest cell.
+    fn clamped_cell_at(&self, position: Vec3) -> (usize, usize) {
+        let max = self.size as f32 - 1.0;
+        let i = ((position.x + self.half_extent()) / self.cell_size).floor().clamp(0.0, max);
+        let j = ((position.z + self.half_extent()) / self.cell_size).floor().clamp(0.0, max);
+        (i as usize, j as usize)
+    }
+
+    /// Whether a tank can drive into a cell.
+    pub fn is_walkable(&self, i: usize, j: usize) -> bool {
+        self.blocking[j * self.size + i] == 0
+    }
+
+    /// Mark the cells outside of a square playable area within `half_extent` of the origin.
+    pub fn set_playable_area(&mut self, half_extent: f32) {
+        for j in 0..self.size {
+            for i in 0..self.size {
+                let center = self.cell_center_2d(i, j);
+                self.outside[j * self.size + i] = center.x.abs() > half_extent || center.y.abs() > half_extent;
+            }
+        }
+        self.revision += 1;
+    }
+
+    /// The cells within `clearance` of the footprint of a collider.
+    fn covered_cells(&self, collider: &Collider, position: Vec3, rotation: Quat, clearance: f32) -> Vec<usize> {
+        let center = position + rotation.mul_vec3(collider.offset);
+        let (half_extents, radius) = match collider.shape {
+            ColliderShape::Cuboid { half_extents } => (half_extents, 0.0),
+            ColliderShape::Sphere { radius } => (Vec3::ZERO, radius),
+            ColliderShape::Capsule { half_height, radius } => (Vec3::new(0.0, half_height, 0.0), radius),
+        };
+        let reach = half_extents.length() + radius + clearance;
+        let inverse = rotation.inverse();
+        let mut cells = Vec::new();
+        let (min_i, min_j) = self.clamped_cell_at(center - Vec3::new(reach, 0.0, reach));
+        let (max_i, max_j) = self.clamped_cell_at(center + Vec3::new(reach, 0.0, reach));
+        for j in min_j..=max_j {
+            for i in min_i..=max_i {
+                let cell = self.cell_center_2d(i, j);
+                let local = inverse.mul_vec3(Vec3::new(cell.x, center.y, cell.y) - center);
+                let outside = Vec2::new(
+                    (local.x.abs() - half_extents.x).max(0.0),
+                    (local.z.abs() - half_extents.z).max(0.0),
+                );
+                if outside.length() <= radius + clearance + self.cell_size / 2.0 {
+                    cells.push(j * self.size + i);
+                }
+            }
+        }
+        cells
+    }
+
+    /// Block the cells around an obstacle.
+    pub fn add_obstacle(&mut self, entity: Entity, collider: &Collider, position: Vec3, rotation: Quat) {
+        if self.obstacles.contains_key(&entity) {
+            return;
+        }
+        let cells = self.covered_cells(collider, position, rotation, NAV_CLEARANCE);
+        for &index in cells.iter() {
+            self.blocking[index] += 1;
+        }
+        self.obstacles.insert(entity, cells);
+        self.revision += 1;
+    }
+
+    /// Unblock the cells of a destroyed obstacle. Cells blocked by other obstacles stay blocked.
+    pub fn remove_obstacle(&mut self, entity: Entity) {
+        let Some(cells) = self.obstacles.remove(&entity) else {
+            return;
+        };
+        for index in cells {
+            self.blocking[index] -= 1;
+        }
+        self.revision += 1;
+    }
+
+    /// Make the cells covered by rubble more expensive to drive through.
+    pub fn add_rubble(&mut self, entity: Entity, rubble: &Rubble, position: Vec3, rotation: Quat) {
+        if self.rubble_piles.contains_key(&entity) {
+            return;
+        }
+        let cells = self.covered_cells(&Collider::cuboid(rubble.half_extents), position, rotation, 0.0);
+        for &index in cells.iter() {
+            self.rubble[index] += 1;
+        }
+        self.rubble_piles.insert(entity, cells);
+        self.revision += 1;
+    }
+
+    /// Remove the rubble of a prop that was restored.
+    pub fn remove_rubble(&mut self, entity: Entity) {
+        let Some(cells) = self.rubble_piles.remove(&entity) else {
+            return;
+        };
+        for index in cells {
+            self.rubble[index] -= 1;
+        }
+        self.revision += 1;
+    }
+
+    /// Find a path from `start` to `goal` with the A* algorithm.
+    /// The path starts facing `start_direction` and turns by at most 45 degrees at a time, with enough straight
+    /// driving between two turns to stay on a circle of `turn_radius`. A `turn_radius` of zero allows turning in place.
+    /// Returns the points where the path changes direction, ending at the goal, or `None` if the goal can not be reached.
+    pub fn find_path(&self, start: Vec3, start_direction: Vec3, goal: Vec3, turn_radius: f32) -> Option<Vec<Vec3>> {
+        let (start_i, start_j) = self.cell_at(start)?;
+        let (goal_i, goal_j) = self.cell_at(goal)?;
+        if !self.is_walkable(goal_i, goal_j) {
+            return None;
+        }
+        let turn_limited = turn_radius > 0.0;
+        // Number of straight steps needed after a 45 degree turn before the next one.
+        let cooldown = if turn_limited {
+            ((turn_radius * FRAC_PI_4 / self.cell_size).ceil() as usize).saturating_sub(1).min(MAX_TURN_COOLDOWN)
+        } else {
+            0
+        };
+        let cooldowns = cooldown + 1;
+        let state_of = |cell: usize, heading: usize, remaining: usize| (cell * 8 + heading) * cooldowns + remaining;
+        let cell_of = |state: usize| state / cooldowns / 8;
+        let heading_of = |state: usize| state / cooldowns % 8;
+        let remaining_of = |state: usize| state % cooldowns;
+        let heuristic = |cell: usize| {
+            let di = (cell % self.size).abs_diff(goal_i) as f32;
+            let dj = (cell / self.size).abs_diff(goal_j) as f32;
+            (di.max(dj) + (std::f32::consts::SQRT_2 - 1.0) * di.min(dj)) * self.cell_size
+        };
+
+        let start_cell = start_j * self.size + start_i;
+        let goal_cell = goal_j * self.size + goal_i;
+        let angle = start_direction.z.atan2(start_direction.x);
+        let start_heading = ((angle / FRAC_PI_4).round() as isize).rem_euclid(8) as usize;
+        let start_state = state_of(start_cell, start_heading, 0);
+
+        let mut costs: HashMap<usize, f32> = HashMap::default();
+        let mut came_from: HashMap<usize, usize> = HashMap::default();
+        let mut open = BinaryHeap::new();
+        costs.insert(start_state, 0.0);
+        open.push(OpenState { estimate: heuristic(start_cell), state: start_state });
+
+        while let Some(OpenState { estimate, state }) = open.pop() {
+            let cell = cell_of(state);
+            let cost = costs[&state];
+            if estimate > cost + heuristic(cell) {
+                continue;
+            }
+            if cell == goal_cell {
+                return Some(self.build_path(state, &came_from, cell_of, start, goal));
+            }
+            let heading = heading_of(state);
+            let remaining = remaining_of(state);
+            let (i, j) = ((cell % self.size) as isize, (cell / self.size) as isize);
+            for (direction, (di, dj)) in DIRECTIONS.iter().enumerate() {
+                let turn = (direction + 8 - heading) % 8;
+                let turns = turn != 0;
+                if turn_limited && turns && (remaining > 0 || !matches!(turn, 1 | 7)) {
+                    continue;
+                }
+                let (ni, nj) = (i + di, j + dj);
+                if ni < 0 || nj < 0 || ni >= self.size as isize || nj >= self.size as isize {
+                    continue;
+                }
+                let (ni, nj) = (ni as usize, nj as usize);
+                if !self.is_walkable(ni, nj) {
+                    continue;
+                }
+                // Diagonal moves must not cut the corners of blocked cells.
+                if *di != 0 && *dj != 0
+                    && (!self.is_walkable(ni, j as usize) || !self.is_walkable(i as usize, nj)) {
+                    continue;
+                }
+                let neighbor = nj * self.size + ni;
+                let distance = ((di * di + dj * dj) as f32).sqrt() * self.cell_size;
+                let climb = self.heights[neighbor] - self.heights[cell];
+                let slope = climb.abs() / distance;
+                if slope > MAX_DRIVABLE_SLOPE {
+                    continue;
+                }
+                let mut step_cost = distance * (1.0 + climb.max(0.0) / distance);
+                if self.rubble[neighbor] > 0 {
+                    step_cost *= RUBBLE_COST;
+                }
+                if self.outside[neighbor] {
+                    step_cost *= OUTSIDE_COST;
+                }
+                let next_remaining = if turns && turn_limited { cooldown } else { remaining.saturating_sub(1) };
+                let next_state = state_of(neighbor, direction, next_remaining);
+                let next_cost = cost + step_cost;
+                if costs.get(&next_state).map_or(true, |&known| next_cost < known) {
+                    costs.insert(next_state, next_cost);
+                    came_from.insert(next_state, state);
+                    open.push(OpenState { estimate: next_cost + heuristic(neighbor), state: next_state });
+                }
+            }
+        }
+        None
+    }
+
+    /// Follow the states of a found path back to the start and keep the points where the direction changes.
+    fn build_path(
+        &self,
+        end: usize,
+        came_from: &HashMap<usize, usize>,
+        cell_of: impl Fn(usize) -> usize,
+        start: Vec3,
+        goal: Vec3,
+    ) -> Vec<Vec3> {
+        let mut cells = vec![cell_of(end)];
+        let mut state = end;
+        while let Some(&previous) = came_from.get(&state) {
+            cells.push(cell_of(previous));
+            state = previous;
+        }
+        cells.reverse();
+
+        let mut path = Vec::new();
+        for window in cells.windows(3) {
+            let [a, b, c] = [window[0], window[1], window[2]];
+            if b as isize - a as isize != c as isize - b as isize {
+                path.push(self.cell_center(b % self.size, b / self.size));
+            }
+        }
+        let goal_height = self.cell_center(cell_of(end) % self.size, cell_of(end) / self.size).y;
+        path.push(Vec3::new(goal.x, goal_height, goal.z));
+        if path.len() > 1 && path[0].distance(start) < self.cell_size / 2.0 {
+            path.remove(0);
+        }
+        path
+    }
+}
+
+/// System to build the navigation grid when the terrain of a map is spawned.
+/// The grid covers the area inside the walls of the map, obstacles block the cells around them and rubble makes cells more expensive.
+#[allow(clippy::type_complexity)]
+pub fn build_nav_grid(
+    mut commands: Commands,
+    terrain: Option<Res<Terrain>>,
+    bounds: Option<Res<MapBounds>>,
+    prop_query: Query<(Entity, Option<&Collider>, Option<&Rubble>, &Position, &Rotation), With<Prop>>,
+) {
+    let Some(terrain) = terrain else {
+        return;
+    };
+    if !terrain.is_changed() {
+        return;
+    }
+    let half_extent = bounds.as_ref().map_or(terrain.half_extent(), |bounds| match bounds.mode {
+        BoundsMode::Wall => bounds.half_extent,
+        BoundsMode::Countdown { .. } => bounds.outer_half_extent,
+    });
+    let mut grid = NavGrid::new(&terrain, half_extent);
+    if let Some(bounds) = bounds {
+        grid.set_playable_area(bounds.half_extent);
+    }
+    for (entity, collider, rubble, position, rotation) in prop_query.iter() {
+        match (rubble, collider) {
+            (Some(rubble), _) => grid.add_rubble(entity, rubble, position.0, rotation.0),
+            (None, Some(collider)) => grid.add_obstacle(entity, collider, position.0, rotation.0),
+            (None, None) => {},
+        }
+    }
+    commands.insert_resource(grid);
+}
+
+/// System to update the navigation grid when obstacles are destroyed and turned into rubble,
+/// or restored when a replay moves back before their destruction.
+#[allow(clippy::type_complexity)]
+pub fn update_nav_grid(
+    grid: Option<ResMut<NavGrid>>,
+    rubble_query: Query<(Entity, &Rubble, &Position, &Rotation), Added<Rubble>>,
+    restored_query: Query<(Entity, &Collider, &Position, &Rotation), (With<Prop>, Added<Collider>)>,
+) {
+    let Some(mut grid) = grid else {
+        return;
+    };
+    for (entity, rubble, position, rotation) in rubble_query.iter() {
+        grid.remove_obstacle(entity);
+        grid.add_rubble(entity, rubble, position.0, rotation.0);
+    }
+    for (entity, collider, position, rotation) in restored_query.iter() {
+        grid.remove_rubble(entity);
+        grid.add_obstacle(entity, collider, position.0, rotation.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Navigation grid from rows of cells, `.` is flat ground, a digit `d` is a hill of height `4 * d` and `#` is a cliff.
+    /// The rows go along the z axis, the columns along the x axis.
+    fn grid(rows: &[&str]) -> NavGrid {
+        let size = rows.len();
+        let mut grid = NavGrid::new(&Terrain::flat(2, 1.0), size as f32 * NAV_CELL_SIZE / 2.0);
+        for (j, row) in rows.iter().enumerate() {
+            for (i, cell) in row.chars().enumerate() {
+                grid.heights[j * size + i] = match cell {
+                    '#' => 100.0,
+                    digit => digit.to_digit(10).unwrap_or(0) as f32 * 4.0,
+                };
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn path_crosses_flat_ground() {
+        let grid = grid(&[
+            ".....",
+            ".....",
+            ".....",
+            ".....",
+            ".....",
+        ]);
+        let goal = grid.cell_center(4, 4);
+        let path = grid.find_path(grid.cell_center(0, 0), Vec3::X, goal, 0.0).unwrap();
+        assert_eq!(*path.last().unwrap(), goal);
+    }
+
+    #[test]
+    fn steep_slopes_block_the_path() {
+        let cliff = grid(&[
+            ".....",
+            ".....",
+            "#####",
+            ".....",
+            ".....",
+        ]);
+        assert!(cliff.find_path(cliff.cell_center(2, 0), Vec3::Z, cliff.cell_center(2, 4), 0.0).is_none());
+
+        // A climb of 8 over a cell of 8 is steeper than the limit, a climb of 4 is not.
+        let steep = grid(&[
+            ".....",
+            ".....",
+            "##2##",
+            ".....",
+            ".....",
+        ]);
+        assert!(steep.find_path(steep.cell_center(2, 0), Vec3::Z, steep.cell_center(2, 4), 0.0).is_none());
+
+        let ramp = grid(&[
+            ".....",
+            ".....",
+            "##1##",
+            ".....",
+            ".....",
+        ]);
+        assert!(ramp.find_path(ramp.cell_center(0, 0), Vec3::Z, ramp.cell_center(4, 4), 0.0).is_some());
+    }
+
+    #[test]
+    fn turning_radius_limits_turns() {
+        // A dead end one cell wide, the tank faces its end and the goal is behind it.
+        let corridor = grid(&[
+            "#######",
+            "#######",
+            "#######",
+            "#.....#",
+            "#######",
+            "#######",
+            "#######",
+        ]);
+        let start = corridor.cell_center(4, 3);
+        let goal = corridor.cell_center(1, 3);
+        assert!(corridor.find_path(start, Vec3::X, goal, 0.0).is_some());
+        assert!(corridor.find_path(start, Vec3::X, goal, 16.0).is_none());
+
+        // In the open, the tank turns around on a wide curve instead.
+        let row = ".".repeat(15);
+        let open = grid(&[row.as_str(); 15]);
+        let start = open.cell_center(9, 7);
+        let goal = open.cell_center(5, 7);
+        assert_eq!(open.find_path(start, Vec3::X, goal, 0.0).unwrap(), vec![goal]);
+        let path = open.find_path(start, Vec3::X, goal, 16.0).unwrap();
+        assert!(path.len() > 2);
+        assert_eq!(*path.last().unwrap(), goal);
+    }
+}
@@ -0,0 +1,273 @@
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, AsyncReadExt, LoadContext};
+use bevy::prelude::*;
+use bevy::reflect::TypePath;
+use bevy::utils::BoxedFuture;
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Asset describing a map. Loaded from `.map.ron` files.
+/// The `prop_scenes` are loaded from the models of the `props` that have one, in the same order,
+/// and the `heightmap` from the image of an image terrain.
+#[derive(Asset, TypePath, Debug, Deserialize)]
+pub struct MapDefinition {
+    pub name: String,
+    /// Length of each side of the square map, centered at the origin.
+    pub size: f32,
+    pub terrain: TerrainDefinition,
+    #[serde(default)]
+    pub props: Vec<PropDefinition>,
+    pub spawn_points: Vec<SpawnPointDefinition>,
+    pub lighting: LightingDefinition,
+    pub sky_color: [f32; 3],
+    /// Playable area of the map. Without bounds the whole map is playable and its edges are walls.
+    #[serde(default)]
+    pub bounds: Option<BoundsDefinition>,
+    #[serde(skip)]
+    #[dependency]
+    pub prop_scenes: Vec<Handle<Scene>>,
+    #[serde(skip)]
+    #[dependency]
+    pub heightmap: Option<Handle<Image>>,
+}
+
+/// Where the heights of the terrain come from.
+#[derive(Debug, Deserialize, Clone)]
+pub enum TerrainSource {
+    Flat,
+    /// Fractal noise, the same seed always gives the same terrain.
+    Noise { seed: u32, amplitude: f32 },
+    /// Grayscale image, black is at height zero and white at `amplitude`.
+    Image { path: String, amplitude: f32 },
+    /// Explicit heights of all `resolution` x `resolution` grid points, row by row.
+    Heights { heights: Vec<f32> },
+}
+
+/// Terrain of a map. `resolution` is the number of grid points on each side of the heightmap,
+/// it is ignored for image terrains which use the size of the image.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TerrainDefinition {
+    pub resolution: usize,
+    pub source: TerrainSource,
+}
+
+/// A model placed on the map.
+/// The y coordinate of `position` is the height above the terrain, `rotation` are euler angles in radians (yaw, pitch, roll).
+/// Props with `half_extents` get a box collider, props with `capsule` an upright capsule collider of `(half_height, radius)`.
+/// Props without a `model` are drawn as their collider in `color`.
+/// Props with `health` can be destroyed by shooting or ramming them and leave rubble behind.
+#[derive(Debug, Deserialize, Clone)]
+pub struct PropDefinition {
+    #[serde(default)]
+    pub model: String,
+    pub position: [f32; 3],
+    #[serde(default)]
+    pub rotation: [f32; 3],
+    #[serde(default = "default_scale")]
+    pub scale: f32,
+    #[serde(default)]
+    pub half_extents: Option<[f32; 3]>,
+    #[serde(default)]
+    pub capsule: Option<[f32; 2]>,
+    #[serde(default = "default_color")]
+    pub color: [f32; 3],
+    #[serde(default)]
+    pub health: Option<f32>,
+}
+
+fn default_scale() -> f32 {
+    1.0
+}
+
+fn default_color() -> [f32; 3] {
+    [0.5, 0.5, 0.5]
+}
+
+/// A point where a tank of a team is spawned, `heading` is the yaw in radians.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct SpawnPointDefinition {
+    pub team: u8,
+    pub position: [f32; 2],
+    #[serde(default)]
+    pub heading: f32,
+}
+
+/// How tanks are kept inside the playable area.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub enum BoundsMode {
+    /// The edges of the playable area are solid walls.
+    Wall,
+    /// Tanks can leave the playable area but are destroyed if they do not return within `seconds`.
+    /// The edges of the map are still solid walls.
+    Countdown { seconds: f32 },
+}
+
+/// Square playable area of a map with the side length `size`, centered at the origin.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct BoundsDefinition {
+    pub size: f32,
+    pub mode: BoundsMode,
+}
+
+/// Ambient light and sun of a map.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct LightingDefinition {
+    pub ambient_color: [f32; 3],
+    pub ambient_brightness: f32,
+    pub sun_color: [f32; 3],
+    pub sun_illuminance: f32,
+    pub sun_direction: [f32; 3],
+}
+
+/// Errors that can occur while loading a map definition.
+#[derive(Debug, Error)]
+pub enum MapDefinitionLoaderError {
+    #[error("Could not read map definition: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not parse map definition: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+    #[error("Map size must be positive, got {0}")]
+    InvalidSize(f32),
+    #[error("Terrain resolution must be at least 2, got {0}")]
+    InvalidResolution(usize),
+    #[error("Terrain amplitude must not be negative, got {0}")]
+    InvalidAmplitude(f32),
+    #[error("Terrain needs {expected} heights for its resolution, got {found}")]
+    InvalidHeights { expected: usize, found: usize },
+    #[error("Prop {index} has neither a model nor a collider")]
+    PropWithoutModel { index: usize },
+    #[error("Prop {index} has health but no collider to be hit")]
+    DestructiblePropWithoutCollider { index: usize },
+    #[error("Prop {index} has both half extents and a capsule")]
+    PropWithTwoColliders { index: usize },
+    #[error("Map has no spawn points")]
+    NoSpawnPoints,
+    #[error("Spawn point {index} at {position:?} is outside of the map")]
+    SpawnPointOutOfBounds { index: usize, position: [f32; 2] },
+    #[error("Prop {index} ({model}) at {position:?} is outside of the map")]
+    PropOutOfBounds { index: usize, model: String, position: [f32; 3] },
+    #[error("Sun direction must not be zero")]
+    InvalidSunDirection,
+    #[error("Bounds size must be positive and not larger than the map size {map_size}, got {size}")]
+    InvalidBounds { size: f32, map_size: f32 },
+    #[error("Out of bounds countdown must be positive, got {0}")]
+    InvalidCountdown(f32),
+}
+
+impl MapDefinition {
+    /// Check the values that can not be checked by parsing.
+    pub fn validate(&self) -> Result<(), MapDefinitionLoaderError> {
+        if self.size.is_nan() || self.size <= 0.0 {
+            return Err(MapDefinitionLoaderError::InvalidSize(self.size));
+        }
+        match self.terrain.source {
+            TerrainSource::Flat => {},
+            TerrainSource::Noise { amplitude, .. } | TerrainSource::Image { amplitude, .. } => {
+                if amplitude < 0.0 {
+                    return Err(MapDefinitionLoaderError::InvalidAmplitude(amplitude));
+                }
+            },
+            TerrainSource::Heights { ref heights } => {
+                let expected = self.terrain.resolution * self.terrain.resolution;
+                if heights.len() != expected {
+                    return Err(MapDefinitionLoaderError::InvalidHeights { expected, found: heights.len() });
+                }
+            },
+        }
+        if !matches!(self.terrain.source, TerrainSource::Image { .. }) && self.terrain.resolution < 2 {
+            return Err(MapDefinitionLoaderError::InvalidResolution(self.terrain.resolution));
+        }
+        if self.spawn_points.is_empty() {
+            return Err(MapDefinitionLoaderError::NoSpawnPoints);
+        }
+        let half_size = self.size / 2.0;
+        for (index, spawn_point) in self.spawn_points.iter().enumerate() {
+            let [x, z] = spawn_point.position;
+            if x.abs() > half_size || z.abs() > half_size {
+                return Err(MapDefinitionLoaderError::SpawnPointOutOfBounds { index, position: spawn_point.position });
+            }
+        }
+        for (index, prop) in self.props.iter().enumerate() {
+            if prop.half_extents.is_some() && prop.capsule.is_some() {
+                return Err(MapDefinitionLoaderError::PropWithTwoColliders { index });
+            }
+            let has_collider = prop.half_extents.is_some() || prop.capsule.is_some();
+            if prop.model.is_empty() && !has_collider {
+                return Err(MapDefinitionLoaderError::PropWithoutModel { index });
+            }
+            if prop.health.is_some() && !has_collider {
+                return Err(MapDefinitionLoaderError::DestructiblePropWithoutCollider { index });
+            }
+            let [x, _, z] = prop.position;
+            if x.abs() > half_size || z.abs() > half_size {
+                return Err(MapDefinitionLoaderError::PropOutOfBounds { index, model: prop.model.clone(), position: prop.position });
+            }
+        }
+        if Vec3::from(self.lighting.sun_direction) == Vec3::ZERO {
+            return Err(MapDefinitionLoaderError::InvalidSunDirection);
+        }
+        if let Some(bounds) = self.bounds {
+            if bounds.size.is_nan() || bounds.size <= 0.0 || bounds.size > self.size {
+                return Err(MapDefinitionLoaderError::InvalidBounds { size: bounds.size, map_size: self.size });
+            }
+            if let BoundsMode::Countdown { seconds } = bounds.mode {
+                if seconds.is_nan() || seconds <= 0.0 {
+                    return Err(MapDefinitionLoaderError::InvalidCountdown(seconds));
+                }
+            }
+            for (index, spawn_point) in self.spawn_points.iter().enumerate() {
+                let [x, z] = spawn_point.position;
+                if x.abs() > bounds.size / 2.0 || z.abs() > bounds.size / 2.0 {
+                    return Err(MapDefinitionLoaderError::SpawnPointOutOfBounds { index, position: spawn_point.position });
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Asset loader for `MapDefinition` assets.
+/// Without `load_models` the scenes of the props are not loaded, the heightmap is always loaded.
+pub struct MapDefinitionLoader {
+    pub load_models: bool,
+}
+
+impl Default for MapDefinitionLoader {
+    fn default() -> Self {
+        MapDefinitionLoader {
+            load_models: true,
+        }
+    }
+}
+
+impl AssetLoader for MapDefinitionLoader {
+    type Asset = MapDefinition;
+    type Settings = ();
+    type Error = MapDefinitionLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let mut definition: MapDefinition = ron::de::from_bytes(&bytes)?;
+            definition.validate()?;
+            definition.prop_scenes = definition.props.iter()
+                .filter(|prop| self.load_models && !prop.model.is_empty())
+                .map(|prop| load_context.load(prop.model.clone()))
+                .collect();
+            if let TerrainSource::Image { path, .. } = &definition.terrain.source {
+                definition.heightmap = Some(load_context.load(path.clone()));
+            }
+            Ok(definition)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["map.ron"]
+    }
+}
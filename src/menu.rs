@@ -1,7 +1,10 @@
+use bevy::app::AppExit;
+use bevy::input::mouse::{MouseScrollUnit, MouseWheel};
 use bevy::prelude::*;
 use bevy_framepace::FramepaceSettings;
 
 use crate::asset_loader::FontAssets;
+use crate::input_map::{ActionInput, Binding, InputAction, InputMap, Layout, INPUT_CONFIG_PATH};
 use crate::schedule::ScheduleSet;
 
 #[derive(Component)]
@@ -13,10 +16,81 @@ pub struct FPSButton;
 #[derive(Component)]
 pub struct QuitButton;
 
+#[derive(Component)]
+pub struct BindingsButton;
+
+#[derive(Component)]
+pub struct ResetBindingsButton;
+
+#[derive(Component)]
+pub struct BackButton;
+
+/// Component for the buttons of the bindings page and their text, storing the action and layout the button rebinds.
+#[derive(Component)]
+pub struct RebindButton(pub InputAction, pub Layout);
+
+/// Marker component for the text showing the state of the rebinding.
+#[derive(Component)]
+pub struct RebindStatusText;
+
+/// Marker component for the root node of the main page of the pause menu.
+#[derive(Component)]
+pub struct PauseMenuPanel;
+
+/// Marker component for the root node of the bindings page of the pause menu.
+#[derive(Component)]
+pub struct BindingsMenuPanel;
+
+/// Resource to store the action and layout the next key or button press is bound to.
+/// `armed` is set one frame after the capture started, so the press that started it is not captured.
+/// `conflicts` are the actions that already use the last captured binding.
+#[derive(Resource)]
+pub struct Rebinding {
+    pub action: Option<InputAction>,
+    pub layout: Layout,
+    pub armed: bool,
+    pub conflicts: Vec<(InputAction, Layout)>,
+}
+
+impl Default for Rebinding {
+    fn default() -> Self {
+        Rebinding {
+            action: None,
+            layout: Layout::Primary,
+            armed: false,
+            conflicts: Vec::new(),
+        }
+    }
+}
+
+/// Component to store the position of a button in the menu, used for the navigation with a gamepad.
+#[derive(Component)]
+pub struct MenuButtonIndex(pub usize);
+
+/// Event sent when a menu button is pressed, by clicking it or by selecting it with the `MenuSelect` action.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct MenuButtonPressed(pub Entity);
+
+/// Resource to store the index of the button selected with the gamepad.
+#[derive(Resource, Default)]
+pub struct MenuSelection(pub usize);
+
 /// Marker component for menu items.
 #[derive(Component)]
 pub struct MenuItem;
 
+/// Component for the list of buttons on the bindings page, storing how far it is scrolled up in pixels.
+#[derive(Component, Default)]
+pub struct ScrollingList {
+    pub position: f32,
+}
+
+/// Highest height of the list of buttons on the bindings page, in percent of the window height.
+const BINDINGS_LIST_HEIGHT: f32 = 55.0;
+
+/// Distance the list of buttons is scrolled for each line of the mouse wheel.
+const SCROLL_LINE_HEIGHT: f32 = 30.0;
+
 
 /// Plugin for the menu system.
 pub struct MenuPlugin;
@@ -39,17 +113,32 @@ impl Default for MenuState {
 /// Implementation of the `Plugin` trait for the `MenuPlugin` struct.
 impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
-        app.add_state::<MenuState>()
+        app.init_resource::<MenuSelection>()
+            .init_resource::<Rebinding>()
+            .add_event::<MenuButtonPressed>()
             .add_systems(Update, (
                 toggle_menu,
                 clear_menu,
             ).in_set(ScheduleSet::CheckMenu))
             .add_systems(Update, (
-                toggle_framerate_lock,
-                fortsetzen_button,
-                quit_game_button,
-                button_hower,
-            ).in_set(ScheduleSet::PauseMenu));
+                (
+                    click_menu_buttons,
+                    action_menu_navigation.run_if(not_rebinding),
+                ),
+                (
+                    toggle_framerate_lock,
+                    fortsetzen_button,
+                    quit_game_button,
+                    bindings_button,
+                    back_button.run_if(not_rebinding),
+                    reset_bindings_button.run_if(not_rebinding),
+                    rebind_button.run_if(not_rebinding),
+                    button_hower,
+                ),
+                capture_rebinding,
+                update_binding_texts,
+                scroll_bindings_list,
+            ).chain().in_set(ScheduleSet::PauseMenu));
     }
         
 }
@@ -59,183 +148,635 @@ fn clear_menu(
     simulation_state: Res<State<MenuState>>,
     mut query: Query<(Entity, &MenuItem)>,
 ) {
-    match simulation_state.get() {
-        MenuState::Closed => {
-            for (entity, _) in query.iter_mut() {
-                commands.entity(entity).despawn_recursive();
-            }
-        },
-        _ => {},
+    if let MenuState::Closed = simulation_state.get() {
+        for (entity, _) in query.iter_mut() {
+            commands.entity(entity).despawn_recursive();
+        }
     }
 }
 
-/// System to toggle the menu state when the Escape key is pressed.
+/// System to toggle the menu state when a key or button of the `Pause` action is pressed.
+/// Does nothing while a key is captured for rebinding, so the pause key can be bound too.
 fn toggle_menu(
     mut commands: Commands,
-    keyboard_inputs: Res<Input<KeyCode>>,
+    input: ActionInput,
     simulation_state: Res<State<MenuState>>,
     font_assets: Res<FontAssets>,
+    input_map: Res<InputMap>,
+    mut selection: ResMut<MenuSelection>,
+    mut rebinding: ResMut<Rebinding>,
 ) {
-    if keyboard_inputs.just_pressed(KeyCode::Escape) {
+    if rebinding.action.is_some() {
+        return;
+    }
+    if input.just_pressed(InputAction::Pause) {
         match simulation_state.get() {
             MenuState::Closed => {
                 commands.insert_resource(NextState(Some(MenuState::Open)));
-                commands.spawn((
-                    MenuItem,
-                    NodeBundle {
-                        style: Style {
-                            width: Val::Percent(100.0),
-                            height: Val::Percent(100.0),
-                            align_items: AlignItems::Center,
-                            justify_content: JustifyContent::Center,
-                            ..default()
-                        },
+                selection.0 = 0;
+                *rebinding = Rebinding::default();
+                spawn_pause_menu(&mut commands, &font_assets, &input_map);
+            },
+            MenuState::Open => {
+                commands.insert_resource(NextState(Some(MenuState::Closed)));
+            },
+        };
+    }
+}
+
+/// Spawn the main page of the pause menu.
+/// The hint how to continue names the current bindings of the `Pause` action.
+fn spawn_pause_menu(commands: &mut Commands, font_assets: &FontAssets, input_map: &InputMap) {
+    let pause_bindings: Vec<String> = input_map.get(InputAction::Pause).iter().map(|binding| binding.to_string()).collect();
+    commands.spawn((
+        MenuItem,
+        PauseMenuPanel,
+        NodeBundle {
+            style: Style {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            ..default()
+        },
+        ))
+        .with_children(|parent| {
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        align_items: AlignItems::Center,
                         ..default()
                     },
-                    ))
+                    background_color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent.spawn(TextBundle::from_section(
+                        "Tank Game",
+                        TextStyle {
+                            font: font_assets.menu_font.clone(),
+                            font_size: 80.0,
+                            color: Color::RED,
+                            },
+                        ).with_style(Style {
+                            margin: UiRect::all(Val::Px(30.0)),
+                            ..default()
+                        }),
+                    );
+                    parent.spawn(TextBundle::from_section(
+                        "Spiel Pausiert",
+                        TextStyle {
+                            font: font_assets.menu_font.clone(),
+                            font_size: 50.0,
+                            color: Color::WHITE,
+                            },
+                        ).with_style(Style {
+                            margin: UiRect::all(Val::Px(15.0)),
+                            ..default()
+                        }),
+                    );
+                    parent.spawn(TextBundle::from_section(
+                        format!("{} zum Fortsetzen", pause_bindings.join(" oder ")),
+                        TextStyle {
+                            font: font_assets.menu_font.clone(),
+                            font_size: 50.0,
+                            color: Color::WHITE,
+                            },
+                        ).with_style(Style {
+                            margin: UiRect::all(Val::Px(15.0)),
+                            ..default()
+                        }),
+                    );
+
+                    // Fortsetzen Button
+                    parent.spawn((
+                        FortsetzenButton,
+                        MenuButtonIndex(0),
+                        ButtonBundle {
+                            style: Style {
+                                margin: UiRect::all(Val::Px(15.0)),
+                                ..default()
+                            },
+                            background_color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
+                            ..Default::default()
+                    }))
                     .with_children(|parent| {
-                        parent
-                            .spawn(NodeBundle {
-                                style: Style {
-                                    flex_direction: FlexDirection::Column,
-                                    align_items: AlignItems::Center,
-                                    ..default()
-                                },
-                                background_color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+                        parent.spawn((MenuItem, TextBundle::from_section(
+                            "Fortsetzen",
+                            TextStyle {
+                                font: font_assets.menu_font.clone(),
+                                font_size: 25.0,
+                                color: Color::WHITE,
+                            },
+                        ).with_style(
+                            Style {
+                                margin: UiRect::all(Val::Px(15.0)),
                                 ..default()
-                            })
-                            .with_children(|parent| {
-                                parent.spawn(TextBundle::from_section(
-                                    "Tank Game",
-                                    TextStyle {
-                                        font: font_assets.menu_font.clone(),
-                                        font_size: 80.0,
-                                        color: Color::RED,
-                                        },
-                                    ).with_style(Style {
-                                        margin: UiRect::all(Val::Px(30.0)),
-                                        ..default()
-                                    }),
-                                );
-                                parent.spawn(TextBundle::from_section(
-                                    "Spiel Pausiert",
-                                    TextStyle {
-                                        font: font_assets.menu_font.clone(),
-                                        font_size: 50.0,
-                                        color: Color::WHITE,
-                                        },
-                                    ).with_style(Style {
-                                        margin: UiRect::all(Val::Px(15.0)),
-                                        ..default()
-                                    }),
-                                );
-                                parent.spawn(TextBundle::from_section(
-                                    "Escape zum Fortsetzen",
-                                    TextStyle {
-                                        font: font_assets.menu_font.clone(),
-                                        font_size: 50.0,
-                                        color: Color::WHITE,
-                                        },
-                                    ).with_style(Style {
-                                        margin: UiRect::all(Val::Px(15.0)),
-                                        ..default()
-                                    }),
-                                );
+                            }
+                        )));
+                    });
 
-                                // Fortsetzen Button
-                                parent.spawn((
-                                    FortsetzenButton,
-                                    ButtonBundle {
-                                        style: Style {
-                                            margin: UiRect::all(Val::Px(15.0)),
-                                            ..default()
-                                        },
-                                        background_color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
-                                        ..Default::default()
-                                }))
-                                .with_children(|parent| {
-                                    parent.spawn((MenuItem, TextBundle::from_section(
-                                        "Fortsetzen",
-                                        TextStyle {
-                                            font: font_assets.menu_font.clone(),
-                                            font_size: 25.0,
-                                            color: Color::WHITE,
-                                        },
-                                    ).with_style(
-                                        Style {
-                                            margin: UiRect::all(Val::Px(15.0)),
-                                            ..default()
-                                        }
-                                    )));
-                                });
+                    // FPS Lock Button
+                    parent.spawn((
+                        FPSButton,
+                        MenuButtonIndex(1),
+                        ButtonBundle {
+                            style: Style {
+                                margin: UiRect::all(Val::Px(15.0)),
+                                ..default()
+                            },
+                            background_color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
+                            ..Default::default()
+                    }))
+                    .with_children(|parent| {
+                        parent.spawn((
+                            FPSButton,
+                            TextBundle::from_section(
+                            "Framerate: Locked",
+                            TextStyle {
+                                font: font_assets.menu_font.clone(),
+                                font_size: 25.0,
+                                color: Color::WHITE,
+                            },
+                        ).with_style(
+                            Style {
+                                margin: UiRect::all(Val::Px(15.0)),
+                                ..default()
+                            }
+                        )));
+                    });
 
-                                // FPS Lock Button
-                                parent.spawn((
-                                    FPSButton,
-                                    ButtonBundle {
-                                        style: Style {
-                                            margin: UiRect::all(Val::Px(15.0)),
-                                            ..default()
-                                        },
-                                        background_color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
-                                        ..Default::default()
-                                }))
-                                .with_children(|parent| {
-                                    parent.spawn((
-                                        FPSButton,
-                                        TextBundle::from_section(
-                                        "Framerate: Locked",
-                                        TextStyle {
-                                            font: font_assets.menu_font.clone(),
-                                            font_size: 25.0,
-                                            color: Color::WHITE,
-                                        },
-                                    ).with_style(
-                                        Style {
-                                            margin: UiRect::all(Val::Px(15.0)),
-                                            ..default()
-                                        }
-                                    )));
-                                });
+                    // Tastenbelegung Button
+                    parent.spawn((
+                        BindingsButton,
+                        MenuButtonIndex(2),
+                        ButtonBundle {
+                            style: Style {
+                                margin: UiRect::all(Val::Px(15.0)),
+                                ..default()
+                            },
+                            background_color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
+                            ..Default::default()
+                    }))
+                    .with_children(|parent| {
+                        parent.spawn(TextBundle::from_section(
+                            "Tastenbelegung",
+                            TextStyle {
+                                font: font_assets.menu_font.clone(),
+                                font_size: 25.0,
+                                color: Color::WHITE,
+                            },
+                        ).with_style(
+                            Style {
+                                margin: UiRect::all(Val::Px(15.0)),
+                                ..default()
+                            }
+                        ));
+                    });
+
+                    // Quit Button
+                    parent.spawn((
+                        QuitButton,
+                        MenuButtonIndex(3),
+                        ButtonBundle {
+                            style: Style {
+                                margin: UiRect::all(Val::Px(15.0)),
+                                ..default()
+                            },
+                            background_color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
+                            ..Default::default()
+                    }))
+                    .with_children(|parent| {
+                        parent.spawn((
+                            QuitButton,
+                            TextBundle::from_section(
+                            "Spiel Beenden",
+                            TextStyle {
+                                font: font_assets.menu_font.clone(),
+                                font_size: 25.0,
+                                color: Color::WHITE,
+                            },
+                        ).with_style(
+                            Style {
+                                margin: UiRect::all(Val::Px(15.0)),
+                                ..default()
+                            }
+                        )));
+                    });
+            });
+    });
+}
+
+/// Spawn the page of the pause menu to change the key bindings.
+/// Every action has a button showing its bindings, pressing it captures the next key or button for the action.
+/// Actions of the second player sharing the keyboard have a second button for the second layout.
+/// The buttons are in a list that is scrolled when it is higher than `BINDINGS_LIST_HEIGHT`.
+fn spawn_bindings_menu(commands: &mut Commands, font_assets: &FontAssets, input_map: &InputMap) {
+    commands.spawn((
+        MenuItem,
+        BindingsMenuPanel,
+        NodeBundle {
+            style: Style {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                align_items: AlignItems::Center,
+                justify_content: JustifyContent::Center,
+                ..default()
+            },
+            ..default()
+        },
+        ))
+        .with_children(|parent| {
+            parent
+                .spawn(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::Column,
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    background_color: Color::rgba(0.0, 0.0, 0.0, 0.5).into(),
+                    ..default()
+                })
+                .with_children(|parent| {
+                    parent.spawn(TextBundle::from_section(
+                        "Tastenbelegung",
+                        TextStyle {
+                            font: font_assets.menu_font.clone(),
+                            font_size: 50.0,
+                            color: Color::WHITE,
+                            },
+                        ).with_style(Style {
+                            margin: UiRect::all(Val::Px(15.0)),
+                            ..default()
+                        }),
+                    );
+                    parent.spawn((
+                        RebindStatusText,
+                        TextBundle::from_section(
+                        "",
+                        TextStyle {
+                            font: font_assets.menu_font.clone(),
+                            font_size: 20.0,
+                            color: Color::YELLOW,
+                            },
+                        ).with_style(Style {
+                            margin: UiRect::all(Val::Px(5.0)),
+                            ..default()
+                        }),
+                    ));
 
-                                // Quit Button
-                                parent.spawn((
-                                    QuitButton,
-                                    ButtonBundle {
+                    let mut count = 0;
+                    parent
+                        .spawn(NodeBundle {
+                            style: Style {
+                                flex_direction: FlexDirection::Column,
+                                align_self: AlignSelf::Stretch,
+                                max_height: Val::Vh(BINDINGS_LIST_HEIGHT),
+                                overflow: Overflow::clip_y(),
+                                ..default()
+                            },
+                            ..default()
+                        })
+                        .with_children(|parent| {
+                            parent
+                                .spawn((
+                                    ScrollingList::default(),
+                                    NodeBundle {
                                         style: Style {
-                                            margin: UiRect::all(Val::Px(15.0)),
+                                            flex_direction: FlexDirection::Column,
+                                            align_items: AlignItems::Center,
                                             ..default()
                                         },
-                                        background_color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
-                                        ..Default::default()
-                                }))
+                                        ..default()
+                                    },
+                                ))
                                 .with_children(|parent| {
-                                    parent.spawn((
-                                        QuitButton,
-                                        TextBundle::from_section(
-                                        "Spiel Beenden",
-                                        TextStyle {
-                                            font: font_assets.menu_font.clone(),
-                                            font_size: 25.0,
-                                            color: Color::WHITE,
-                                        },
-                                    ).with_style(
-                                        Style {
-                                            margin: UiRect::all(Val::Px(15.0)),
+                                    for action in InputAction::ALL {
+                                        parent.spawn(NodeBundle {
+                                            style: Style {
+                                                flex_direction: FlexDirection::Row,
+                                                ..default()
+                                            },
                                             ..default()
-                                        }
-                                    )));
+                                        })
+                                        .with_children(|parent| {
+                                            for layout in Layout::ALL {
+                                                if layout == Layout::Secondary && !action.has_secondary() {
+                                                    continue;
+                                                }
+                                                let label = binding_label(input_map, action, layout);
+                                                spawn_menu_button(parent, font_assets, (RebindButton(action, layout), MenuButtonIndex(count)), RebindButton(action, layout), label);
+                                                count += 1;
+                                            }
+                                        });
+                                    }
                                 });
                         });
+                    spawn_menu_button(parent, font_assets, (ResetBindingsButton, MenuButtonIndex(count)), (), "Standard".to_string());
+                    spawn_menu_button(parent, font_assets, (BackButton, MenuButtonIndex(count + 1)), (), "Zurück".to_string());
                 });
+        });
+}
+
+/// Spawn a button of the menu with a single line of text.
+/// `button` is added to the button and `text` to its text.
+fn spawn_menu_button(parent: &mut ChildBuilder, font_assets: &FontAssets, button: impl Bundle, text: impl Bundle, label: String) {
+    parent.spawn((
+        button,
+        ButtonBundle {
+            style: Style {
+                margin: UiRect::all(Val::Px(5.0)),
+                ..default()
             },
-            MenuState::Open => {
-                commands.insert_resource(NextState(Some(MenuState::Closed)));
+            background_color: Color::rgba(0.0, 0.0, 0.0, 0.8).into(),
+            ..Default::default()
+    }))
+    .with_children(|parent| {
+        parent.spawn((
+            text,
+            TextBundle::from_section(
+            label,
+            TextStyle {
+                font: font_assets.menu_font.clone(),
+                font_size: 20.0,
+                color: Color::WHITE,
             },
+        ).with_style(
+            Style {
+                margin: UiRect::all(Val::Px(8.0)),
+                ..default()
+            }
+        )));
+    });
+}
+
+/// Name of an action in a layout, the second keyboard layout is marked as such.
+fn action_label(action: InputAction, layout: Layout) -> String {
+    match layout {
+        Layout::Primary => action.label().to_string(),
+        Layout::Secondary => format!("{} (Tastatur 2)", action.label()),
+    }
+}
+
+/// Text of the button of an action in a layout on the bindings page.
+fn binding_label(input_map: &InputMap, action: InputAction, layout: Layout) -> String {
+    let bindings: Vec<String> = input_map.get_in(layout, action).iter().map(|binding| binding.to_string()).collect();
+    format!("{}: {}", action_label(action, layout), bindings.join(", "))
+}
+
+/// Run condition that is true while no key or button is captured for rebinding.
+fn not_rebinding(rebinding: Res<Rebinding>) -> bool {
+    rebinding.action.is_none()
+}
+
+/// System to send a `MenuButtonPressed` event for every menu button clicked with the mouse.
+#[allow(clippy::type_complexity)]
+fn click_menu_buttons(
+    interaction_query: Query<(Entity, &Interaction), (Changed<Interaction>, With<Button>)>,
+    mut pressed_events: EventWriter<MenuButtonPressed>,
+) {
+    for (entity, interaction) in interaction_query.iter() {
+        if *interaction == Interaction::Pressed {
+            pressed_events.send(MenuButtonPressed(entity));
+        }
+    }
+}
+
+/// System to navigate the menu buttons with the `MenuUp` and `MenuDown` actions, by default on the D-pad of a gamepad.
+/// The selected button is highlighted, the `MenuSelect` action presses it.
+fn action_menu_navigation(
+    mut button_query: Query<(Entity, &MenuButtonIndex, &mut BackgroundColor), With<Button>>,
+    input: ActionInput,
+    mut selection: ResMut<MenuSelection>,
+    mut pressed_events: EventWriter<MenuButtonPressed>,
+) {
+    let count = button_query.iter().count();
+    if count == 0 {
+        return;
+    }
+    let mut moved = false;
+    if input.just_pressed(InputAction::MenuUp) {
+        selection.0 = (selection.0 + count - 1) % count;
+        moved = true;
+    }
+    if input.just_pressed(InputAction::MenuDown) {
+        selection.0 = (selection.0 + 1) % count;
+        moved = true;
+    }
+    let pressed = input.just_pressed(InputAction::MenuSelect);
+    if !moved && !pressed {
+        return;
+    }
+
+    for (entity, index, mut background_color) in button_query.iter_mut() {
+        if index.0 == selection.0 {
+            background_color.0 = Color::rgba(0.0, 0.0, 0.0, 0.8);
+            if pressed {
+                pressed_events.send(MenuButtonPressed(entity));
+            }
+        } else {
+            background_color.0 = Color::rgba(0.0, 0.0, 0.0, 0.5);
+        }
+    }
+}
+
+/// System to open the bindings page of the menu.
+fn bindings_button(
+    mut pressed_events: EventReader<MenuButtonPressed>,
+    button_query: Query<(), With<BindingsButton>>,
+    panel_query: Query<Entity, With<PauseMenuPanel>>,
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    input_map: Res<InputMap>,
+    mut selection: ResMut<MenuSelection>,
+) {
+    for pressed in pressed_events.read() {
+        if button_query.contains(pressed.0) {
+            for panel in panel_query.iter() {
+                commands.entity(panel).despawn_recursive();
+            }
+            spawn_bindings_menu(&mut commands, &font_assets, &input_map);
+            selection.0 = 0;
+        }
+    }
+}
+
+/// System to go back from the bindings page to the main page of the menu.
+fn back_button(
+    mut pressed_events: EventReader<MenuButtonPressed>,
+    button_query: Query<(), With<BackButton>>,
+    panel_query: Query<Entity, With<BindingsMenuPanel>>,
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+    input_map: Res<InputMap>,
+    mut selection: ResMut<MenuSelection>,
+) {
+    for pressed in pressed_events.read() {
+        if button_query.contains(pressed.0) {
+            for panel in panel_query.iter() {
+                commands.entity(panel).despawn_recursive();
+            }
+            spawn_pause_menu(&mut commands, &font_assets, &input_map);
+            selection.0 = 0;
+        }
+    }
+}
+
+/// System to reset all bindings to their defaults and save them.
+fn reset_bindings_button(
+    mut pressed_events: EventReader<MenuButtonPressed>,
+    button_query: Query<(), With<ResetBindingsButton>>,
+    mut input_map: ResMut<InputMap>,
+) {
+    for pressed in pressed_events.read() {
+        if button_query.contains(pressed.0) {
+            *input_map = InputMap::default();
+            if let Err(error) = input_map.save(INPUT_CONFIG_PATH) {
+                warn!("{}", error);
+            }
+        }
+    }
+}
+
+/// System to start capturing a new binding for the action of the pressed button.
+fn rebind_button(
+    mut pressed_events: EventReader<MenuButtonPressed>,
+    button_query: Query<&RebindButton, With<Button>>,
+    mut rebinding: ResMut<Rebinding>,
+) {
+    for pressed in pressed_events.read() {
+        if let Ok(button) = button_query.get(pressed.0) {
+            *rebinding = Rebinding {
+                action: Some(button.0),
+                layout: button.1,
+                armed: false,
+                conflicts: Vec::new(),
+            };
+        }
+    }
+}
+
+/// System to bind the next pressed key, mouse button or gamepad button to the captured action.
+/// The second keyboard layout only takes keys.
+/// A binding already used by another action in either layout is rejected and the conflict is shown, Escape cancels the capture.
+/// The input map is saved to the config file after every change.
+fn capture_rebinding(
+    mut rebinding: ResMut<Rebinding>,
+    mut input_map: ResMut<InputMap>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    gamepad_input: Res<Input<GamepadButton>>,
+) {
+    let Some(action) = rebinding.action else {
+        return;
+    };
+    if !rebinding.armed {
+        rebinding.armed = true;
+        return;
+    }
+    let layout = rebinding.layout;
+    if keyboard_input.just_pressed(KeyCode::Escape) && !input_map.get_in(layout, action).contains(&Binding::Key(KeyCode::Escape)) {
+        *rebinding = Rebinding::default();
+        return;
+    }
+
+    let mut binding = keyboard_input.get_just_pressed().next().map(|key| Binding::Key(*key));
+    if layout == Layout::Primary {
+        binding = binding
+            .or_else(|| mouse_input.get_just_pressed().next().map(|button| Binding::Mouse(*button)))
+            .or_else(|| gamepad_input.get_just_pressed().next().map(|button| Binding::Gamepad(button.button_type)));
+    }
+    let Some(binding) = binding else {
+        return;
+    };
+
+    let conflicts = input_map.conflicts(action, layout, binding);
+    if !conflicts.is_empty() {
+        rebinding.conflicts = conflicts;
+        return;
+    }
+    input_map.rebind(action, layout, binding);
+    if let Err(error) = input_map.save(INPUT_CONFIG_PATH) {
+        warn!("{}", error);
+    }
+    *rebinding = Rebinding::default();
+}
+
+/// System to update the texts of the bindings page when the bindings or the capture state change.
+fn update_binding_texts(
+    mut button_text_query: Query<(&RebindButton, &mut Text), Without<RebindStatusText>>,
+    mut status_query: Query<&mut Text, With<RebindStatusText>>,
+    input_map: Res<InputMap>,
+    rebinding: Res<Rebinding>,
+) {
+    if !input_map.is_changed() && !rebinding.is_changed() {
+        return;
+    }
+    for (button, mut text) in button_text_query.iter_mut() {
+        text.sections[0].value = if rebinding.action == Some(button.0) && rebinding.layout == button.1 {
+            format!("{}: ...", action_label(button.0, button.1))
+        } else {
+            binding_label(&input_map, button.0, button.1)
+        };
+    }
+    let status = match rebinding.action {
+        Some(action) if !rebinding.conflicts.is_empty() => {
+            let names: Vec<String> = rebinding.conflicts.iter().map(|(conflict, layout)| action_label(*conflict, *layout)).collect();
+            format!("Bereits belegt von {}. Andere Taste für {} drücken", names.join(", "), action_label(action, rebinding.layout))
+        },
+        Some(action) => format!("Taste für {} drücken, Escape zum Abbrechen", action_label(action, rebinding.layout)),
+        None => String::new(),
+    };
+    for mut text in status_query.iter_mut() {
+        text.sections[0].value = status.clone();
+    }
+}
+
+/// System to scroll the list of buttons on the bindings page with the mouse wheel,
+/// and to keep the button selected with the menu actions visible.
+fn scroll_bindings_list(
+    mut wheel_events: EventReader<MouseWheel>,
+    mut list_query: Query<(&mut ScrollingList, &mut Style, &Parent, &Node, &GlobalTransform)>,
+    container_query: Query<&Node>,
+    button_query: Query<(&MenuButtonIndex, &Node, &GlobalTransform), With<RebindButton>>,
+    selection: Res<MenuSelection>,
+) {
+    let scrolled: f32 = wheel_events.read()
+        .map(|event| match event.unit {
+            MouseScrollUnit::Line => event.y * SCROLL_LINE_HEIGHT,
+            MouseScrollUnit::Pixel => event.y,
+        })
+        .sum();
+    for (mut list, mut style, parent, list_node, list_transform) in list_query.iter_mut() {
+        let Ok(container) = container_query.get(parent.get()) else {
+            continue;
         };
+        let container_height = container.size().y;
+        let max_scroll = (list_node.size().y - container_height).max(0.0);
+        let mut position = list.position + scrolled;
+
+        if selection.is_changed() {
+            let list_top = list_transform.translation().y - list_node.size().y / 2.0;
+            let selected = button_query.iter().find(|(index, _, _)| index.0 == selection.0);
+            if let Some((_, node, transform)) = selected {
+                let top = transform.translation().y - node.size().y / 2.0 - list_top;
+                let bottom = top + node.size().y;
+                position = position.max(container_height - bottom).min(-top);
+            }
+        }
+
+        position = position.clamp(-max_scroll, 0.0);
+        if position != list.position {
+            list.position = position;
+            style.top = Val::Px(position);
+        }
     }
 }
 
+#[allow(clippy::type_complexity)]
 fn button_hower(
     mut interaction_query: Query<(&Interaction,&mut BackgroundColor),(Changed<Interaction>, With<Button>)>,
 ) {
@@ -253,61 +794,54 @@ fn button_hower(
 }
 
 fn fortsetzen_button(
-    mut interaction_query: Query<&Interaction,(Changed<Interaction>, With<FortsetzenButton>)>,
+    mut pressed_events: EventReader<MenuButtonPressed>,
+    button_query: Query<(), With<FortsetzenButton>>,
     mut commands: Commands,
 ) {
-    for interaction in interaction_query.iter_mut() {
-        match interaction {
-            Interaction::Pressed => {
-                commands.insert_resource(NextState(Some(MenuState::Closed)));
-            },
-            _ => {},
+    for pressed in pressed_events.read() {
+        if button_query.contains(pressed.0) {
+            commands.insert_resource(NextState(Some(MenuState::Closed)));
         }
     }
 }
 
+/// System to quit the game. The app is exited with an event, so a running replay recording is saved.
 fn quit_game_button(
-    mut interaction_query: Query<&Interaction,(Changed<Interaction>, With<QuitButton>)>,
+    mut pressed_events: EventReader<MenuButtonPressed>,
+    button_query: Query<(), (With<QuitButton>, With<Button>)>,
+    mut exit_events: EventWriter<AppExit>,
 ) {
-    for interaction in interaction_query.iter_mut() {
-        match interaction {
-            Interaction::Pressed => {
-                std::process::exit(0);
-            },
-            _ => {},
+    for pressed in pressed_events.read() {
+        if button_query.contains(pressed.0) {
+            exit_events.send(AppExit);
         }
     }
 }
 
 fn toggle_framerate_lock(
-    mut interaction_query: Query<(&Interaction,&mut Children),(Changed<Interaction>, With<FPSButton>)>,
+    mut pressed_events: EventReader<MenuButtonPressed>,
+    button_query: Query<&Children, (With<FPSButton>, With<Button>)>,
     mut framerate_resource: ResMut<FramepaceSettings>,
     mut text_query: Query<&mut Text, With<FPSButton>>,
 ) {
-    for (interaction, children) in interaction_query.iter_mut() {
-        match interaction {
-            Interaction::Pressed => {
-                match framerate_resource.limiter {
-                    bevy_framepace::Limiter::Auto => {
-                        framerate_resource.limiter = bevy_framepace::Limiter::Off;
-                        if let Some(text_entity) = children.get(0) {
-                            if let Ok(mut text) = text_query.get_mut(*text_entity) {
-                                text.sections[0].value = "Framerate: Unlocked".to_string();
-                            }
-                        }
-                    },
-                    _ => {
-                        framerate_resource.limiter = bevy_framepace::Limiter::Auto;
-                        if let Some(text_entity) = children.get(0) {
-                            if let Ok(mut text) = text_query.get_mut(*text_entity) {
-                                text.sections[0].value = "Framerate: Locked".to_string();
-                            }
-                        }
-                    },
-                }
+    for pressed in pressed_events.read() {
+        let Ok(children) = button_query.get(pressed.0) else {
+            continue;
+        };
+        let label = match framerate_resource.limiter {
+            bevy_framepace::Limiter::Auto => {
+                framerate_resource.limiter = bevy_framepace::Limiter::Off;
+                "Framerate: Unlocked"
             },
-            _ => {},
+            _ => {
+                framerate_resource.limiter = bevy_framepace::Limiter::Auto;
+                "Framerate: Locked"
+            },
+        };
+        if let Some(text_entity) = children.first() {
+            if let Ok(mut text) = text_query.get_mut(*text_entity) {
+                text.sections[0].value = label.to_string();
+            }
         }
     }
-    
 }
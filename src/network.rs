@@ -0,0 +1,925 @@
+use std::collections::VecDeque;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+
+pub mod link;
+use link::{Link, LinkConditions};
+
+use crate::collision::resolve_collisions;
+use crate::control::{ControlSource, LocalPlayers, ScriptedControl, TankControl};
+use crate::damage::{Health, Wreck};
+use crate::map::{enforce_bounds, SpawnPoints};
+use crate::physics::{apply_force, apply_slope_force, follow_terrain, Force, Grounded, Position, Rotation, SimulationTick, Velocity};
+use crate::prop::{apply_rubble_drag, DestroyedProps};
+use crate::schedule::ScheduleSet;
+use crate::tank::{apply_tank_control, player_spawn_transform, slowdown_tank, spawn_tank, Player, Tank, TankKind, TankType, Team, PLAYER_TANK};
+
+/// Default UDP port of the server.
+pub const DEFAULT_PORT: u16 = 7777;
+
+/// Physics ticks between two snapshots of the server.
+const SNAPSHOT_INTERVAL: u32 = 2;
+
+/// Number of clients that can join a server.
+const MAX_CLIENTS: usize = 8;
+
+/// Number of the latest inputs a client repeats in every message, so a lost datagram doesn't lose inputs.
+const REDUNDANT_INPUTS: usize = 16;
+
+/// Inputs the server buffers per client before it drops the oldest to catch up.
+const MAX_BUFFERED_INPUTS: usize = 8;
+
+/// Predicted states a client keeps for reconciliation, in physics ticks.
+const MAX_PREDICTION_HISTORY: usize = 128;
+
+/// How far in the past remote tanks are shown on a client, in physics ticks.
+/// Covers a few snapshot intervals so there is usually a later snapshot to interpolate to.
+const INTERPOLATION_DELAY: f32 = 6.0;
+
+/// Ticks the interpolation clock may drift from the snapshots before it is reset.
+const MAX_CLOCK_DRIFT: f32 = 16.0;
+
+/// Part of the drift of the interpolation clock corrected per physics tick.
+const CLOCK_CORRECTION: f32 = 0.05;
+
+/// Snapshots kept per remote tank.
+const SNAPSHOT_BUFFER_SIZE: usize = 32;
+
+/// Position error of the predicted tank below which the state of the server is ignored.
+const RECONCILE_TOLERANCE: f32 = 0.05;
+
+/// Rotation error of the predicted tank in radians below which the state of the server is ignored.
+const RECONCILE_ANGLE_TOLERANCE: f32 = 0.01;
+
+/// Time without messages after which the other side is considered gone.
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Time between the connection requests of a client.
+const HELLO_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Resource to store the role of the game in a network match.
+#[derive(Resource, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkMode {
+    /// Single-player without network.
+    Offline,
+    /// Authoritative server listening on `port`. It simulates the match with the controls of the clients and sends them snapshots.
+    Server { port: u16 },
+    /// Client of the server at `server`. It predicts the own tank and interpolates the others between the snapshots.
+    Client { server: SocketAddr },
+}
+
+/// Run condition for the systems that set up the match, which is done by the server or the offline game.
+/// Clients get all tanks from the snapshots of the server.
+pub fn is_authority(mode: Option<Res<NetworkMode>>) -> bool {
+    !matches!(mode.as_deref(), Some(NetworkMode::Client { .. }))
+}
+
+/// Schedule with the systems that move a tank in a physics tick.
+/// Clients run it to simulate the inputs the server has not applied yet again after correcting their tank.
+/// Only the own tank of a client has a `Force` and is `Grounded`, so it is the only tank driven by it.
+#[derive(ScheduleLabel, Debug, Clone, PartialEq, Eq, Hash)]
+struct PredictionStep;
+
+/// Component to identify a tank on a client with the id it has in the snapshots of the server.
+/// The server uses the bits of the entity of the tank as id.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct NetworkId(pub u64);
+
+/// Component to store the latest snapshots of a remote tank on a client, with the tick of the server they were taken in.
+#[derive(Component, Debug, Default)]
+struct SnapshotBuffer(VecDeque<(u32, TankState)>);
+
+/// The state of a tank in a snapshot.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TankState {
+    id: u64,
+    kind: TankKind,
+    team: u8,
+    position: Vec3,
+    rotation: Quat,
+    velocity: Vec3,
+    force: Vec3,
+    control: TankControl,
+    health: f32,
+    wreck: bool,
+}
+
+/// Messages from a client to the server.
+#[derive(Debug, Serialize, Deserialize)]
+enum ClientMessage {
+    /// Request to join the match, repeated until the server answers.
+    Hello,
+    /// The latest controls of the tank of the client that the server has not acknowledged yet,
+    /// numbered with increasing sequence numbers.
+    Input { inputs: Vec<(u32, TankControl)> },
+}
+
+/// Messages from the server to a client.
+#[derive(Debug, Serialize, Deserialize)]
+enum ServerMessage {
+    /// Answer to `Hello` with the id of the tank of the client.
+    Welcome { tank: u64 },
+    /// Answer to `Hello` when `MAX_CLIENTS` clients have joined already.
+    Full,
+    /// The state of all tanks and the destroyed props after the physics tick `tick`.
+    /// `last_input` is the sequence number of the last input of the receiving client applied before the tick, in the tick
+    /// `input_tick`. The ticks after `input_tick` repeated its controls because no newer input had arrived.
+    Snapshot { tick: u32, last_input: u32, input_tick: u32, tanks: Vec<TankState>, destroyed_props: DestroyedProps },
+}
+
+/// Encode a message for sending.
+fn encode<T: Serialize>(message: &T) -> Vec<u8> {
+    bincode::serialize(message).expect("network messages can always be encoded")
+}
+
+/// A client connected to the server.
+struct RemoteClient {
+    tank: Entity,
+    inputs: VecDeque<(u32, TankControl)>,
+    last_input: u32,
+    /// Tick in which `last_input` was applied.
+    last_input_tick: u32,
+    last_heard: Instant,
+}
+
+/// Resource to store the clients connected to the server.
+#[derive(Resource, Default)]
+struct ServerState {
+    clients: HashMap<SocketAddr, RemoteClient>,
+}
+
+/// The state of the own tank a client predicted after applying the input `control`.
+struct PredictedState {
+    sequence: u32,
+    control: TankControl,
+    position: Vec3,
+    rotation: Quat,
+    velocity: Vec3,
+    force: Vec3,
+}
+
+/// Resource to store the connection of a client to the server.
+#[derive(Resource)]
+struct ClientState {
+    server: SocketAddr,
+    /// Id of the own tank, known once the server answered.
+    tank: Option<u64>,
+    /// Sequence number of the latest input.
+    sequence: u32,
+    /// Inputs not acknowledged by the server yet.
+    inputs: VecDeque<(u32, TankControl)>,
+    history: VecDeque<PredictedState>,
+    /// The latest state of the own tank on the server with the sequence number of the last input applied to it,
+    /// to correct the prediction with.
+    correction: Option<(u32, TankState)>,
+    /// Tick of the server the remote tanks are shown at.
+    clock: Option<f32>,
+    latest_tick: Option<u32>,
+    last_heard: Option<Instant>,
+    last_hello: Option<Instant>,
+    entities: HashMap<u64, Entity>,
+}
+
+impl ClientState {
+    fn new(server: SocketAddr) -> Self {
+        ClientState {
+            server,
+            tank: None,
+            sequence: 0,
+            inputs: VecDeque::new(),
+            history: VecDeque::new(),
+            correction: None,
+            clock: None,
+            latest_tick: None,
+            last_heard: None,
+            last_hello: None,
+            entities: HashMap::new(),
+        }
+    }
+}
+
+/// Plugin for multiplayer over UDP.
+/// The server is authoritative: clients send the controls of their tank, the server applies them in its simulation
+/// and sends snapshots of the position, rotation and velocity of all tanks back. Only the server applies damage, clients get the
+/// health and wrecks of the tanks and the destroyed props from the snapshots. A client predicts its own tank by simulating
+/// its controls right away. When a snapshot differs from the prediction, the tank is moved back to the state of the server
+/// and the inputs the server has not applied yet are simulated again. The other tanks are interpolated between the snapshots.
+/// Server and clients have to play on the same map. `conditions` simulate latency and packet loss for testing on localhost.
+pub struct NetworkPlugin {
+    pub mode: NetworkMode,
+    pub conditions: LinkConditions,
+}
+
+impl Default for NetworkPlugin {
+    fn default() -> Self {
+        NetworkPlugin {
+            mode: NetworkMode::Offline,
+            conditions: LinkConditions::default(),
+        }
+    }
+}
+
+impl Plugin for NetworkPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.mode);
+        let address = match self.mode {
+            NetworkMode::Offline => return,
+            NetworkMode::Server { port } => SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)),
+            NetworkMode::Client { .. } => SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)),
+        };
+        match Link::bind(address, self.conditions) {
+            Ok(link) => {
+                app.insert_resource(link);
+            },
+            Err(error) => error!("Failed to open UDP socket on {}: {}", address, error),
+        }
+        app.add_systems(Update, (
+            flush_link.run_if(resource_exists::<Link>()),
+        ));
+        match self.mode {
+            NetworkMode::Server { .. } => {
+                app.init_resource::<ServerState>()
+                    .add_systems(FixedUpdate, (
+                        receive_client_messages.before(ScheduleSet::Control),
+                        apply_client_inputs.before(apply_tank_control).in_set(ScheduleSet::Control),
+                        send_snapshots.after(ScheduleSet::Physics),
+                    ).run_if(resource_exists::<Link>()));
+            },
+            NetworkMode::Client { server } => {
+                app.insert_resource(ClientState::new(server))
+                    .add_systems(PredictionStep, (
+                        (apply_tank_control, slowdown_tank),
+                        apply_rubble_drag,
+                        apply_slope_force,
+                        apply_force,
+                        follow_terrain,
+                        resolve_collisions,
+                        enforce_bounds,
+                    ).chain())
+                    .add_systems(Update, (
+                        send_hello.run_if(resource_exists::<Link>()),
+                    ))
+                    .add_systems(FixedUpdate, (
+                        (receive_server_messages, reconcile).chain().before(ScheduleSet::Control),
+                        send_client_input.after(apply_tank_control).in_set(ScheduleSet::Control),
+                        (
+                            record_prediction,
+                            interpolate_remote_tanks,
+                        ).after(ScheduleSet::Physics),
+                    ).run_if(resource_exists::<Link>()));
+            },
+            NetworkMode::Offline => {},
+        }
+    }
+}
+
+/// System to send the datagrams whose simulated delay is over.
+fn flush_link(
+    mut link: ResMut<Link>,
+) {
+    link.flush();
+}
+
+/// System to handle the messages of the clients on the server.
+/// A new client gets a `PLAYER_TANK` at the spawn points of team 0 unless `MAX_CLIENTS` have joined already,
+/// the inputs of known clients are buffered until they are applied.
+/// Clients that were not heard from for a while are dropped together with their tank.
+fn receive_client_messages(
+    mut commands: Commands,
+    mut link: ResMut<Link>,
+    mut server: ResMut<ServerState>,
+    asset_server: Res<AssetServer>,
+    spawn_points: Option<Res<SpawnPoints>>,
+    local_players: Res<LocalPlayers>,
+) {
+    let now = Instant::now();
+    for (address, bytes) in link.receive() {
+        let Ok(message) = bincode::deserialize::<ClientMessage>(&bytes) else {
+            warn!("Invalid message from {}", address);
+            continue;
+        };
+        match message {
+            ClientMessage::Hello => {
+                if !server.clients.contains_key(&address) {
+                    if server.clients.len() >= MAX_CLIENTS {
+                        debug!("Rejected client {}, the server is full", address);
+                        link.send(address, encode(&ServerMessage::Full));
+                        continue;
+                    }
+                    // The client asks again once the map is loaded.
+                    let Some(spawn_points) = spawn_points.as_deref() else {
+                        continue;
+                    };
+                    let (position, rotation) = player_spawn_transform(spawn_points, local_players.sources.len() + server.clients.len());
+                    let tank = spawn_tank(&mut commands, asset_server.load(PLAYER_TANK), Team(0), position, rotation);
+                    commands.entity(tank).insert(ControlSource::Network);
+                    info!("Client {} joined", address);
+                    server.clients.insert(address, RemoteClient {
+                        tank,
+                        inputs: VecDeque::new(),
+                        last_input: 0,
+                        last_input_tick: 0,
+                        last_heard: now,
+                    });
+                }
+                let Some(client) = server.clients.get_mut(&address) else {
+                    continue;
+                };
+                client.last_heard = now;
+                let tank = client.tank.to_bits();
+                link.send(address, encode(&ServerMessage::Welcome { tank }));
+            },
+            ClientMessage::Input { inputs } => {
+                let Some(client) = server.clients.get_mut(&address) else {
+                    continue;
+                };
+                client.last_heard = now;
+                for (sequence, control) in inputs {
+                    let newest = client.inputs.back().map_or(client.last_input, |(newest, _)| *newest);
+                    if sequence > newest {
+                        client.inputs.push_back((sequence, control));
+                    }
+                }
+                while client.inputs.len() > MAX_BUFFERED_INPUTS {
+                    client.inputs.pop_front();
+                }
+            },
+        }
+    }
+
+    server.clients.retain(|address, client| {
+        let connected = now.duration_since(client.last_heard) < CONNECTION_TIMEOUT;
+        if !connected {
+            info!("Client {} timed out", address);
+            if let Some(tank) = commands.get_entity(client.tank) {
+                tank.despawn_recursive();
+            }
+        }
+        connected
+    });
+}
+
+/// System to apply the next buffered input of every client to its tank, one input per physics tick.
+/// Without a new input the tank keeps the last controls.
+fn apply_client_inputs(
+    mut server: ResMut<ServerState>,
+    mut query: Query<&mut TankControl>,
+    tick: Res<SimulationTick>,
+) {
+    for client in server.clients.values_mut() {
+        let Ok(mut control) = query.get_mut(client.tank) else {
+            continue;
+        };
+        if let Some((sequence, input)) = client.inputs.pop_front() {
+            *control = input;
+            client.last_input = sequence;
+            client.last_input_tick = tick.0;
+        }
+    }
+}
+
+/// System to send the state of all tanks to the clients every `SNAPSHOT_INTERVAL` physics ticks.
+/// Tanks whose definition was not loaded from a file have no `TankKind` and are left out.
+#[allow(clippy::type_complexity)]
+fn send_snapshots(
+    mut link: ResMut<Link>,
+    server: Res<ServerState>,
+    tick: Res<SimulationTick>,
+    asset_server: Res<AssetServer>,
+    destroyed_props: Res<DestroyedProps>,
+    query: Query<(Entity, &TankType, &Team, &Position, &Rotation, &Velocity, &Force, &TankControl, Option<&Health>, Has<Wreck>), With<Tank>>,
+) {
+    if server.clients.is_empty() || tick.0 % SNAPSHOT_INTERVAL != 0 {
+        return;
+    }
+    let tanks: Vec<TankState> = query.iter()
+        .filter_map(|(entity, tank_type, team, position, rotation, velocity, force, control, health, wreck)| Some(TankState {
+            id: entity.to_bits(),
+            kind: TankKind::of(&tank_type.0, &asset_server)?,
+            team: team.0,
+            position: position.0,
+            rotation: rotation.0,
+            velocity: velocity.0,
+            force: force.0,
+            control: *control,
+            health: health.map_or(0.0, |health| health.current),
+            wreck,
+        }))
+        .collect();
+    for (address, client) in server.clients.iter() {
+        link.send(*address, encode(&ServerMessage::Snapshot {
+            tick: tick.0,
+            last_input: client.last_input,
+            input_tick: client.last_input_tick,
+            tanks: tanks.clone(),
+            destroyed_props: destroyed_props.clone(),
+        }));
+    }
+}
+
+/// System to ask the server to join until it answers, and again after the connection was lost.
+fn send_hello(
+    mut link: ResMut<Link>,
+    mut client: ResMut<ClientState>,
+) {
+    let now = Instant::now();
+    let connected = client.last_heard.is_some_and(|last_heard| now.duration_since(last_heard) < CONNECTION_TIMEOUT);
+    if client.tank.is_some() && connected {
+        return;
+    }
+    if client.last_hello.is_some_and(|last_hello| now.duration_since(last_hello) < HELLO_INTERVAL) {
+        return;
+    }
+    if client.tank.is_some() {
+        warn!("Lost connection to server {}, reconnecting", client.server);
+        client.tank = None;
+    }
+    client.last_hello = Some(now);
+    let server = client.server;
+    link.send(server, encode(&ClientMessage::Hello));
+}
+
+/// System to handle the messages of the server on a client.
+/// Tanks that are new in a snapshot are spawned, tanks missing from it are removed. Health, wrecks and destroyed props
+/// are taken over from the server. The state of the own tank on the server is kept for `reconcile`,
+/// the states of the other tanks are buffered for interpolation.
+#[allow(clippy::type_complexity)]
+fn receive_server_messages(
+    mut commands: Commands,
+    mut link: ResMut<Link>,
+    mut client: ResMut<ClientState>,
+    asset_server: Res<AssetServer>,
+    local_players: Res<LocalPlayers>,
+    mut client_destroyed_props: ResMut<DestroyedProps>,
+    mut tank_query: Query<(&mut TankControl, Option<&mut Health>, Option<&mut SnapshotBuffer>, Has<Wreck>), With<NetworkId>>,
+) {
+    for (address, bytes) in link.receive() {
+        if address != client.server {
+            continue;
+        }
+        let Ok(message) = bincode::deserialize::<ServerMessage>(&bytes) else {
+            warn!("Invalid message from {}", address);
+            continue;
+        };
+        client.last_heard = Some(Instant::now());
+        match message {
+            ServerMessage::Welcome { tank } => {
+                if client.tank != Some(tank) {
+                    info!("Joined server {}", address);
+                    client.tank = Some(tank);
+                    client.inputs.clear();
+                    client.history.clear();
+                    client.correction = None;
+                }
+            },
+            ServerMessage::Full => {
+                if client.tank.is_none() {
+                    warn!("Server {} is full", address);
+                }
+            },
+            ServerMessage::Snapshot { tick, last_input, input_tick, tanks, destroyed_props } => {
+                // Snapshots that arrive out of order are older than what is known already.
+                if client.latest_tick.is_some_and(|latest_tick| tick <= latest_tick) {
+                    continue;
+                }
+                client.latest_tick = Some(tick);
+                client.inputs.retain(|(sequence, _)| *sequence > last_input);
+                client_destroyed_props.set_if_neq(destroyed_props);
+
+                let ids: HashSet<u64> = tanks.iter().map(|state| state.id).collect();
+                for state in tanks {
+                    let own = client.tank == Some(state.id);
+                    let Some(&entity) = client.entities.get(&state.id) else {
+                        let tank = spawn_tank(&mut commands, state.kind.definition(&asset_server), Team(state.team), state.position, state.rotation);
+                        let mut tank_commands = commands.entity(tank);
+                        tank_commands.insert((NetworkId(state.id), Velocity(state.velocity)));
+                        if state.wreck {
+                            tank_commands.insert(Wreck);
+                        }
+                        if own && local_players.demo {
+                            tank_commands.insert((Player(0), ControlSource::Scripted, ScriptedControl::demo()));
+                        } else if own {
+                            tank_commands.insert((Player(0), local_players.source(0)));
+                        } else {
+                            // Remote tanks are moved by the snapshots, not by the physics.
+                            tank_commands.insert((ControlSource::Network, state.control))
+                                .remove::<(Force, Grounded)>();
+                            tank_commands.insert(SnapshotBuffer(VecDeque::from([(tick, state.clone())])));
+                        }
+                        client.entities.insert(state.id, tank);
+                        continue;
+                    };
+                    let Ok((mut control, health, buffer, wreck)) = tank_query.get_mut(entity) else {
+                        continue;
+                    };
+                    if let Some(mut health) = health {
+                        health.current = state.health;
+                    }
+                    match (state.wreck, wreck) {
+                        (true, false) => {
+                            commands.entity(entity).insert(Wreck);
+                        },
+                        (false, true) => {
+                            commands.entity(entity).remove::<Wreck>();
+                        },
+                        _ => {},
+                    }
+                    match buffer {
+                        Some(mut buffer) => {
+                            *control = state.control;
+                            buffer.0.push_back((tick, state));
+                            while buffer.0.len() > SNAPSHOT_BUFFER_SIZE {
+                                buffer.0.pop_front();
+                            }
+                        },
+                        // A state in which the server repeated the last input for lack of a newer one can not be compared,
+                        // the client predicted those ticks with its newer inputs.
+                        None if own && tick == input_tick => client.correction = Some((last_input, state)),
+                        None => {},
+                    }
+                }
+
+                client.entities.retain(|id, entity| {
+                    let exists = ids.contains(id);
+                    if !exists {
+                        if let Some(tank) = commands.get_entity(*entity) {
+                            tank.despawn_recursive();
+                        }
+                    }
+                    exists
+                });
+            },
+        }
+    }
+}
+
+/// System to correct the predicted state of the own tank with its state on the server.
+/// If the state the client predicted after the same input differs, the tank is moved back to the state of the server
+/// and the later inputs are simulated again with the `PredictionStep`, which also updates their predicted states.
+fn reconcile(world: &mut World) {
+    world.resource_scope(|world, mut client: Mut<ClientState>| {
+        let Some((last_input, state)) = client.correction.take() else {
+            return;
+        };
+        let Some(entity) = client.tank.and_then(|tank| client.entities.get(&tank)).copied() else {
+            return;
+        };
+        while client.history.front().is_some_and(|predicted| predicted.sequence < last_input) {
+            client.history.pop_front();
+        }
+        if let Some(predicted) = client.history.front() {
+            if predicted.sequence != last_input {
+                return;
+            }
+            let position_error = predicted.position.distance(state.position);
+            if position_error < RECONCILE_TOLERANCE && predicted.rotation.angle_between(state.rotation) < RECONCILE_ANGLE_TOLERANCE {
+                return;
+            }
+        }
+
+        set_tank_state(world, entity, state.position, state.rotation, state.velocity, state.force);
+        let Some(latest) = client.history.back().map(|predicted| predicted.control) else {
+            // Nothing was predicted yet.
+            return;
+        };
+        let corrected = &mut client.history[0];
+        corrected.position = state.position;
+        corrected.rotation = state.rotation;
+        corrected.velocity = state.velocity;
+        corrected.force = state.force;
+        for predicted in client.history.iter_mut().skip(1) {
+            if let Some(mut control) = world.get_mut::<TankControl>(entity) {
+                *control = predicted.control;
+            }
+            world.run_schedule(PredictionStep);
+            let Some((position, rotation, velocity, force)) = tank_state(world, entity) else {
+                return;
+            };
+            predicted.position = position;
+            predicted.rotation = rotation;
+            predicted.velocity = velocity;
+            predicted.force = force;
+        }
+        if let Some(mut control) = world.get_mut::<TankControl>(entity) {
+            *control = latest;
+        }
+    });
+}
+
+/// The position, rotation, velocity and force of a tank.
+fn tank_state(world: &World, entity: Entity) -> Option<(Vec3, Quat, Vec3, Vec3)> {
+    let entity = world.get_entity(entity)?;
+    Some((
+        entity.get::<Position>()?.0,
+        entity.get::<Rotation>()?.0,
+        entity.get::<Velocity>()?.0,
+        entity.get::<Force>()?.0,
+    ))
+}
+
+/// Set the position, rotation, velocity and force of a tank.
+fn set_tank_state(world: &mut World, entity: Entity, position: Vec3, rotation: Quat, velocity: Vec3, force: Vec3) {
+    let Some(mut entity) = world.get_entity_mut(entity) else {
+        return;
+    };
+    if let Some(mut component) = entity.get_mut::<Position>() {
+        component.0 = position;
+    }
+    if let Some(mut component) = entity.get_mut::<Rotation>() {
+        component.0 = rotation;
+    }
+    if let Some(mut component) = entity.get_mut::<Velocity>() {
+        component.0 = velocity;
+    }
+    if let Some(mut component) = entity.get_mut::<Force>() {
+        component.0 = force;
+    }
+}
+
+/// System to send the controls of the own tank to the server, together with the inputs it has not acknowledged yet.
+fn send_client_input(
+    mut link: ResMut<Link>,
+    mut client: ResMut<ClientState>,
+    query: Query<(&NetworkId, &TankControl), With<Player>>,
+) {
+    let Some(tank) = client.tank else {
+        return;
+    };
+    let Some((_, control)) = query.iter().find(|(id, _)| id.0 == tank) else {
+        return;
+    };
+    client.sequence += 1;
+    let sequence = client.sequence;
+    client.inputs.push_back((sequence, *control));
+    while client.inputs.len() > REDUNDANT_INPUTS {
+        client.inputs.pop_front();
+    }
+    let message = ClientMessage::Input {
+        inputs: client.inputs.iter().copied().collect(),
+    };
+    let server = client.server;
+    link.send(server, encode(&message));
+}
+
+/// System to remember the predicted state of the own tank after the latest input, for reconciliation.
+#[allow(clippy::type_complexity)]
+fn record_prediction(
+    mut client: ResMut<ClientState>,
+    query: Query<(&NetworkId, &TankControl, &Position, &Rotation, &Velocity, &Force), With<Player>>,
+) {
+    let Some(tank) = client.tank else {
+        return;
+    };
+    let sequence = client.sequence;
+    // The physics ran without a new input while the menu was open.
+    if sequence == 0 || client.history.back().is_some_and(|predicted| predicted.sequence == sequence) {
+        return;
+    }
+    let Some((_, control, position, rotation, velocity, force)) = query.iter().find(|(id, ..)| id.0 == tank) else {
+        return;
+    };
+    client.history.push_back(PredictedState {
+        sequence,
+        control: *control,
+        position: position.0,
+        rotation: rotation.0,
+        velocity: velocity.0,
+        force: force.0,
+    });
+    while client.history.len() > MAX_PREDICTION_HISTORY {
+        client.history.pop_front();
+    }
+}
+
+/// System to move the remote tanks to their interpolated state `INTERPOLATION_DELAY` ticks behind the latest snapshot.
+/// The interpolation clock advances one tick per physics tick and is slowly pulled towards the snapshots,
+/// so jitter of the arrival times doesn't make the tanks stutter.
+fn interpolate_remote_tanks(
+    mut client: ResMut<ClientState>,
+    mut query: Query<(&mut SnapshotBuffer, &mut Position, &mut Rotation, &mut Velocity)>,
+) {
+    let Some(latest_tick) = client.latest_tick else {
+        return;
+    };
+    let target = latest_tick as f32 - INTERPOLATION_DELAY;
+    let clock = match client.clock {
+        Some(clock) if (clock + 1.0 - target).abs() < MAX_CLOCK_DRIFT => clock + 1.0 + (target - clock - 1.0) * CLOCK_CORRECTION,
+        _ => target,
+    };
+    client.clock = Some(clock);
+
+    for (mut buffer, mut position, mut rotation, mut velocity) in query.iter_mut() {
+        while buffer.0.len() > 1 && buffer.0[1].0 as f32 <= clock {
+            buffer.0.pop_front();
+        }
+        let Some((from_tick, from)) = buffer.0.iter().rev().find(|(tick, _)| *tick as f32 <= clock).or(buffer.0.front()) else {
+            continue;
+        };
+        let (to_tick, to) = buffer.0.iter()
+            .find(|(tick, _)| *tick as f32 > clock)
+            .map_or((*from_tick, from), |(tick, state)| (*tick, state));
+        let t = if to_tick > *from_tick {
+            ((clock - *from_tick as f32) / (to_tick - *from_tick) as f32).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        position.0 = from.position.lerp(to.position, t);
+        rotation.0 = from.rotation.slerp(to.rotation, t);
+        velocity.0 = from.velocity.lerp(to.velocity, t);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::input::InputPlugin;
+    use bevy::render::texture::ImagePlugin;
+    use bevy::time::TimeUpdateStrategy;
+
+    use super::*;
+    use crate::ai::AiPlugin;
+    use crate::asset_loader::AssetLoaderPlugin;
+    use crate::collision::CollisionPlugin;
+    use crate::control::ControlPlugin;
+    use crate::damage::DamagePlugin;
+    use crate::input_map::InputMapPlugin;
+    use crate::map::{MapPlugin, MapSource};
+    use crate::map::generator::MapGeneratorSettings;
+    use crate::physics::PhysicsPlugin;
+    use crate::projectile::ProjectilePlugin;
+    use crate::prop::PropPlugin;
+    use crate::replay::ReplayPlugin;
+    use crate::schedule::SchedulePlugin;
+    use crate::tank::TankPlugin;
+    use crate::turret::TurretPlugin;
+
+    /// UDP port of the server in the test.
+    const TEST_PORT: u16 = 47_777;
+
+    /// Updates of the server and the client while the client drives, after it joined.
+    const DRIVING_UPDATES: u32 = 384;
+
+    /// Updates of the server and the client after the client stopped.
+    const IDLE_UPDATES: u32 = 192;
+
+    /// Updates the test waits for the assets to load and the client to join.
+    const MAX_UPDATES: u32 = 2_000;
+
+    /// Headless app of a match on a small generated map, with `local_players` driving the demo sequence.
+    /// Every update advances the game time by exactly one physics tick.
+    fn app(network: NetworkPlugin, local_players: usize) -> App {
+        let physics = PhysicsPlugin::default();
+        let tick = Duration::from_secs_f64(1.0 / physics.tick_rate);
+        let mut app = App::new();
+        app.add_plugins((
+                MinimalPlugins,
+                TransformPlugin,
+                HierarchyPlugin,
+                InputPlugin,
+                AssetPlugin::default(),
+                ImagePlugin::default(),
+                AssetLoaderPlugin {
+                    render_assets: false,
+                },
+                InputMapPlugin,
+                MapPlugin {
+                    source: MapSource::Generated(MapGeneratorSettings {
+                        seed: 3,
+                        size: 800.0,
+                        ..Default::default()
+                    }),
+                },
+                SchedulePlugin,
+            ))
+            .add_plugins((
+                physics,
+                CollisionPlugin,
+                TankPlugin,
+                ControlPlugin {
+                    local_players,
+                    demo: true,
+                },
+                TurretPlugin,
+                ProjectilePlugin,
+                DamagePlugin,
+                AiPlugin::default(),
+                PropPlugin,
+                network,
+                ReplayPlugin::default(),
+            ))
+            .init_asset::<Mesh>()
+            .init_asset::<StandardMaterial>()
+            .init_asset::<Scene>()
+            .insert_resource(TimeUpdateStrategy::ManualDuration(tick));
+        app
+    }
+
+    /// The positions of all tanks on the server by their network id.
+    fn server_positions(server: &mut App) -> HashMap<u64, Vec3> {
+        server.world.query_filtered::<(Entity, &Position), With<Tank>>().iter(&server.world)
+            .map(|(entity, position)| (entity.to_bits(), position.0))
+            .collect()
+    }
+
+    /// Update the server and the client once, at about the speed of the physics so the simulated latency is realistic.
+    fn update(server: &mut App, client: &mut App) {
+        server.update();
+        client.update();
+        std::thread::sleep(Duration::from_secs_f64(1.0 / PhysicsPlugin::default().tick_rate));
+    }
+
+    #[test]
+    fn client_predicts_its_tank_and_interpolates_the_others() {
+        let conditions = LinkConditions {
+            latency: Duration::from_millis(40),
+            jitter: Duration::from_millis(10),
+            loss: 0.05,
+        };
+        let mut server = app(NetworkPlugin {
+            mode: NetworkMode::Server { port: TEST_PORT },
+            conditions,
+        }, 0);
+        let mut client = app(NetworkPlugin {
+            mode: NetworkMode::Client { server: SocketAddr::from((Ipv4Addr::LOCALHOST, TEST_PORT)) },
+            conditions,
+        }, 1);
+
+        let mut own_tank = None;
+        for _ in 0..MAX_UPDATES {
+            update(&mut server, &mut client);
+            own_tank = client.world.resource::<ClientState>().tank
+                .and_then(|tank| client.world.resource::<ClientState>().entities.get(&tank).copied());
+            if own_tank.is_some() {
+                break;
+            }
+        }
+        let own_tank = own_tank.expect("the client did not join");
+        let own_id = client.world.get::<NetworkId>(own_tank).unwrap().0;
+        let server_tank = Entity::from_bits(own_id);
+
+        // The states of the own tank after every input, as predicted by the client and on the server.
+        let mut predicted: HashMap<u32, Vec3> = HashMap::new();
+        let mut applied: HashMap<u32, Vec3> = HashMap::new();
+        // The positions of all tanks on the server in every tick, to compare the interpolated tanks with.
+        let mut history: HashMap<u32, HashMap<u64, Vec3>> = HashMap::new();
+        let mut interpolation_errors = Vec::new();
+        for _ in 0..DRIVING_UPDATES {
+            update(&mut server, &mut client);
+
+            let tick = server.world.resource::<SimulationTick>().0;
+            history.insert(tick, server_positions(&mut server));
+            if let Some(remote) = server.world.resource::<ServerState>().clients.values().next() {
+                if remote.last_input_tick == tick {
+                    applied.insert(remote.last_input, server.world.get::<Position>(server_tank).unwrap().0);
+                }
+            }
+            let state = client.world.resource::<ClientState>();
+            if let Some(latest) = state.history.back() {
+                predicted.entry(latest.sequence).or_insert(latest.position);
+            }
+
+            let Some(clock) = client.world.resource::<ClientState>().clock else {
+                continue;
+            };
+            let (Some(from), Some(to)) = (history.get(&(clock.floor() as u32)), history.get(&(clock.ceil() as u32))) else {
+                continue;
+            };
+            let from = from.clone();
+            let to = to.clone();
+            let mut remote_query = client.world.query_filtered::<(&NetworkId, &Position), With<SnapshotBuffer>>();
+            for (id, position) in remote_query.iter(&client.world) {
+                if let (Some(from), Some(to)) = (from.get(&id.0), to.get(&id.0)) {
+                    interpolation_errors.push(from.lerp(*to, clock.fract()).distance(position.0));
+                }
+            }
+        }
+
+        // The prediction matches the server, apart from ticks the server repeated an input when one was late.
+        let mut prediction_errors: Vec<f32> = applied.iter()
+            .filter_map(|(sequence, position)| Some(predicted.get(sequence)?.distance(*position)))
+            .collect();
+        prediction_errors.sort_by(f32::total_cmp);
+        assert!(prediction_errors.len() > DRIVING_UPDATES as usize / 2);
+        let median = prediction_errors[prediction_errors.len() / 2];
+        assert!(median < 0.05, "median prediction error {}", median);
+
+        // The other tanks follow the server with a delay.
+        assert!(!interpolation_errors.is_empty());
+        let max = interpolation_errors.iter().copied().fold(0.0, f32::max);
+        assert!(max < 0.5, "interpolation error {}", max);
+
+        // A push the client can not predict is corrected, and the client ends up where the server has its tank.
+        server.world.get_mut::<Position>(server_tank).unwrap().0.x += 3.0;
+        *client.world.get_mut::<ScriptedControl>(own_tank).unwrap() = ScriptedControl::new(Vec::new(), false);
+        for _ in 0..IDLE_UPDATES {
+            update(&mut server, &mut client);
+        }
+        let server_position = server.world.get::<Position>(server_tank).unwrap().0;
+        let client_position = client.world.get::<Position>(own_tank).unwrap().0;
+        assert!(server_position.distance(client_position) < RECONCILE_TOLERANCE, "{} on the server, {} on the client", server_position, client_position);
+    }
+}
@@ -0,0 +1,113 @@
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use bevy::prelude::*;
+
+/// Largest datagram that is received, larger datagrams are cut off and fail to decode.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Simulated network conditions applied to the outgoing datagrams of a `Link`.
+/// Every datagram is dropped with the probability `loss` and otherwise delayed by `latency`
+/// plus a random part of up to `jitter`, so datagrams can arrive out of order like on a real connection.
+/// The conditions are applied on both ends, the round trip time is twice the latency.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkConditions {
+    pub latency: Duration,
+    pub jitter: Duration,
+    pub loss: f32,
+}
+
+/// A datagram held back until its simulated delay is over.
+struct DelayedDatagram {
+    send_at: Instant,
+    address: SocketAddr,
+    bytes: Vec<u8>,
+}
+
+/// Resource for the UDP socket of the game, with simulated latency and packet loss for testing on localhost.
+#[derive(Resource)]
+pub struct Link {
+    socket: UdpSocket,
+    conditions: LinkConditions,
+    delayed: Vec<DelayedDatagram>,
+    rng: u32,
+}
+
+impl Link {
+    /// Open a non-blocking socket on `address`.
+    pub fn bind(address: SocketAddr, conditions: LinkConditions) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Link {
+            socket,
+            conditions,
+            delayed: Vec::new(),
+            rng: 0x2545F491,
+        })
+    }
+
+    /// Random number in the range `0.0..1.0`.
+    /// Uses a xorshift generator, the simulated conditions don't need to be reproducible.
+    fn random(&mut self) -> f32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng as f32 / u32::MAX as f32
+    }
+
+    /// Send a datagram, or queue it until its simulated delay is over.
+    pub fn send(&mut self, address: SocketAddr, bytes: Vec<u8>) {
+        if self.conditions.loss > 0.0 && self.random() < self.conditions.loss {
+            return;
+        }
+        let delay = self.conditions.latency + self.conditions.jitter.mul_f32(self.random());
+        if delay.is_zero() {
+            self.send_now(address, &bytes);
+        } else {
+            self.delayed.push(DelayedDatagram {
+                send_at: Instant::now() + delay,
+                address,
+                bytes,
+            });
+        }
+    }
+
+    /// Send the queued datagrams whose delay is over.
+    pub fn flush(&mut self) {
+        let now = Instant::now();
+        let (due, delayed): (Vec<_>, Vec<_>) = self.delayed.drain(..).partition(|datagram| datagram.send_at <= now);
+        self.delayed = delayed;
+        for datagram in due {
+            self.send_now(datagram.address, &datagram.bytes);
+        }
+    }
+
+    fn send_now(&self, address: SocketAddr, bytes: &[u8]) {
+        if let Err(error) = self.socket.send_to(bytes, address) {
+            // A datagram that can't be sent is lost like on a bad connection.
+            if error.kind() != io::ErrorKind::WouldBlock {
+                warn!("Failed to send to {}: {}", address, error);
+            }
+        }
+    }
+
+    /// All datagrams received since the last call.
+    pub fn receive(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        let mut datagrams = Vec::new();
+        let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((length, address)) => datagrams.push((address, buffer[..length].to_vec())),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                // On some platforms an unreachable peer shows up as an error of the next receive.
+                Err(error) if error.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(error) => {
+                    warn!("Failed to receive: {}", error);
+                    break;
+                },
+            }
+        }
+        datagrams
+    }
+}
@@ -1,6 +1,8 @@
 use bevy::prelude::*;
 
+use crate::collision::Collider;
 use crate::schedule::ScheduleSet;
+use crate::terrain::Terrain;
 
 /// Component to store the position of an entity
 #[derive(Component, Debug)]
@@ -18,52 +20,191 @@ pub struct Mass(pub f32);
 #[derive(Component)]
 pub struct Velocity(pub Vec3);
 
-/// Component to store the force applied to an entity in the current frame
+/// Component to store the force applied to an entity in the current physics tick
 #[derive(Component)]
 pub struct Force(pub Vec3);
 
-pub struct PhysicsPlugin;
+/// Marker component for entities that fly on a ballistic trajectory.
+/// Ballistic entities are only affected by gravity, not by forces.
+#[derive(Component)]
+pub struct Ballistic;
+
+/// Marker component for entities that drive on the terrain.
+/// Grounded entities stay on the ground, are tilted to match the slope
+/// and are pulled downhill by the part of gravity along the slope.
+#[derive(Component)]
+pub struct Grounded;
+
+/// How fast grounded entities are tilted towards the slope of the terrain, per physics tick.
+const TILT_SMOOTHING: f32 = 0.3;
+
+/// Gravitational acceleration applied to ballistic and grounded entities.
+pub const GRAVITY: Vec3 = Vec3::new(0.0, -9.81, 0.0);
+
+/// Component to store the position of an entity at the start of the current physics tick.
+/// Used to interpolate the rendered position between two ticks.
+#[derive(Component)]
+pub struct PreviousPosition(pub Vec3);
+
+/// Component to store the rotation of an entity at the start of the current physics tick.
+/// Used to interpolate the rendered rotation between two ticks.
+#[derive(Component)]
+pub struct PreviousRotation(pub Quat);
+
+/// Resource to count the physics ticks since the start of the game.
+/// Used to number the ticks when they are exchanged over the network.
+#[derive(Resource, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SimulationTick(pub u32);
+
+/// Plugin for the physics system.
+/// The physics run in the `FixedUpdate` schedule with `tick_rate` ticks per second,
+/// so the simulation behaves the same regardless of the framerate.
+pub struct PhysicsPlugin {
+    pub tick_rate: f64,
+}
+
+impl Default for PhysicsPlugin {
+    fn default() -> Self {
+        PhysicsPlugin {
+            tick_rate: 64.0,
+        }
+    }
+}
 
 impl Plugin for PhysicsPlugin {
     fn build(&self, app: &mut App) {
-        
-        app.add_systems(Update, (
-            apply_force,
-        ).in_set(ScheduleSet::Physics));
+        app.insert_resource(Time::<Fixed>::from_hz(self.tick_rate))
+            .init_resource::<SimulationTick>()
+            .add_systems(FixedUpdate, (
+                advance_tick,
+                store_previous_state,
+            ).before(ScheduleSet::Control))
+            .add_systems(FixedUpdate, (
+                (
+                    apply_slope_force,
+                    apply_force,
+                    follow_terrain,
+                ).chain(),
+                apply_ballistics,
+            ).in_set(ScheduleSet::Physics));
+    }
+}
+
+/// System to count the physics ticks.
+pub fn advance_tick(
+    mut tick: ResMut<SimulationTick>,
+) {
+    tick.0 = tick.0.wrapping_add(1);
+}
+
+/// System to store the position and rotation at the start of a physics tick.
+fn store_previous_state (
+    mut query: Query<(&Position, &Rotation, &mut PreviousPosition, &mut PreviousRotation)>,
+) {
+    for (position, rotation, mut previous_position, mut previous_rotation) in query.iter_mut() {
+        previous_position.0 = position.0;
+        previous_rotation.0 = rotation.0;
     }
 }
 
 /// System to apply force to entities with a position, velocity, force, and mass.
 /// The force is applied to the velocity, and the velocity is used to update the position.
-/// The force is then reset to zero.
-/// The direction of the entity is also updated to face the direction of the velocity.
-/// If the velocity is less than 2.5, the velocity is set to zero.
-fn apply_force (
+/// Once the entity moves, the force is reset to zero.
+/// The direction of the entity is also turned around the vertical axis to face the direction of the velocity,
+/// or the opposite direction when driving backwards, keeping the tilt of grounded entities.
+/// If the velocity is less than 2.5, the velocity is set to zero and the force is kept,
+/// so the forces of the following ticks add up until they get the entity moving.
+/// Every tick is integrated with the fixed timestep, not the time that passed, so the same forces always give the same
+/// movement. Replays rely on this to reproduce a match from the recorded controls.
+pub fn apply_force (
     mut query: Query<(&mut Position, &mut Velocity, &mut Force, &Mass, &mut Rotation)>,
-    time: Res<Time>,
+    time: Res<Time<Fixed>>,
 ) {
+    let dt = time.timestep().as_secs_f32();
     for (mut position, mut velocity, mut force, mass, mut direction) in query.iter_mut() {
         let acceleration = force.0 / mass.0;
-        velocity.0 += acceleration * time.delta_seconds();
+        velocity.0 += acceleration * dt;
         
         if velocity.0.length() <= 2.5  {
             velocity.0 = Vec3::ZERO;
             continue;
         }
+        force.0 = Vec3::ZERO;
+
+        let forward = direction.0.mul_vec3(Vec3::Z);
+        let mut turn = wrap_angle(velocity.0.x.atan2(velocity.0.z) - forward.x.atan2(forward.z));
+        if turn.abs() > std::f32::consts::FRAC_PI_2 {
+            turn = wrap_angle(turn + std::f32::consts::PI);
+        }
+        direction.0 = Quat::from_rotation_y(turn * 0.1) * direction.0;
+        position.0 += velocity.0 * dt;
+    }
+}
+
+/// Wrap an angle to the range `-PI..PI`.
+pub fn wrap_angle(angle: f32) -> f32 {
+    (angle + std::f32::consts::PI).rem_euclid(std::f32::consts::TAU) - std::f32::consts::PI
+}
+
+/// System to apply the part of gravity along the slope of the terrain to grounded entities.
+/// Driving uphill works against this force, driving downhill is helped by it.
+pub fn apply_slope_force (
+    mut query: Query<(&Position, &Mass, &mut Force), With<Grounded>>,
+    terrain: Option<Res<Terrain>>,
+) {
+    let Some(terrain) = terrain else {
+        return;
+    };
+    for (position, mass, mut force) in query.iter_mut() {
+        let normal = terrain.normal_at(position.0.x, position.0.z);
+        let along_slope = GRAVITY - normal * GRAVITY.dot(normal);
+        force.0 += along_slope * mass.0;
+    }
+}
 
-        let next_dir = Quat::from_rotation_y(velocity.0.x.atan2(velocity.0.z));
-        direction.0 = if direction.0.angle_between(next_dir) < 1.57 {
-            direction.0.slerp(next_dir, 0.1)
-        } else {
-            direction.0.slerp(next_dir.mul_quat(Quat::from_rotation_y(3.14)), 0.1)
+/// System to keep grounded entities on the terrain.
+/// The position is moved to the ground, the velocity is kept parallel to the ground
+/// and the rotation is tilted to the slope while keeping the heading.
+pub fn follow_terrain (
+    mut query: Query<(&mut Position, &mut Velocity, &mut Rotation), With<Grounded>>,
+    terrain: Option<Res<Terrain>>,
+) {
+    for (mut position, mut velocity, mut rotation) in query.iter_mut() {
+        let (height, normal) = match terrain.as_deref() {
+            Some(terrain) => (terrain.height_at(position.0.x, position.0.z), terrain.normal_at(position.0.x, position.0.z)),
+            None => (0.0, Vec3::Y),
         };
-        position.0 += velocity.0 * time.delta_seconds();
-        force.0 = Vec3::ZERO;
+        position.0.y = height;
+        let into_ground = velocity.0.dot(normal);
+        velocity.0 -= normal * into_ground;
+
+        let forward = rotation.0.mul_vec3(Vec3::Z);
+        let heading = Quat::from_rotation_y(forward.x.atan2(forward.z));
+        let target = Quat::from_rotation_arc(Vec3::Y, normal) * heading;
+        rotation.0 = rotation.0.slerp(target, TILT_SMOOTHING);
+    }
+}
+
+/// System to move ballistic entities along their trajectory.
+/// Gravity is applied to the velocity and the entity is rotated to face its direction of flight.
+/// Like `apply_force` it is integrated with the fixed timestep.
+pub fn apply_ballistics (
+    mut query: Query<(&mut Position, &mut Velocity, &mut Rotation), With<Ballistic>>,
+    time: Res<Time<Fixed>>,
+) {
+    let dt = time.timestep().as_secs_f32();
+    for (mut position, mut velocity, mut rotation) in query.iter_mut() {
+        velocity.0 += GRAVITY * dt;
+        position.0 += velocity.0 * dt;
+        if let Some(direction) = velocity.0.try_normalize() {
+            rotation.0 = Quat::from_rotation_arc(Vec3::Z, direction);
+        }
     }
 }
 
 /// Bundle of components for physics.
-/// This bundle includes the position, rotation, mass, velocity, and force components.
+/// This bundle includes the position, rotation, mass, velocity, force and collider components,
+/// as well as the state of the previous tick used for interpolation.
 #[derive(Bundle)]
 pub struct Physics {
     pub position: Position,
@@ -71,6 +212,9 @@ pub struct Physics {
     pub mass: Mass,
     pub velocity: Velocity,
     pub force: Force,
+    pub collider: Collider,
+    pub previous_position: PreviousPosition,
+    pub previous_rotation: PreviousRotation,
 }
 
 impl Default for Physics {
@@ -81,6 +225,9 @@ impl Default for Physics {
             mass: Mass(1.0),
             velocity: Velocity(Vec3::ZERO),
             force: Force(Vec3::ZERO),
+            collider: Collider::default(),
+            previous_position: PreviousPosition(Vec3::ZERO),
+            previous_rotation: PreviousRotation(Quat::IDENTITY),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,241 @@
+use bevy::prelude::*;
+
+use crate::collision::{raycast, Collider, RayHit, WorldShape};
+use crate::physics::{apply_ballistics, Ballistic, Position, PreviousPosition, PreviousRotation, Rotation, Velocity};
+use crate::schedule::ScheduleSet;
+use crate::damage::Wreck;
+use crate::control::TankControl;
+use crate::tank::Tank;
+use crate::terrain::Terrain;
+use crate::turret::{muzzle, traverse_turret, Gun, Turret};
+
+/// Time in seconds before a projectile that did not hit anything is despawned.
+const PROJECTILE_LIFETIME: f32 = 8.0;
+
+/// Component for projectiles fired by a gun.
+/// The `shooter` is the tank that fired the projectile, it can not be hit by its own projectile.
+/// `penetration` is the armor thickness the projectile can go through, `damage` the hit points it takes when it does.
+#[derive(Component, Debug)]
+pub struct Projectile {
+    pub shooter: Entity,
+    pub lifetime: f32,
+    pub penetration: f32,
+    pub damage: f32,
+}
+
+/// Event sent when a projectile hits a tank or the terrain.
+/// `target` is `None` if the ground was hit. The projectile is despawned when the event is sent.
+#[derive(Event, Debug, Clone, Copy)]
+pub struct ProjectileHitEvent {
+    pub shooter: Entity,
+    pub target: Option<Entity>,
+    pub point: Vec3,
+    pub normal: Vec3,
+    pub velocity: Vec3,
+    pub penetration: f32,
+    pub damage: f32,
+}
+
+/// Resource to store the mesh and material of projectiles.
+#[derive(Resource, Debug, Default)]
+pub struct ProjectileAssets {
+    pub mesh: Handle<Mesh>,
+    pub material: Handle<StandardMaterial>,
+}
+
+/// Plugin for firing and flying projectiles.
+pub struct ProjectilePlugin;
+
+impl Plugin for ProjectilePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ProjectileHitEvent>()
+            .init_resource::<ProjectileAssets>()
+            .add_systems(Startup, (
+                create_projectile_assets,
+            ))
+            .add_systems(FixedUpdate, (
+                fire_guns.after(traverse_turret),
+            ).in_set(ScheduleSet::Control))
+            .add_systems(FixedUpdate, (
+                detect_projectile_hits.after(apply_ballistics),
+                expire_projectiles,
+            ).in_set(ScheduleSet::Physics))
+            .add_systems(Update, (
+                update_projectile_model,
+            ).in_set(ScheduleSet::UpdateWorld));
+    }
+}
+
+/// System to create the mesh and material used by all projectiles.
+fn create_projectile_assets(
+    mut assets: ResMut<ProjectileAssets>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    *assets = ProjectileAssets {
+        mesh: meshes.add(Mesh::from(shape::UVSphere {
+            radius: 0.4,
+            sectors: 8,
+            stacks: 8,
+        })),
+        material: materials.add(StandardMaterial {
+            base_color: Color::rgb(1.0, 0.8, 0.3),
+            emissive: Color::rgb(1.0, 0.6, 0.1),
+            ..default()
+        }),
+    }
+}
+
+/// System to reload the guns and fire a projectile from the muzzle of every loaded gun of tanks that want to fire.
+/// The projectile inherits the velocity of the tank. Wrecks can not fire.
+#[allow(clippy::type_complexity)]
+fn fire_guns(
+    mut commands: Commands,
+    tank_query: Query<(Entity, &Position, &Rotation, &Velocity, &TankControl, &Children), (With<Tank>, Without<Wreck>)>,
+    turret_query: Query<(&Turret, &Children)>,
+    mut gun_query: Query<&mut Gun>,
+    assets: Res<ProjectileAssets>,
+    time: Res<Time>,
+) {
+    for (tank, position, rotation, velocity, control, children) in tank_query.iter() {
+        for (turret, turret_children) in children.iter().filter_map(|child| turret_query.get(*child).ok()) {
+            for gun_entity in turret_children.iter() {
+                let Ok(mut gun) = gun_query.get_mut(*gun_entity) else {
+                    continue;
+                };
+                gun.reload_remaining = (gun.reload_remaining - time.delta_seconds()).max(0.0);
+                if !control.fire || gun.reload_remaining > 0.0 {
+                    continue;
+                }
+                gun.reload_remaining = gun.reload_time;
+
+                let (muzzle_position, direction) = muzzle(position.0, rotation.0, turret, &gun);
+                let projectile = Projectile {
+                    shooter: tank,
+                    lifetime: PROJECTILE_LIFETIME,
+                    penetration: gun.penetration,
+                    damage: gun.damage,
+                };
+                let projectile_rotation = Quat::from_rotation_arc(Vec3::Z, direction);
+                spawn_projectile(&mut commands, &assets, projectile, muzzle_position, projectile_rotation, direction * gun.muzzle_velocity + velocity.0);
+            }
+        }
+    }
+}
+
+/// Spawn a projectile in flight, when a gun fires or a replay restores the projectiles of a keyframe.
+pub fn spawn_projectile(
+    commands: &mut Commands,
+    assets: &ProjectileAssets,
+    projectile: Projectile,
+    position: Vec3,
+    rotation: Quat,
+    velocity: Vec3,
+) {
+    commands.spawn((
+        projectile,
+        Ballistic,
+        Position(position),
+        Rotation(rotation),
+        Velocity(velocity),
+        PreviousPosition(position),
+        PreviousRotation(rotation),
+        PbrBundle {
+            mesh: assets.mesh.clone(),
+            material: assets.material.clone(),
+            transform: Transform::from_translation(position),
+            ..default()
+        },
+    ));
+}
+
+/// System to detect projectiles hitting a collider or the terrain in the current physics tick.
+/// The path of the projectile since the last tick is cast against all colliders except the shooter,
+/// so fast projectiles can not pass through thin objects.
+pub fn detect_projectile_hits(
+    mut commands: Commands,
+    projectile_query: Query<(Entity, &Projectile, &Position, &PreviousPosition, &Velocity)>,
+    target_query: Query<(Entity, &Collider, &Position, &Rotation), Without<Projectile>>,
+    mut hit_events: EventWriter<ProjectileHitEvent>,
+    terrain: Option<Res<Terrain>>,
+) {
+    for (entity, projectile, position, previous_position, velocity) in projectile_query.iter() {
+        let path = position.0 - previous_position.0;
+        let Some(direction) = path.try_normalize() else {
+            continue;
+        };
+        let length = path.length();
+
+        let mut closest: Option<(Option<Entity>, RayHit)> = ground_hit(terrain.as_deref(), previous_position.0, direction, length)
+            .map(|hit| (None, hit));
+        for (target, collider, target_position, target_rotation) in target_query.iter() {
+            if target == projectile.shooter {
+                continue;
+            }
+            let shape = WorldShape::new(collider, target_position.0, target_rotation.0);
+            if let Some(hit) = raycast(&shape, previous_position.0, direction, length) {
+                if closest.map_or(true, |(_, closest_hit)| hit.distance < closest_hit.distance) {
+                    closest = Some((Some(target), hit));
+                }
+            }
+        }
+
+        if let Some((target, hit)) = closest {
+            hit_events.send(ProjectileHitEvent {
+                shooter: projectile.shooter,
+                target,
+                point: hit.point,
+                normal: hit.normal,
+                velocity: velocity.0,
+                penetration: projectile.penetration,
+                damage: projectile.damage,
+            });
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// Intersection of a ray with the terrain, or with the ground plane if there is no terrain.
+fn ground_hit(terrain: Option<&Terrain>, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<RayHit> {
+    if let Some(terrain) = terrain {
+        return terrain.raycast(origin, direction, max_distance);
+    }
+    if direction.y >= 0.0 || origin.y < 0.0 {
+        return None;
+    }
+    let distance = -origin.y / direction.y;
+    if distance > max_distance {
+        return None;
+    }
+    Some(RayHit {
+        distance,
+        point: origin + direction * distance,
+        normal: Vec3::Y,
+    })
+}
+
+/// System to despawn projectiles at the end of their lifetime.
+fn expire_projectiles(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut Projectile)>,
+    time: Res<Time>,
+) {
+    for (entity, mut projectile) in query.iter_mut() {
+        projectile.lifetime -= time.delta_seconds();
+        if projectile.lifetime <= 0.0 {
+            commands.entity(entity).despawn_recursive();
+        }
+    }
+}
+
+/// System to update the projectile model, interpolated between the last two physics ticks.
+fn update_projectile_model(
+    mut query: Query<(&Position, &Rotation, &PreviousPosition, &PreviousRotation, &mut Transform), With<Projectile>>,
+    fixed_time: Res<Time<Fixed>>,
+) {
+    let alpha = fixed_time.overstep_percentage();
+    for (position, rotation, previous_position, previous_rotation, mut transform) in query.iter_mut() {
+        transform.translation = previous_position.0.lerp(position.0, alpha);
+        transform.rotation = previous_rotation.0.slerp(rotation.0, alpha);
+    }
+}
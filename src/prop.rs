@@ -0,0 +1,136 @@
+use std::collections::BTreeSet;
+
+use bevy::ecs::system::EntityCommands;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::collision::{Collider, ColliderShape};
+use crate::damage::{destroy_entities, DestroyedEvent, Health, Wreck};
+use crate::physics::{apply_force, Force, Grounded, Mass, Position, Rotation, Velocity};
+use crate::schedule::ScheduleSet;
+
+/// Drag applied to tanks driving over rubble, in addition to the drag of their engine.
+const RUBBLE_DRAG: f32 = 3.0;
+
+/// Height of rubble relative to the height of the prop it was.
+const RUBBLE_HEIGHT: f32 = 0.2;
+
+/// Marker component for props placed on the map.
+#[derive(Component)]
+pub struct Prop;
+
+/// Component to store the index of a prop in its map definition.
+/// The id is the same on every machine loading the same map, so it identifies props in the match state.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct PropId(pub u32);
+
+/// Component for destroyed props. Rubble does not block movement or projectiles,
+/// but slows down tanks driving over the area of `half_extents` around it.
+/// It keeps the collider, transform and maximum health of the prop, so a replay can restore the prop.
+#[derive(Component, Debug)]
+pub struct Rubble {
+    pub half_extents: Vec3,
+    collider: Collider,
+    transform: Transform,
+    max_health: Option<f32>,
+}
+
+impl Rubble {
+    /// Turn the rubble back into the prop it was, with `health` left or undamaged.
+    pub fn restore(&self, commands: &mut EntityCommands, transform: &mut Transform, health: Option<f32>) {
+        *transform = self.transform;
+        commands.remove::<(Rubble, Wreck)>()
+            .insert(self.collider);
+        if let Some(max) = self.max_health {
+            commands.insert(Health {
+                current: health.unwrap_or(max),
+                max,
+            });
+        }
+    }
+}
+
+/// Resource to store which props of the map are destroyed.
+/// This is the part of the match state describing the props: props in the set are turned into rubble,
+/// so setting it from the network or a replay reproduces the destruction.
+#[derive(Resource, Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DestroyedProps(pub BTreeSet<PropId>);
+
+/// Plugin for destructible props.
+pub struct PropPlugin;
+
+impl Plugin for PropPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<DestroyedProps>()
+            .add_systems(FixedUpdate, (
+                apply_rubble_drag.before(apply_force),
+                (
+                    record_destroyed_props.after(destroy_entities),
+                    turn_props_into_rubble,
+                ).chain(),
+            ).in_set(ScheduleSet::Physics));
+    }
+}
+
+/// System to add destroyed props to the `DestroyedProps` of the match state.
+fn record_destroyed_props(
+    mut destroyed_events: EventReader<DestroyedEvent>,
+    prop_query: Query<&PropId>,
+    mut destroyed_props: ResMut<DestroyedProps>,
+) {
+    for destroyed in destroyed_events.read() {
+        if let Ok(id) = prop_query.get(destroyed.entity) {
+            destroyed_props.0.insert(*id);
+        }
+    }
+}
+
+/// System to turn the props in `DestroyedProps` into rubble.
+/// The collider and health are removed and the model is flattened to the ground.
+#[allow(clippy::type_complexity)]
+fn turn_props_into_rubble(
+    mut commands: Commands,
+    mut prop_query: Query<(Entity, &PropId, &Collider, Option<&Health>, &mut Transform), (With<Prop>, Without<Rubble>)>,
+    destroyed_props: Res<DestroyedProps>,
+) {
+    if destroyed_props.0.is_empty() {
+        return;
+    }
+    for (entity, id, collider, health, mut transform) in prop_query.iter_mut() {
+        if !destroyed_props.0.contains(id) {
+            continue;
+        }
+        let half_extents = match collider.shape {
+            ColliderShape::Cuboid { half_extents } => half_extents,
+            ColliderShape::Capsule { half_height, radius } => Vec3::new(radius, half_height + radius, radius),
+            ColliderShape::Sphere { radius } => Vec3::splat(radius),
+        };
+        let rubble = Rubble {
+            half_extents,
+            collider: *collider,
+            transform: *transform,
+            max_health: health.map(|health| health.max),
+        };
+        transform.translation.y -= half_extents.y * (1.0 - RUBBLE_HEIGHT);
+        transform.scale.y *= RUBBLE_HEIGHT;
+        commands.entity(entity)
+            .remove::<(Collider, Health)>()
+            .insert((rubble, Wreck));
+    }
+}
+
+/// System to slow down grounded entities driving over rubble.
+pub fn apply_rubble_drag(
+    mut query: Query<(&Position, &Velocity, &Mass, &mut Force), With<Grounded>>,
+    rubble_query: Query<(&Position, &Rotation, &Rubble)>,
+) {
+    for (position, velocity, mass, mut force) in query.iter_mut() {
+        let on_rubble = rubble_query.iter().any(|(rubble_position, rubble_rotation, rubble)| {
+            let local = rubble_rotation.0.inverse().mul_vec3(position.0 - rubble_position.0);
+            local.x.abs() <= rubble.half_extents.x && local.z.abs() <= rubble.half_extents.z
+        });
+        if on_rubble {
+            force.0 -= velocity.0 * RUBBLE_DRAG * mass.0;
+        }
+    }
+}
@@ -0,0 +1,981 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::app::AppExit;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::asset_loader::tank_definitions_loaded;
+use crate::camera::{CameraMode, PlayerCamera};
+use crate::control::{ControlSource, TankControl};
+use crate::damage::{Health, Wreck};
+use crate::input_map::{ActionInput, InputAction};
+use crate::map::{MapSource, SpawnPoints};
+use crate::physics::{Force, Grounded, Position, PreviousPosition, PreviousRotation, Rotation, Velocity};
+use crate::projectile::{spawn_projectile, Projectile, ProjectileAssets};
+use crate::prop::{DestroyedProps, Prop, PropId, Rubble};
+use crate::schedule::ScheduleSet;
+use crate::tank::{apply_tank_control, spawn_tank, Player, SpawnTanks, Tank, TankKind, TankType, Team};
+use crate::tank_definition::TankDefinition;
+use crate::turret::{Gun, Turret};
+
+/// Version of the replay format. Replays of other versions can not be played.
+const REPLAY_VERSION: u32 = 1;
+
+/// Physics ticks between two keyframes.
+const KEYFRAME_INTERVAL: u32 = 64;
+
+/// Physics ticks between two saves of a recording, so a crash doesn't lose the whole match.
+const SAVE_INTERVAL: u32 = KEYFRAME_INTERVAL * 30;
+
+/// Keyframes skipped when seeking forward or back.
+const SEEK_KEYFRAMES: u32 = 5;
+
+/// Slowest playback speed relative to real time.
+const MIN_SPEED: f32 = 0.125;
+
+/// Fastest playback speed relative to real time.
+const MAX_SPEED: f32 = 8.0;
+
+/// Errors that can occur while loading or saving a replay.
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("Could not access replay: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not encode replay: {0}")]
+    Encode(#[from] bincode::Error),
+    #[error("Replay has version {0}, only version {REPLAY_VERSION} can be played")]
+    Version(u32),
+}
+
+/// A tank of a replay, identified by its slot, the index in `Replay::tanks`.
+/// The tank exists from `spawn_tick` until before `despawn_tick` and is spawned at `position` with `rotation`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ReplayTank {
+    spawn_tick: u32,
+    despawn_tick: Option<u32>,
+    kind: TankKind,
+    team: u8,
+    player: Option<usize>,
+    position: Vec3,
+    rotation: Quat,
+}
+
+impl ReplayTank {
+    /// Whether the tank exists during the physics tick `tick`.
+    fn exists_at(&self, tick: u32) -> bool {
+        self.spawn_tick <= tick && self.despawn_tick.map_or(true, |despawn_tick| tick < despawn_tick)
+    }
+}
+
+/// The state of a tank in a keyframe.
+/// `force` is the force a standing tank carries over to the next tick until it gets moving.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TankFrame {
+    slot: u16,
+    position: Vec3,
+    rotation: Quat,
+    velocity: Vec3,
+    force: Vec3,
+    grounded: bool,
+    control: TankControl,
+    health: Option<f32>,
+    turret_yaw: f32,
+    gun_pitch: f32,
+    reload_remaining: f32,
+}
+
+/// The state of a projectile in flight in a keyframe, fired by the tank in the slot `shooter`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ProjectileFrame {
+    shooter: u16,
+    position: Vec3,
+    velocity: Vec3,
+    lifetime: f32,
+    penetration: f32,
+    damage: f32,
+}
+
+/// The state of all tanks, projectiles and props after the physics tick `tick`.
+/// `damaged_props` is the health of the props that were damaged but not destroyed.
+/// Playback starts from keyframes when seeking and corrects the tanks with them, in case the simulation drifted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Keyframe {
+    tick: u32,
+    tanks: Vec<TankFrame>,
+    projectiles: Vec<ProjectileFrame>,
+    destroyed_props: DestroyedProps,
+    damaged_props: Vec<(PropId, f32)>,
+}
+
+/// A recorded match that can be simulated again.
+/// The physics are deterministic, so the map, the spawned tanks and the controls of every tank in every physics tick
+/// reproduce the match. Controls are only stored in the ticks they changed, as pairs of tank slot and controls.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub map: MapSource,
+    tanks: Vec<ReplayTank>,
+    ticks: Vec<Vec<(u16, TankControl)>>,
+    keyframes: Vec<Keyframe>,
+}
+
+impl Replay {
+    fn new(map: MapSource) -> Self {
+        Replay {
+            map,
+            tanks: Vec::new(),
+            ticks: Vec::new(),
+            keyframes: Vec::new(),
+        }
+    }
+
+    /// Number of recorded physics ticks.
+    pub fn len(&self) -> u32 {
+        self.ticks.len() as u32
+    }
+
+    /// The encoding of replay files, with variable length integers to keep them small.
+    fn options() -> impl Options {
+        bincode::DefaultOptions::new()
+    }
+
+    /// Load a replay from a file.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        let bytes = fs::read(path)?;
+        let mut reader = bytes.as_slice();
+        let version: u32 = Self::options().deserialize_from(&mut reader)?;
+        if version != REPLAY_VERSION {
+            return Err(ReplayError::Version(version));
+        }
+        Ok(Self::options().deserialize_from(&mut reader)?)
+    }
+
+    /// Save the replay to a file, creating the directory if needed.
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ReplayError> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut bytes = Self::options().serialize(&REPLAY_VERSION)?;
+        bytes.extend(Self::options().serialize(self)?);
+        fs::write(path, bytes)?;
+        Ok(())
+    }
+}
+
+/// Component for the tanks spawned by a replay, with their slot in the replay.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReplaySlot(pub u16);
+
+/// Resource to store the match being recorded.
+#[derive(Resource)]
+struct ReplayRecording {
+    path: PathBuf,
+    replay: Replay,
+    slots: HashMap<Entity, u16>,
+    /// The latest recorded controls of every slot.
+    controls: Vec<TankControl>,
+    /// Whether the controls of a tick were recorded and its keyframe is still to be taken.
+    simulated: bool,
+}
+
+/// Resource to store the replay being played.
+#[derive(Resource)]
+pub struct ReplayPlayback {
+    replay: Replay,
+    /// The physics tick of the replay simulated next.
+    pub tick: u32,
+    /// The tank of every slot while it exists.
+    entities: Vec<Option<Entity>>,
+    /// Whether the tanks of the first tick were spawned, which waits for the map.
+    started: bool,
+    /// Whether the controls of a tick were applied and it is still to be finished.
+    simulated: bool,
+    /// Index of the keyframe to continue from.
+    seek: Option<u32>,
+}
+
+impl ReplayPlayback {
+    /// Number of physics ticks of the replay.
+    pub fn len(&self) -> u32 {
+        self.replay.len()
+    }
+
+    /// Whether all ticks of the replay were played.
+    pub fn finished(&self) -> bool {
+        self.tick >= self.replay.len()
+    }
+}
+
+/// Run condition for the systems that set up a match, which a replay does itself from the recording.
+pub fn not_replaying(playback: Option<Res<ReplayPlayback>>) -> bool {
+    playback.is_none()
+}
+
+/// What the `ReplayPlugin` does.
+#[derive(Debug, Clone)]
+pub enum ReplayMode {
+    /// Neither record nor play.
+    Off,
+    /// Record the match on `map` into the file at `path`.
+    Record { path: PathBuf, map: MapSource },
+    /// Play a replay instead of a match. The game has to be started on the map of the replay.
+    Play(Replay),
+}
+
+/// Plugin for recording matches and playing them back.
+/// The recording stores the controls of every tank per physics tick and a keyframe with the state of all tanks
+/// every `KEYFRAME_INTERVAL` ticks. Playback simulates the match again with the recorded controls and can be paused,
+/// sped up, slowed down and moved forward and back by keyframes, while the cameras move freely.
+/// Seeking back before the destruction of a prop turns its rubble back into the prop.
+pub struct ReplayPlugin {
+    pub mode: ReplayMode,
+}
+
+impl Default for ReplayPlugin {
+    fn default() -> Self {
+        ReplayPlugin {
+            mode: ReplayMode::Off,
+        }
+    }
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        match &self.mode {
+            ReplayMode::Off => {},
+            ReplayMode::Record { path, map } => {
+                app.insert_resource(ReplayRecording {
+                        path: path.clone(),
+                        replay: Replay::new(map.clone()),
+                        slots: HashMap::new(),
+                        controls: Vec::new(),
+                        simulated: false,
+                    })
+                    .add_systems(FixedUpdate, (
+                        record_controls.after(apply_tank_control).in_set(ScheduleSet::Control),
+                        record_keyframe.after(ScheduleSet::Physics),
+                    ))
+                    .add_systems(Last, (
+                        save_recording_on_exit,
+                    ));
+            },
+            ReplayMode::Play(replay) => {
+                app.insert_resource(ReplayPlayback {
+                        replay: replay.clone(),
+                        tick: 0,
+                        entities: vec![None; replay.tanks.len()],
+                        started: false,
+                        simulated: false,
+                        seek: None,
+                    })
+                    .add_systems(Update, (
+                        control_playback,
+                        seek_playback,
+                    ).chain().in_set(ScheduleSet::Input))
+                    .add_systems(Update, (
+                        start_playback.in_set(SpawnTanks)
+                            .run_if(resource_exists::<SpawnPoints>().and_then(tank_definitions_loaded)),
+                    ).in_set(ScheduleSet::UpdateWorld))
+                    .add_systems(FixedUpdate, (
+                        play_controls.before(apply_tank_control).in_set(ScheduleSet::Control),
+                        finish_replay_tick.after(ScheduleSet::Physics),
+                    ));
+            },
+        }
+    }
+}
+
+/// System parameter to read and write the state of tanks stored in keyframes.
+#[derive(SystemParam)]
+#[allow(clippy::type_complexity)]
+struct TankStates<'w, 's> {
+    tanks: Query<'w, 's, (
+        &'static mut Position,
+        &'static mut PreviousPosition,
+        &'static mut Rotation,
+        &'static mut PreviousRotation,
+        &'static mut Velocity,
+        &'static mut Force,
+        Has<Grounded>,
+        &'static mut TankControl,
+        Option<&'static mut Health>,
+        Has<Wreck>,
+        &'static Children,
+    ), With<Tank>>,
+    turrets: Query<'w, 's, (&'static mut Turret, Option<&'static Children>)>,
+    guns: Query<'w, 's, &'static mut Gun>,
+}
+
+impl TankStates<'_, '_> {
+    /// The state of a tank, or `None` if the entity is not a tank.
+    fn read(&self, entity: Entity, slot: u16) -> Option<TankFrame> {
+        let (position, _, rotation, _, velocity, force, grounded, control, health, _, children) = self.tanks.get(entity).ok()?;
+        let mut frame = TankFrame {
+            slot,
+            position: position.0,
+            rotation: rotation.0,
+            velocity: velocity.0,
+            force: force.0,
+            grounded,
+            control: *control,
+            health: health.map(|health| health.current),
+            turret_yaw: 0.0,
+            gun_pitch: 0.0,
+            reload_remaining: 0.0,
+        };
+        for (turret, turret_children) in children.iter().filter_map(|child| self.turrets.get(*child).ok()) {
+            frame.turret_yaw = turret.yaw;
+            if let Some(gun) = turret_children.into_iter().flatten().find_map(|child| self.guns.get(*child).ok()) {
+                frame.gun_pitch = gun.pitch;
+                frame.reload_remaining = gun.reload_remaining;
+            }
+        }
+        Some(frame)
+    }
+
+    /// Set a tank to a state. With `jump` the tank is not interpolated from its previous position.
+    fn write(&mut self, commands: &mut Commands, entity: Entity, frame: &TankFrame, jump: bool) {
+        let Ok((mut position, mut previous_position, mut rotation, mut previous_rotation, mut velocity, mut force, grounded, mut control, health, wreck, children)) = self.tanks.get_mut(entity) else {
+            return;
+        };
+        position.0 = frame.position;
+        rotation.0 = frame.rotation;
+        velocity.0 = frame.velocity;
+        force.0 = frame.force;
+        *control = frame.control;
+        match (frame.grounded, grounded) {
+            (true, false) => {
+                commands.entity(entity).insert(Grounded);
+            },
+            (false, true) => {
+                commands.entity(entity).remove::<Grounded>();
+            },
+            _ => {},
+        }
+        if jump {
+            previous_position.0 = frame.position;
+            previous_rotation.0 = frame.rotation;
+        }
+        if let (Some(mut health), Some(current)) = (health, frame.health) {
+            health.current = current;
+            match (current <= 0.0, wreck) {
+                (true, false) => {
+                    commands.entity(entity).insert(Wreck);
+                },
+                (false, true) => {
+                    commands.entity(entity).remove::<Wreck>();
+                },
+                _ => {},
+            }
+        }
+        for child in children.iter() {
+            let Ok((mut turret, turret_children)) = self.turrets.get_mut(*child) else {
+                continue;
+            };
+            turret.yaw = frame.turret_yaw;
+            if let Some(gun) = turret_children.into_iter().flatten().find(|child| self.guns.contains(**child)) {
+                if let Ok(mut gun) = self.guns.get_mut(*gun) {
+                    gun.pitch = frame.gun_pitch;
+                    gun.reload_remaining = frame.reload_remaining;
+                }
+            }
+        }
+    }
+}
+
+/// System parameter to read and write the state of props stored in keyframes.
+#[derive(SystemParam)]
+#[allow(clippy::type_complexity)]
+struct PropStates<'w, 's> {
+    props: Query<'w, 's, (
+        Entity,
+        &'static PropId,
+        Option<&'static mut Health>,
+        Option<&'static Rubble>,
+        &'static mut Transform,
+    ), (With<Prop>, Without<Tank>)>,
+}
+
+impl PropStates<'_, '_> {
+    /// The health of the damaged props, ordered by id.
+    fn read(&self) -> Vec<(PropId, f32)> {
+        let mut damaged: Vec<(PropId, f32)> = self.props.iter()
+            .filter_map(|(_, id, health, ..)| health.filter(|health| health.current < health.max).map(|health| (*id, health.current)))
+            .collect();
+        damaged.sort_by_key(|(id, _)| *id);
+        damaged
+    }
+
+    /// Set the props to a keyframe. The rubble of props that are not in `destroyed_props` is turned back into the props,
+    /// the props in it are turned into rubble by the `PropPlugin`.
+    fn write(&mut self, commands: &mut Commands, destroyed_props: &DestroyedProps, damaged_props: &[(PropId, f32)]) {
+        for (entity, id, health, rubble, mut transform) in self.props.iter_mut() {
+            if destroyed_props.0.contains(id) {
+                continue;
+            }
+            let damaged = damaged_props.iter().find(|(damaged, _)| damaged == id).map(|(_, health)| *health);
+            match (health, rubble) {
+                (Some(mut health), _) => health.current = damaged.unwrap_or(health.max),
+                (None, Some(rubble)) => rubble.restore(&mut commands.entity(entity), &mut transform, damaged),
+                (None, None) => {},
+            }
+        }
+    }
+}
+
+/// System to record the controls of all tanks after they were produced for the physics tick.
+/// Tanks that appear get the next slot, with the position and rotation they start the tick with.
+/// Nothing is recorded until the first tank exists.
+#[allow(clippy::type_complexity)]
+fn record_controls(
+    mut recording: ResMut<ReplayRecording>,
+    asset_server: Res<AssetServer>,
+    query: Query<(Entity, &TankType, &Team, &PreviousPosition, &PreviousRotation, &TankControl, Option<&Player>), With<Tank>>,
+) {
+    if recording.replay.ticks.is_empty() && query.is_empty() {
+        return;
+    }
+    let recording = &mut *recording;
+    let tick = recording.replay.len();
+
+    let replay = &mut recording.replay;
+    recording.slots.retain(|entity, slot| {
+        let exists = query.contains(*entity);
+        if !exists {
+            replay.tanks[*slot as usize].despawn_tick = Some(tick);
+        }
+        exists
+    });
+
+    let mut changes = Vec::new();
+    for (entity, tank_type, team, position, rotation, control, player) in query.iter() {
+        let slot = match recording.slots.get(&entity) {
+            Some(&slot) => slot,
+            None => {
+                let Some(kind) = TankKind::of(&tank_type.0, &asset_server) else {
+                    warn!("Tank {:?} can not be recorded, its definition was not loaded from a file", entity);
+                    continue;
+                };
+                replay.tanks.push(ReplayTank {
+                    spawn_tick: tick,
+                    despawn_tick: None,
+                    kind,
+                    team: team.0,
+                    player: player.map(|player| player.0),
+                    position: position.0,
+                    rotation: rotation.0,
+                });
+                recording.controls.push(TankControl::default());
+                let slot = (replay.tanks.len() - 1) as u16;
+                recording.slots.insert(entity, slot);
+                slot
+            },
+        };
+        if recording.controls[slot as usize] != *control {
+            recording.controls[slot as usize] = *control;
+            changes.push((slot, *control));
+        }
+    }
+    replay.ticks.push(changes);
+    recording.simulated = true;
+}
+
+/// System to take a keyframe of all tanks, projectiles and props after the physics of every `KEYFRAME_INTERVAL`th recorded tick,
+/// and to save the recording every `SAVE_INTERVAL` ticks. Projectiles of tanks that were not recorded are left out.
+fn record_keyframe(
+    mut recording: ResMut<ReplayRecording>,
+    destroyed_props: Res<DestroyedProps>,
+    states: TankStates,
+    props: PropStates,
+    projectile_query: Query<(&Projectile, &Position, &Velocity), Without<Tank>>,
+) {
+    if !recording.simulated {
+        return;
+    }
+    recording.simulated = false;
+    let tick = recording.replay.len() - 1;
+    if tick % KEYFRAME_INTERVAL != 0 {
+        return;
+    }
+    let mut tanks: Vec<TankFrame> = recording.slots.iter()
+        .filter_map(|(entity, slot)| states.read(*entity, *slot))
+        .collect();
+    tanks.sort_by_key(|frame| frame.slot);
+    let projectiles = projectile_query.iter()
+        .filter_map(|(projectile, position, velocity)| Some(ProjectileFrame {
+            shooter: *recording.slots.get(&projectile.shooter)?,
+            position: position.0,
+            velocity: velocity.0,
+            lifetime: projectile.lifetime,
+            penetration: projectile.penetration,
+            damage: projectile.damage,
+        }))
+        .collect();
+    recording.replay.keyframes.push(Keyframe {
+        tick,
+        tanks,
+        projectiles,
+        destroyed_props: destroyed_props.clone(),
+        damaged_props: props.read(),
+    });
+
+    if tick % SAVE_INTERVAL == 0 {
+        if let Err(error) = recording.replay.save(&recording.path) {
+            warn!("{}", error);
+        }
+    }
+}
+
+/// System to save the recording when the game is closed.
+fn save_recording_on_exit(
+    mut exit_events: EventReader<AppExit>,
+    recording: Res<ReplayRecording>,
+) {
+    if exit_events.read().next().is_none() {
+        return;
+    }
+    match recording.replay.save(&recording.path) {
+        Ok(()) => info!("Saved replay to {}", recording.path.display()),
+        Err(error) => error!("{}", error),
+    }
+}
+
+/// Spawn and despawn the tanks of a replay, so exactly the tanks existing in the physics tick `tick` are there.
+/// Tanks in `keyframe` are spawned in the state they have in it, the others where they were spawned in the match.
+fn sync_tanks(
+    commands: &mut Commands,
+    replay: &Replay,
+    entities: &mut [Option<Entity>],
+    tick: u32,
+    keyframe: Option<&Keyframe>,
+    asset_server: &AssetServer,
+    tank_definitions: &Assets<TankDefinition>,
+) {
+    for (slot, tank) in replay.tanks.iter().enumerate() {
+        match (tank.exists_at(tick), entities[slot]) {
+            (true, None) => {
+                let definition = tank.kind.definition(asset_server);
+                let frame = keyframe.and_then(|keyframe| keyframe.tanks.iter().find(|frame| frame.slot as usize == slot));
+                let (position, rotation) = frame.map_or((tank.position, tank.rotation), |frame| (frame.position, frame.rotation));
+                let entity = spawn_tank(commands, definition.clone(), Team(tank.team), position, rotation);
+                let mut tank_commands = commands.entity(entity);
+                tank_commands.insert((ReplaySlot(slot as u16), ControlSource::Replay, TankControl::default()));
+                if let Some(player) = tank.player {
+                    tank_commands.insert(Player(player));
+                }
+                if let Some(frame) = frame {
+                    tank_commands.insert((Velocity(frame.velocity), Force(frame.force), frame.control));
+                    if !frame.grounded {
+                        tank_commands.remove::<Grounded>();
+                    }
+                    // The health is scaled to the definition when it is applied, so it needs the maximum of the definition.
+                    if let (Some(current), Some(definition)) = (frame.health, tank_definitions.get(&definition)) {
+                        tank_commands.insert(Health {
+                            current,
+                            max: definition.health,
+                        });
+                        if current <= 0.0 {
+                            tank_commands.insert(Wreck);
+                        }
+                    }
+                }
+                entities[slot] = Some(entity);
+            },
+            (false, Some(entity)) => {
+                commands.entity(entity).despawn_recursive();
+                entities[slot] = None;
+            },
+            _ => {},
+        }
+    }
+}
+
+/// System to spawn the tanks of the first tick of the replay once the map and the tank definitions are there,
+/// so the tanks get their definition before their first physics tick.
+/// Without a player tank in the replay the cameras fly freely.
+fn start_playback(
+    mut commands: Commands,
+    mut playback: ResMut<ReplayPlayback>,
+    asset_server: Res<AssetServer>,
+    tank_definitions: Res<Assets<TankDefinition>>,
+    mut camera_query: Query<(&mut CameraMode, &Transform), With<PlayerCamera>>,
+) {
+    if playback.started {
+        return;
+    }
+    let ReplayPlayback { replay, entities, .. } = &mut *playback;
+    sync_tanks(&mut commands, replay, entities, 0, None, &asset_server, &tank_definitions);
+    if !replay.tanks.iter().any(|tank| tank.player.is_some()) {
+        for (mut mode, transform) in camera_query.iter_mut() {
+            let forward = transform.forward();
+            *mode = CameraMode::FreeFly { yaw: forward.x.atan2(forward.z), pitch: forward.y.clamp(-1.0, 1.0).asin() };
+        }
+    }
+    playback.started = true;
+    info!("Playing replay of {} ticks", playback.len());
+}
+
+/// System to pause, speed up and slow down the playback and to move it forward and back.
+/// Speed and pause change the game time, so the physics run as many ticks per second as the speed asks for.
+/// Seeking moves to the keyframe `SEEK_KEYFRAMES` keyframes away from the last one played.
+fn control_playback(
+    mut playback: ResMut<ReplayPlayback>,
+    mut time: ResMut<Time<Virtual>>,
+    input: ActionInput,
+) {
+    if input.just_pressed(InputAction::ReplayPause) {
+        if time.is_paused() {
+            if playback.finished() {
+                playback.seek = Some(0);
+            }
+            time.unpause();
+        } else {
+            time.pause();
+        }
+    }
+    if input.just_pressed(InputAction::ReplayFaster) {
+        let speed = (time.relative_speed() * 2.0).min(MAX_SPEED);
+        time.set_relative_speed(speed);
+    }
+    if input.just_pressed(InputAction::ReplaySlower) {
+        let speed = (time.relative_speed() / 2.0).max(MIN_SPEED);
+        time.set_relative_speed(speed);
+    }
+
+    let current = playback.tick.saturating_sub(1) / KEYFRAME_INTERVAL;
+    let last = (playback.replay.keyframes.len() as u32).saturating_sub(1);
+    if input.just_pressed(InputAction::ReplayForward) {
+        playback.seek = Some((current + SEEK_KEYFRAMES).min(last));
+    }
+    if input.just_pressed(InputAction::ReplayBack) {
+        playback.seek = Some(current.saturating_sub(SEEK_KEYFRAMES));
+    }
+}
+
+/// System to continue the playback from the keyframe chosen by seeking.
+/// Tanks, projectiles in flight and props are set to the keyframe.
+#[allow(clippy::too_many_arguments)]
+fn seek_playback(
+    mut commands: Commands,
+    mut playback: ResMut<ReplayPlayback>,
+    asset_server: Res<AssetServer>,
+    tank_definitions: Res<Assets<TankDefinition>>,
+    projectile_assets: Res<ProjectileAssets>,
+    mut destroyed_props: ResMut<DestroyedProps>,
+    projectile_query: Query<Entity, With<Projectile>>,
+    mut states: TankStates,
+    mut props: PropStates,
+) {
+    let Some(index) = playback.seek.take() else {
+        return;
+    };
+    if !playback.started {
+        return;
+    }
+    let ReplayPlayback { replay, entities, tick, .. } = &mut *playback;
+    let Some(keyframe) = replay.keyframes.get(index as usize) else {
+        return;
+    };
+
+    for projectile in projectile_query.iter() {
+        commands.entity(projectile).despawn_recursive();
+    }
+    *destroyed_props = keyframe.destroyed_props.clone();
+    props.write(&mut commands, &keyframe.destroyed_props, &keyframe.damaged_props);
+
+    for frame in keyframe.tanks.iter() {
+        if let Some(entity) = entities[frame.slot as usize] {
+            states.write(&mut commands, entity, frame, true);
+        }
+    }
+    sync_tanks(&mut commands, replay, entities, keyframe.tick, Some(keyframe), &asset_server, &tank_definitions);
+    for frame in keyframe.projectiles.iter() {
+        let Some(shooter) = entities[frame.shooter as usize] else {
+            continue;
+        };
+        let projectile = Projectile {
+            shooter,
+            lifetime: frame.lifetime,
+            penetration: frame.penetration,
+            damage: frame.damage,
+        };
+        let rotation = Quat::from_rotation_arc(Vec3::Z, frame.velocity.normalize_or_zero());
+        spawn_projectile(&mut commands, &projectile_assets, projectile, frame.position, rotation, frame.velocity);
+    }
+    *tick = keyframe.tick + 1;
+    sync_tanks(&mut commands, replay, entities, *tick, None, &asset_server, &tank_definitions);
+}
+
+/// System to apply the recorded controls of the current tick to the tanks of the replay.
+fn play_controls(
+    mut playback: ResMut<ReplayPlayback>,
+    mut query: Query<(&ReplaySlot, &mut TankControl)>,
+) {
+    if !playback.started || playback.finished() {
+        return;
+    }
+    let changes = &playback.replay.ticks[playback.tick as usize];
+    for (slot, mut control) in query.iter_mut() {
+        if let Some((_, recorded)) = changes.iter().find(|(changed, _)| *changed == slot.0) {
+            *control = *recorded;
+        }
+    }
+    playback.simulated = true;
+}
+
+/// System to finish a tick of the replay after the physics.
+/// The tanks are corrected with the keyframe of the tick, and the tanks of the next tick are spawned.
+/// The game time is paused at the end of the replay.
+fn finish_replay_tick(
+    mut commands: Commands,
+    mut playback: ResMut<ReplayPlayback>,
+    mut time: ResMut<Time<Virtual>>,
+    asset_server: Res<AssetServer>,
+    tank_definitions: Res<Assets<TankDefinition>>,
+    mut states: TankStates,
+) {
+    if !playback.simulated {
+        return;
+    }
+    playback.simulated = false;
+    let ReplayPlayback { replay, entities, tick, .. } = &mut *playback;
+    if *tick % KEYFRAME_INTERVAL == 0 {
+        if let Some(keyframe) = replay.keyframes.get((*tick / KEYFRAME_INTERVAL) as usize) {
+            for frame in keyframe.tanks.iter() {
+                if let Some(entity) = entities[frame.slot as usize] {
+                    states.write(&mut commands, entity, frame, false);
+                }
+            }
+        }
+    }
+    *tick += 1;
+    sync_tanks(&mut commands, replay, entities, *tick, None, &asset_server, &tank_definitions);
+    if playback.finished() {
+        info!("Replay finished");
+        time.pause();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bevy::ecs::system::SystemState;
+    use bevy::input::InputPlugin;
+    use bevy::render::texture::ImagePlugin;
+    use bevy::time::TimeUpdateStrategy;
+
+    use super::*;
+    use crate::ai::AiPlugin;
+    use crate::asset_loader::AssetLoaderPlugin;
+    use crate::collision::CollisionPlugin;
+    use crate::control::ControlPlugin;
+    use crate::damage::DamagePlugin;
+    use crate::input_map::InputMapPlugin;
+    use crate::map::MapPlugin;
+    use crate::map::generator::MapGeneratorSettings;
+    use crate::network::NetworkPlugin;
+    use crate::physics::PhysicsPlugin;
+    use crate::projectile::ProjectilePlugin;
+    use crate::prop::PropPlugin;
+    use crate::schedule::SchedulePlugin;
+    use crate::tank::TankPlugin;
+    use crate::turret::TurretPlugin;
+
+    /// Physics ticks recorded by the tests.
+    const RECORDED_TICKS: u32 = 640;
+
+    /// Physics ticks recorded by the seeking test, long enough for a tank to destroy a prop.
+    const SEEK_RECORDED_TICKS: u32 = 1280;
+
+    /// Updates the test waits for the assets to load and the replay to finish.
+    const MAX_UPDATES: u32 = 20_000;
+
+    /// A small generated map, so the tanks meet quickly.
+    fn map() -> MapSource {
+        MapSource::Generated(MapGeneratorSettings {
+            seed: 3,
+            size: 800.0,
+            ..Default::default()
+        })
+    }
+
+    /// Headless app of a match on `map` with a player tank driving the demo sequence against the AI tanks.
+    /// Every update advances the game time by exactly one physics tick.
+    fn app(map: MapSource, mode: ReplayMode) -> App {
+        let physics = PhysicsPlugin::default();
+        let tick = Duration::from_secs_f64(1.0 / physics.tick_rate);
+        let mut app = App::new();
+        app.add_plugins((
+                MinimalPlugins,
+                TransformPlugin,
+                HierarchyPlugin,
+                InputPlugin,
+                AssetPlugin::default(),
+                ImagePlugin::default(),
+                AssetLoaderPlugin {
+                    render_assets: false,
+                },
+                InputMapPlugin,
+                MapPlugin {
+                    source: map,
+                },
+                SchedulePlugin,
+            ))
+            .add_plugins((
+                physics,
+                CollisionPlugin,
+                TankPlugin,
+                ControlPlugin {
+                    local_players: 1,
+                    demo: true,
+                },
+                TurretPlugin,
+                ProjectilePlugin,
+                DamagePlugin,
+                AiPlugin::default(),
+                PropPlugin,
+                NetworkPlugin::default(),
+                ReplayPlugin {
+                    mode,
+                },
+            ))
+            .init_asset::<Mesh>()
+            .init_asset::<StandardMaterial>()
+            .init_asset::<Scene>()
+            .insert_resource(TimeUpdateStrategy::ManualDuration(tick));
+        app
+    }
+
+    /// The states of the tanks of a replay, by slot.
+    fn tank_frames(app: &mut App, tanks: impl Iterator<Item = (Entity, u16)>) -> Vec<TankFrame> {
+        let mut state = SystemState::<TankStates>::new(&mut app.world);
+        let states = state.get_mut(&mut app.world);
+        let mut frames: Vec<TankFrame> = tanks.filter_map(|(entity, slot)| states.read(entity, slot)).collect();
+        frames.sort_by_key(|frame| frame.slot);
+        frames
+    }
+
+    /// The health of the damaged props.
+    fn damaged_props(app: &mut App) -> Vec<(PropId, f32)> {
+        let mut state = SystemState::<PropStates>::new(&mut app.world);
+        state.get_mut(&mut app.world).read()
+    }
+
+    /// Record a match of `ticks` physics ticks.
+    fn record(ticks: u32) -> App {
+        let path = std::env::temp_dir().join(format!("tank-replay-test-{}.replay", ticks));
+        let mut recording = app(map(), ReplayMode::Record {
+            path,
+            map: map(),
+        });
+        for _ in 0..MAX_UPDATES {
+            recording.update();
+            if recording.world.resource::<ReplayRecording>().replay.len() >= ticks {
+                break;
+            }
+        }
+        assert_eq!(recording.world.resource::<ReplayRecording>().replay.len(), ticks);
+        recording
+    }
+
+    /// The states of the recorded tanks.
+    fn recorded_frames(recording: &mut App) -> Vec<TankFrame> {
+        let slots: Vec<(Entity, u16)> = recording.world.resource::<ReplayRecording>().slots.iter()
+            .map(|(entity, slot)| (*entity, *slot))
+            .collect();
+        tank_frames(recording, slots.into_iter())
+    }
+
+    /// Update the playback until the replay is finished, and return the states of its tanks.
+    fn play_to_end(playback: &mut App) -> Vec<TankFrame> {
+        for _ in 0..MAX_UPDATES {
+            playback.update();
+            if playback.world.resource::<ReplayPlayback>().finished() {
+                break;
+            }
+        }
+        assert!(playback.world.resource::<ReplayPlayback>().finished());
+        let entities = playback.world.resource::<ReplayPlayback>().entities.clone();
+        tank_frames(playback, entities.into_iter().enumerate()
+            .filter_map(|(slot, entity)| Some((entity?, slot as u16))))
+    }
+
+    #[test]
+    fn playback_reproduces_the_recording() {
+        let mut recording = record(RECORDED_TICKS);
+        let replay = recording.world.resource::<ReplayRecording>().replay.clone();
+        let recorded = recorded_frames(&mut recording);
+        let recorded_props = recording.world.resource::<DestroyedProps>().clone();
+        assert!(recorded.len() > 1);
+
+        // Without keyframes the playback can only reproduce the match by simulating it again.
+        let mut playback = app(replay.map.clone(), ReplayMode::Play(Replay {
+            keyframes: Vec::new(),
+            ..replay
+        }));
+        let played = play_to_end(&mut playback);
+
+        assert_eq!(format!("{:?}", played), format!("{:?}", recorded));
+        assert_eq!(*playback.world.resource::<DestroyedProps>(), recorded_props);
+    }
+
+    #[test]
+    fn seeking_back_restores_destroyed_props() {
+        let mut recording = record(SEEK_RECORDED_TICKS);
+        let replay = recording.world.resource::<ReplayRecording>().replay.clone();
+        let recorded = recorded_frames(&mut recording);
+        let recorded_props = recording.world.resource::<DestroyedProps>().clone();
+        let recorded_damage = damaged_props(&mut recording);
+        let destroyed = replay.keyframes.iter()
+            .position(|keyframe| !keyframe.destroyed_props.0.is_empty())
+            .expect("no prop was destroyed in the recording");
+        assert!(destroyed > 0);
+        let last = replay.keyframes.len() as u32 - 1;
+
+        let keyframes = replay.keyframes.clone();
+        let mut playback = app(replay.map.clone(), ReplayMode::Play(replay));
+        for _ in 0..MAX_UPDATES {
+            playback.update();
+            if playback.world.resource::<ReplayPlayback>().started {
+                break;
+            }
+        }
+        // Seek past the destruction of the prop and back to the keyframe before it.
+        playback.world.resource_mut::<ReplayPlayback>().seek = Some(last);
+        playback.update();
+        playback.update();
+        let mut rubble_query = playback.world.query_filtered::<(), With<Rubble>>();
+        assert!(rubble_query.iter(&playback.world).next().is_some());
+        playback.world.resource_mut::<ReplayPlayback>().seek = Some(destroyed as u32 - 1);
+        playback.update();
+
+        // The tanks are corrected with the keyframes, the props have to match them on their own.
+        let mut checked = 0;
+        for _ in 0..MAX_UPDATES {
+            let tick = playback.world.resource::<ReplayPlayback>().tick;
+            if let Some(keyframe) = keyframes.iter().find(|keyframe| keyframe.tick + 1 == tick) {
+                assert_eq!(*playback.world.resource::<DestroyedProps>(), keyframe.destroyed_props, "tick {}", keyframe.tick);
+                assert_eq!(damaged_props(&mut playback), keyframe.damaged_props, "tick {}", keyframe.tick);
+                checked += 1;
+            }
+            if playback.world.resource::<ReplayPlayback>().finished() {
+                break;
+            }
+            playback.update();
+        }
+        assert!(checked > 1);
+        let entities = playback.world.resource::<ReplayPlayback>().entities.clone();
+        let played = tank_frames(&mut playback, entities.into_iter().enumerate()
+            .filter_map(|(slot, entity)| Some((entity?, slot as u16))));
+
+        assert_eq!(format!("{:?}", played), format!("{:?}", recorded));
+        assert_eq!(*playback.world.resource::<DestroyedProps>(), recorded_props);
+        assert_eq!(damaged_props(&mut playback), recorded_damage);
+    }
+}
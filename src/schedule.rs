@@ -6,6 +6,9 @@ use crate::menu::MenuState;
 pub enum ScheduleSet {
     CheckMenu,
     Input,
+    /// Fixed timestep set that turns tank controls into forces before the physics step.
+    Control,
+    /// Fixed timestep set that integrates the physics simulation.
     Physics,
     UpdateWorld,   
     Debug, 
@@ -16,15 +19,19 @@ pub struct SchedulePlugin;
 
 impl Plugin for SchedulePlugin {
     fn build(&self, app: &mut App) {
-        app.configure_sets(Update, (
-            ScheduleSet::CheckMenu,
-            (
-                ScheduleSet::Input,
+        app.add_state::<MenuState>()
+            .configure_sets(Update, (
+                ScheduleSet::CheckMenu,
+                (
+                    ScheduleSet::Input,
+                    ScheduleSet::UpdateWorld,
+                ).chain().run_if(in_state(MenuState::Closed)),
+                ScheduleSet::PauseMenu.run_if(in_state(MenuState::Open)),
+                ScheduleSet::Debug,
+            ).chain())
+            .configure_sets(FixedUpdate, (
+                ScheduleSet::Control,
                 ScheduleSet::Physics,
-                ScheduleSet::UpdateWorld,
-            ).chain().run_if(in_state(MenuState::Closed)),
-            ScheduleSet::PauseMenu.run_if(in_state(MenuState::Open)),
-            ScheduleSet::Debug,
-        ).chain());
+            ).chain().run_if(in_state(MenuState::Closed)));
     }
 }
\ No newline at end of file
@@ -1,106 +1,308 @@
 use bevy::prelude::*;
 
-use crate::{asset_loader::SceneAssets, physics::{Force, Mass, Physics, Position, Rotation, Velocity}};
+use bevy::utils::HashSet;
+use serde::{Deserialize, Serialize};
+
+use crate::{map::SpawnPoints, collision::Collider, physics::{Force, Grounded, Mass, Physics, Position, PreviousPosition, PreviousRotation, Rotation, Velocity}};
+use crate::asset_loader::tank_definitions_loaded;
+use crate::control::{ControlSource, LocalPlayers, ScriptedControl, TankControl};
+use crate::damage::{Armor, Health, Wreck};
+use crate::network::is_authority;
+use crate::replay::not_replaying;
 use crate::schedule::ScheduleSet;
+use crate::tank_definition::{TankDefinition, WeaponDefinition};
+use crate::turret::{spawn_gun, spawn_turret, Gun, Turret};
 
 /// Marker component for Tanks
 #[derive(Component)]
 pub struct Tank;
 
-/// Marker component for the player
+/// Component for the tanks of the local players, with the number of the player.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Player(pub usize);
+
+/// Component to store the team of a tank. Tanks attack tanks of other teams.
+#[derive(Component, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Team(pub u8);
+
+/// Component to store the definition a tank was created from.
+/// All values of the definition are applied to the tank when the definition is loaded or changed.
 #[derive(Component)]
-pub struct Player;
+pub struct TankType(pub Handle<TankDefinition>);
+
+/// Asset path of the definition the player tanks are created from.
+pub const PLAYER_TANK: &str = "tanks/medium.tank.ron";
+
+/// The type of a tank, the asset path of its definition.
+/// Used to exchange the type over the network and to store it in replays instead of the handle of the definition.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TankKind(pub String);
+
+impl TankKind {
+    /// The type of a tank created from `definition`, or `None` if the definition was not loaded from a file.
+    pub fn of(definition: &Handle<TankDefinition>, asset_server: &AssetServer) -> Option<Self> {
+        asset_server.get_path(definition.id()).map(|path| TankKind(path.to_string()))
+    }
+
+    /// The definition of the type. Definitions outside of the `tanks` folder are loaded, unknown paths fail to load with an error.
+    pub fn definition(&self, asset_server: &AssetServer) -> Handle<TankDefinition> {
+        asset_server.load(self.0.clone())
+    }
+}
+
+/// Component to store the engine and handling values of a tank.
+#[derive(Component, Debug)]
+pub struct Engine {
+    pub forward_force: f32,
+    pub reverse_force: f32,
+    pub turn_rate: f32,
+    pub drag: f32,
+}
 
 /// Plugin for the tank system.
 pub struct TankPlugin;
 
+/// System set of the systems that spawn tanks in the `Update` schedule.
+/// The spawned tanks get their definition in the same update, so they are complete before their first physics tick.
+#[derive(SystemSet, Debug, Hash, PartialEq, Eq, Clone)]
+pub struct SpawnTanks;
+
 impl Plugin for TankPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Startup, (
-            spawn_player_tank,
-        ))
-        .add_systems(Update, (
-            player_tank_movement_input,
-            slowdown_player_tank,
-        ).in_set(ScheduleSet::Input))
+        app.add_systems(Update, (
+            spawn_player_tank.in_set(SpawnTanks)
+                .run_if(resource_exists::<SpawnPoints>().and_then(tank_definitions_loaded).and_then(is_authority).and_then(not_replaying)),
+        ).in_set(ScheduleSet::UpdateWorld))
+        .add_systems(FixedUpdate, (
+            apply_tank_control,
+            slowdown_tank,
+        ).in_set(ScheduleSet::Control))
         .add_systems(Update, (
+            apply_deferred.after(SpawnTanks).before(apply_tank_definitions),
+            apply_tank_definitions,
             update_model_pos,                                                                                                                                                                                                                                                                                                                                                                                                                                                                                                                                               
         ).in_set(ScheduleSet::UpdateWorld));
     }
 }
 
-/// System to handle the player tank movement input.
-fn player_tank_movement_input (
-    mut query: Query<(&mut Rotation, &mut Force, &Velocity), With<Player>>,
-    keyboard_input: Res<Input<KeyCode>>,
+/// System to turn the tank controls into forces.
+/// While moving, steering pushes the tank sideways. While standing still without throttle, the tank turns on the spot.
+/// Wrecks can not be driven anymore.
+pub fn apply_tank_control (
+    mut query: Query<(&mut Rotation, &mut Force, &Velocity, &Engine, &TankControl), Without<Wreck>>,
     delta_time: Res<Time>,
 ) {
-    for (mut rotation, mut force, velocity) in query.iter_mut() {
+    for (mut rotation, mut force, velocity, engine, control) in query.iter_mut() {
+        let throttle = control.throttle.clamp(-1.0, 1.0);
+        let steer = control.steer.clamp(-1.0, 1.0);
         if velocity.0.length() > 2.5 {
-            if keyboard_input.pressed(KeyCode::A) {
-                // if driving forward, turn left else turn right
-                force.0 += rotation.0.mul_vec3(Vec3::new(200.0 + velocity.0.length() * 2.0, 0.0, 0.0));
-            }
-            if keyboard_input.pressed(KeyCode::D) {
-                force.0 += rotation.0.mul_vec3(Vec3::new(-200.0 - velocity.0.length() * 2.0, 0.0, 0.0));
-            }
-        } else {
-            if keyboard_input.pressed(KeyCode::A) && !keyboard_input.pressed(KeyCode::W) && !keyboard_input.pressed(KeyCode::S) {
-                rotation.0 *= Quat::from_rotation_y(0.75 * delta_time.delta_seconds());
-            }
-            if keyboard_input.pressed(KeyCode::D) && !keyboard_input.pressed(KeyCode::W) && !keyboard_input.pressed(KeyCode::S){
-                rotation.0 *= Quat::from_rotation_y(-0.75 * delta_time.delta_seconds());
-            }
-        }
-        
-        if keyboard_input.pressed(KeyCode::W) {
-            force.0 += rotation.0.mul_vec3(Vec3::new(0.0, 0.0, 5000.0));
+            // if driving forward, turn left else turn right
+            force.0 += rotation.0.mul_vec3(Vec3::new(steer * (200.0 + velocity.0.length() * 2.0), 0.0, 0.0));
+        } else if throttle == 0.0 {
+            rotation.0 *= Quat::from_rotation_y(steer * engine.turn_rate * delta_time.delta_seconds());
         }
-        if keyboard_input.pressed(KeyCode::S) {
-            force.0 += rotation.0.mul_vec3(Vec3::new(0.0, 0.0, -4000.0));
+
+        if throttle > 0.0 {
+            force.0 += rotation.0.mul_vec3(Vec3::new(0.0, 0.0, throttle * engine.forward_force));
+        } else {
+            force.0 += rotation.0.mul_vec3(Vec3::new(0.0, 0.0, throttle * engine.reverse_force));
         }
     }
 }
 
-/// System to slow down the player tank while moving.
-fn slowdown_player_tank (
-    mut query: Query<(&mut Force, &Velocity, &Mass), With<Player>>,
+/// System to slow down the tanks while moving.
+pub fn slowdown_tank (
+    mut query: Query<(&mut Force, &Velocity, &Mass, &Engine), With<Tank>>,
 ) {
-    for (mut force, velocity, mass) in query.iter_mut() {
+    for (mut force, velocity, mass, engine) in query.iter_mut() {
         if velocity.0.length() > 2.5 {
-            force.0 -= velocity.0 * 2.0 * mass.0;
+            force.0 -= velocity.0 * engine.drag * mass.0;
         }
     }
 }
 
 /// System to update the model position based on the physics position and rotation.
-fn update_model_pos(
-    mut query: Query<(&Position, &Rotation, &mut Transform), With<Tank>>
+/// The model is interpolated between the last two physics ticks so it moves smoothly
+/// when the framerate is higher than the physics tick rate.
+pub fn update_model_pos(
+    mut query: Query<(&Position, &Rotation, &PreviousPosition, &PreviousRotation, &mut Transform), With<Tank>>,
+    fixed_time: Res<Time<Fixed>>,
 ) {
-    for (position, rotation, mut transform) in query.iter_mut() {
-        transform.translation = position.0;
-        transform.rotation = rotation.0;
+    let alpha = fixed_time.overstep_percentage();
+    for (position, rotation, previous_position, previous_rotation, mut transform) in query.iter_mut() {
+        transform.translation = previous_position.0.lerp(position.0, alpha);
+        transform.rotation = previous_rotation.0.slerp(rotation.0, alpha);
     }
 }
 
-/// System to spawn the player tank.
-/// This system is run once at the start of the game.
-/// It spawns the player tank with the `Tank`, `Player`, 'Physics', and `SceneBundle` components.
-fn spawn_player_tank (
+/// System to apply tank definitions to the tanks created from them.
+/// Runs for newly spawned tanks and for all tanks of a type whenever its definition is loaded or modified,
+/// so changes to the definition files take effect without restarting the game.
+#[allow(clippy::type_complexity)]
+fn apply_tank_definitions(
     mut commands: Commands,
-    assets: Res<SceneAssets>
+    mut asset_events: EventReader<AssetEvent<TankDefinition>>,
+    definitions: Res<Assets<TankDefinition>>,
+    mut tank_query: Query<(Entity, Ref<TankType>, &mut Mass, &mut Collider, &mut Handle<Scene>, Option<&Health>, &Children)>,
+    mut turret_query: Query<(Entity, &mut Turret, Option<&Children>)>,
+    mut gun_query: Query<&mut Gun>,
 ) {
+    let changed: HashSet<AssetId<TankDefinition>> = asset_events.read()
+        .filter_map(|event| match event {
+            AssetEvent::LoadedWithDependencies { id } | AssetEvent::Modified { id } => Some(*id),
+            _ => None,
+        })
+        .collect();
+
+    for (entity, tank_type, mut mass, mut collider, mut scene, health, children) in tank_query.iter_mut() {
+        if !tank_type.is_added() && !changed.contains(&tank_type.0.id()) {
+            continue;
+        }
+        let Some(definition) = definitions.get(&tank_type.0) else {
+            continue;
+        };
+
+        mass.0 = definition.mass;
+        *collider = Collider::cuboid(Vec3::from(definition.hull.half_extents))
+            .with_offset(Vec3::from(definition.hull.offset));
+        if *scene != definition.scene {
+            *scene = definition.scene.clone();
+        }
+        let health = match health {
+            Some(health) => Health {
+                current: health.current / health.max * definition.health,
+                max: definition.health,
+            },
+            None => Health::new(definition.health),
+        };
+        commands.entity(entity).insert((
+            Engine {
+                forward_force: definition.forward_force,
+                reverse_force: definition.reverse_force,
+                turn_rate: definition.turn_rate,
+                drag: definition.drag,
+            },
+            Armor {
+                front: definition.armor.front,
+                side: definition.armor.side,
+                rear: definition.armor.rear,
+            },
+            health,
+        ));
+
+        for child in children.iter() {
+            let Ok((turret_entity, mut turret, turret_children)) = turret_query.get_mut(*child) else {
+                continue;
+            };
+            turret.traverse_speed = definition.turret.traverse_speed;
+
+            let guns: Vec<Entity> = turret_children
+                .map(|children| children.iter().copied().filter(|child| gun_query.contains(*child)).collect())
+                .unwrap_or_default();
+            for (i, weapon) in definition.weapons.iter().enumerate() {
+                match guns.get(i).and_then(|gun| gun_query.get_mut(*gun).ok()) {
+                    Some(mut gun) => apply_weapon_definition(&mut gun, weapon),
+                    None => {
+                        let gun = Gun::new(weapon.min_pitch, weapon.max_pitch, weapon.elevation_speed)
+                            .with_ballistics(weapon.muzzle_velocity, weapon.reload_time)
+                            .with_shell(weapon.penetration, weapon.damage);
+                        commands.entity(turret_entity).with_children(|turret| spawn_gun(turret, gun));
+                    },
+                }
+            }
+            for gun in guns.iter().skip(definition.weapons.len()) {
+                commands.entity(*gun).despawn_recursive();
+            }
+        }
+    }
+}
+
+/// Apply the values of a weapon definition to a gun, keeping its current aim and reload state.
+fn apply_weapon_definition(gun: &mut Gun, weapon: &WeaponDefinition) {
+    gun.min_pitch = weapon.min_pitch;
+    gun.max_pitch = weapon.max_pitch;
+    gun.elevation_speed = weapon.elevation_speed;
+    gun.muzzle_velocity = weapon.muzzle_velocity;
+    gun.reload_time = weapon.reload_time;
+    gun.penetration = weapon.penetration;
+    gun.damage = weapon.damage;
+}
+
+/// Spawn a tank of the given type and team at `position`.
+/// The values of the tank definition are applied once the definition is loaded.
+pub fn spawn_tank(
+    commands: &mut Commands,
+    definition: Handle<TankDefinition>,
+    team: Team,
+    position: Vec3,
+    rotation: Quat,
+) -> Entity {
     commands.spawn((
         Tank,
-        Player,
+        TankType(definition),
+        team,
+        Grounded,
         Physics {
-            mass: Mass(100.0),
+            position: Position(position),
+            rotation: Rotation(rotation),
+            previous_position: PreviousPosition(position),
+            previous_rotation: PreviousRotation(rotation),
+            ..Default::default()
+        },
+        TankControl {
+            aim: position + rotation.mul_vec3(Vec3::new(0.0, 0.0, 100.0)),
             ..Default::default()
         },
         SceneBundle {
-            scene: assets.tank.clone(),
-            transform: Transform::from_xyz(0.0, 0.0, 0.0),
+            transform: Transform::from_translation(position).with_rotation(rotation),
             ..Default::default()
         },
-    ));
-}
\ No newline at end of file
+    ))
+    .with_children(|tank| {
+        spawn_turret(tank, Turret::new(0.0));
+    })
+    .id()
+}
+
+/// Position and rotation of the tank of a player on the spawn points of team 0.
+/// Players sharing a spawn point are placed next to each other.
+pub fn player_spawn_transform(spawn_points: &SpawnPoints, index: usize) -> (Vec3, Quat) {
+    let team_spawn_points: Vec<_> = spawn_points.for_team(Team(0)).collect();
+    match team_spawn_points.get(index % team_spawn_points.len().max(1)) {
+        Some(spawn_point) => {
+            let row = (index / team_spawn_points.len()) as f32;
+            (spawn_point.position + spawn_point.rotation.mul_vec3(Vec3::X * row * 15.0), spawn_point.rotation)
+        },
+        None => {
+            warn!("Map has no spawn point for team 0, spawning the player at the origin");
+            (Vec3::new(index as f32 * 20.0, 0.0, 0.0), Quat::IDENTITY)
+        },
+    }
+}
+
+/// System to spawn the player tanks.
+/// This system runs once when both the spawn points and the tank definitions are available,
+/// so the tanks get their definition before their first physics tick.
+/// It spawns a `PLAYER_TANK` with the `Player` component for every local player, controlled with the device of the player
+/// or by the demo sequence, at the spawn points of team 0.
+fn spawn_player_tank (
+    mut commands: Commands,
+    mut spawned: Local<bool>,
+    asset_server: Res<AssetServer>,
+    spawn_points: Res<SpawnPoints>,
+    local_players: Res<LocalPlayers>,
+) {
+    if *spawned {
+        return;
+    }
+    *spawned = true;
+    for (index, source) in local_players.sources.iter().enumerate() {
+        let (position, rotation) = player_spawn_transform(&spawn_points, index);
+        let tank = spawn_tank(&mut commands, asset_server.load(PLAYER_TANK), Team(0), position, rotation);
+        if local_players.demo {
+            commands.entity(tank).insert((Player(index), ControlSource::Scripted, ScriptedControl::demo()));
+        } else {
+            commands.entity(tank).insert((Player(index), *source));
+        }
+    }
+}
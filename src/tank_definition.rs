@@ -0,0 +1,111 @@
+use bevy::asset::io::Reader;
+use bevy::asset::{AssetLoader, AsyncReadExt, LoadContext};
+use bevy::prelude::*;
+use bevy::reflect::TypePath;
+use bevy::utils::BoxedFuture;
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Asset describing a type of tank. Loaded from `.tank.ron` files.
+/// The `scene` is loaded from the `model` path when the definition is loaded.
+#[derive(Asset, TypePath, Debug, Deserialize)]
+pub struct TankDefinition {
+    pub name: String,
+    pub model: String,
+    #[serde(skip)]
+    #[dependency]
+    pub scene: Handle<Scene>,
+    pub mass: f32,
+    pub forward_force: f32,
+    pub reverse_force: f32,
+    pub turn_rate: f32,
+    pub drag: f32,
+    pub health: f32,
+    pub hull: HullDefinition,
+    pub armor: ArmorDefinition,
+    pub turret: TurretDefinition,
+    pub weapons: Vec<WeaponDefinition>,
+}
+
+/// Size of the hull collider of a tank.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct HullDefinition {
+    pub half_extents: [f32; 3],
+    pub offset: [f32; 3],
+}
+
+/// Armor thickness on each side of the hull.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct ArmorDefinition {
+    pub front: f32,
+    pub side: f32,
+    pub rear: f32,
+}
+
+/// Turret of a tank.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct TurretDefinition {
+    pub traverse_speed: f32,
+}
+
+/// Gun mounted on the turret of a tank.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct WeaponDefinition {
+    pub min_pitch: f32,
+    pub max_pitch: f32,
+    pub elevation_speed: f32,
+    pub muzzle_velocity: f32,
+    pub reload_time: f32,
+    pub penetration: f32,
+    pub damage: f32,
+}
+
+/// Errors that can occur while loading a tank definition.
+#[derive(Debug, Error)]
+pub enum TankDefinitionLoaderError {
+    #[error("Could not read tank definition: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Could not parse tank definition: {0}")]
+    Ron(#[from] ron::error::SpannedError),
+}
+
+/// Asset loader for `TankDefinition` assets.
+/// Without `load_models` the scene of the tank is not loaded, e.g. on the headless server that has no renderer.
+pub struct TankDefinitionLoader {
+    pub load_models: bool,
+}
+
+impl Default for TankDefinitionLoader {
+    fn default() -> Self {
+        TankDefinitionLoader {
+            load_models: true,
+        }
+    }
+}
+
+impl AssetLoader for TankDefinitionLoader {
+    type Asset = TankDefinition;
+    type Settings = ();
+    type Error = TankDefinitionLoaderError;
+
+    fn load<'a>(
+        &'a self,
+        reader: &'a mut Reader,
+        _settings: &'a (),
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<Self::Asset, Self::Error>> {
+        Box::pin(async move {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).await?;
+            let mut definition: TankDefinition = ron::de::from_bytes(&bytes)?;
+            if self.load_models {
+                definition.scene = load_context.load(definition.model.clone());
+            }
+            Ok(definition)
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tank.ron"]
+    }
+}
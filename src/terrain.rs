@@ -0,0 +1,211 @@
+use bevy::prelude::*;
+use bevy::render::mesh::Indices;
+use bevy::render::render_resource::PrimitiveTopology;
+
+use crate::collision::RayHit;
+
+/// Steepest slope a tank can drive up, as the ratio of height to distance.
+pub const MAX_DRIVABLE_SLOPE: f32 = 0.6;
+
+/// Resource to store the heightmap of the terrain.
+/// The heightmap is a square grid of `size` x `size` heights with `spacing` between two points, centered at the origin.
+/// Heights between the grid points are interpolated bilinearly.
+#[derive(Resource, Debug, Clone)]
+pub struct Terrain {
+    pub size: usize,
+    pub spacing: f32,
+    pub heights: Vec<f32>,
+}
+
+impl Terrain {
+    /// Terrain without any hills.
+    pub fn flat(size: usize, spacing: f32) -> Self {
+        Terrain {
+            size,
+            spacing,
+            heights: vec![0.0; size * size],
+        }
+    }
+
+    /// Terrain generated from fractal value noise.
+    /// The heights are in the range `0.0..=amplitude`, the same seed always gives the same terrain.
+    pub fn from_noise(size: usize, spacing: f32, amplitude: f32, seed: u32) -> Self {
+        let mut heights = Vec::with_capacity(size * size);
+        for j in 0..size {
+            for i in 0..size {
+                let x = i as f32 / 32.0;
+                let z = j as f32 / 32.0;
+                heights.push(fractal_noise(x, z, seed) * amplitude);
+            }
+        }
+        Terrain {
+            size,
+            spacing,
+            heights,
+        }
+    }
+
+    /// Terrain from a grayscale image, stretched to `world_size` on each side. Black is at height zero, white at `amplitude`.
+    /// The image has to be square, only the first byte of every pixel is used.
+    pub fn from_image(image: &Image, world_size: f32, amplitude: f32) -> Self {
+        let size = image.texture_descriptor.size.width as usize;
+        let pixel_size = (image.data.len() / (size * size).max(1)).max(1);
+        let heights = (0..size * size)
+            .map(|i| image.data.get(i * pixel_size).copied().unwrap_or(0) as f32 / 255.0 * amplitude)
+            .collect();
+        Terrain {
+            size,
+            spacing: world_size / (size.max(2) - 1) as f32,
+            heights,
+        }
+    }
+
+    /// Distance from the center of the terrain to its edges.
+    pub fn half_extent(&self) -> f32 {
+        (self.size.max(1) - 1) as f32 * self.spacing / 2.0
+    }
+
+    /// Height of a grid point. Points outside of the grid use the height of the closest edge.
+    pub fn grid_height(&self, i: isize, j: isize) -> f32 {
+        if self.size == 0 {
+            return 0.0;
+        }
+        let max = self.size as isize - 1;
+        let i = i.clamp(0, max) as usize;
+        let j = j.clamp(0, max) as usize;
+        self.heights[j * self.size + i]
+    }
+
+    /// Height of the terrain at a point in world space.
+    pub fn height_at(&self, x: f32, z: f32) -> f32 {
+        let gx = (x + self.half_extent()) / self.spacing;
+        let gz = (z + self.half_extent()) / self.spacing;
+        let i = gx.floor();
+        let j = gz.floor();
+        let tx = gx - i;
+        let tz = gz - j;
+        let (i, j) = (i as isize, j as isize);
+        let top = self.grid_height(i, j) * (1.0 - tx) + self.grid_height(i + 1, j) * tx;
+        let bottom = self.grid_height(i, j + 1) * (1.0 - tx) + self.grid_height(i + 1, j + 1) * tx;
+        top * (1.0 - tz) + bottom * tz
+    }
+
+    /// Normal of the terrain surface at a point in world space.
+    pub fn normal_at(&self, x: f32, z: f32) -> Vec3 {
+        let d = self.spacing;
+        let dx = self.height_at(x + d, z) - self.height_at(x - d, z);
+        let dz = self.height_at(x, z + d) - self.height_at(x, z - d);
+        Vec3::new(-dx, 2.0 * d, -dz).normalize()
+    }
+
+    /// Cast a ray against the terrain.
+    /// The ray is marched in steps of half the grid spacing and the hit is refined with a binary search.
+    pub fn raycast(&self, origin: Vec3, direction: Vec3, max_distance: f32) -> Option<RayHit> {
+        let above = |distance: f32| {
+            let point = origin + direction * distance;
+            point.y - self.height_at(point.x, point.z)
+        };
+        if above(0.0) < 0.0 {
+            return None;
+        }
+        let step = self.spacing / 2.0;
+        let mut previous = 0.0;
+        let mut distance = step.min(max_distance);
+        loop {
+            if above(distance) < 0.0 {
+                let (mut low, mut high) = (previous, distance);
+                for _ in 0..16 {
+                    let mid = (low + high) / 2.0;
+                    if above(mid) < 0.0 {
+                        high = mid;
+                    } else {
+                        low = mid;
+                    }
+                }
+                let point = origin + direction * high;
+                return Some(RayHit {
+                    distance: high,
+                    point,
+                    normal: self.normal_at(point.x, point.z),
+                });
+            }
+            if distance >= max_distance {
+                return None;
+            }
+            previous = distance;
+            distance = (distance + step).min(max_distance);
+        }
+    }
+
+    /// Build the mesh of the terrain.
+    pub fn mesh(&self) -> Mesh {
+        let half_extent = self.half_extent();
+        let mut positions = Vec::with_capacity(self.size * self.size);
+        let mut normals = Vec::with_capacity(self.size * self.size);
+        let mut uvs = Vec::with_capacity(self.size * self.size);
+        for j in 0..self.size {
+            for i in 0..self.size {
+                let x = i as f32 * self.spacing - half_extent;
+                let z = j as f32 * self.spacing - half_extent;
+                positions.push([x, self.grid_height(i as isize, j as isize), z]);
+                normals.push(self.normal_at(x, z).to_array());
+                uvs.push([i as f32 / 4.0, j as f32 / 4.0]);
+            }
+        }
+
+        let mut indices = Vec::with_capacity(self.size.saturating_sub(1).pow(2) * 6);
+        for j in 0..self.size.saturating_sub(1) {
+            for i in 0..self.size.saturating_sub(1) {
+                let a = (j * self.size + i) as u32;
+                let b = a + 1;
+                let c = a + self.size as u32;
+                let d = c + 1;
+                indices.extend_from_slice(&[a, c, b, b, c, d]);
+            }
+        }
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+        mesh.set_indices(Some(Indices::U32(indices)));
+        mesh
+    }
+}
+
+/// Random value in the range `0.0..1.0` for a grid point of the noise.
+fn hash(x: i32, z: i32, seed: u32) -> f32 {
+    let mut h = seed
+        .wrapping_add((x as u32).wrapping_mul(374761393))
+        .wrapping_add((z as u32).wrapping_mul(668265263));
+    h = (h ^ (h >> 13)).wrapping_mul(1274126177);
+    h ^= h >> 16;
+    h as f32 / u32::MAX as f32
+}
+
+/// Smoothly interpolated value noise in the range `0.0..1.0`.
+fn value_noise(x: f32, z: f32, seed: u32) -> f32 {
+    let (i, j) = (x.floor() as i32, z.floor() as i32);
+    let tx = x - x.floor();
+    let tz = z - z.floor();
+    let tx = tx * tx * (3.0 - 2.0 * tx);
+    let tz = tz * tz * (3.0 - 2.0 * tz);
+    let top = hash(i, j, seed) * (1.0 - tx) + hash(i + 1, j, seed) * tx;
+    let bottom = hash(i, j + 1, seed) * (1.0 - tx) + hash(i + 1, j + 1, seed) * tx;
+    top * (1.0 - tz) + bottom * tz
+}
+
+/// Four octaves of value noise, normalized to the range `0.0..1.0`.
+fn fractal_noise(x: f32, z: f32, seed: u32) -> f32 {
+    let mut value = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    let mut total = 0.0;
+    for octave in 0..4 {
+        value += value_noise(x * frequency, z * frequency, seed.wrapping_add(octave)) * amplitude;
+        total += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+    value / total
+}
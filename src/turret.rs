@@ -0,0 +1,246 @@
+use bevy::prelude::*;
+
+use crate::physics::{wrap_angle, Position, Rotation};
+use crate::schedule::ScheduleSet;
+use crate::damage::Wreck;
+use crate::control::TankControl;
+use crate::tank::{apply_tank_control, Tank};
+
+/// Name of the turret node in the tank model.
+const TURRET_NODE_NAME: &str = "Cube.003";
+
+/// Name of the gun barrel node in the tank model.
+const GUN_NODE_NAME: &str = "Cylinder";
+
+/// Offset of the turret pivot relative to the tank position.
+pub const TURRET_OFFSET: Vec3 = Vec3::new(0.27, 5.06, -1.37);
+
+/// Offset of the gun pivot relative to the turret pivot.
+pub const GUN_OFFSET: Vec3 = Vec3::new(0.0, -1.0, 2.75);
+
+/// Distance from the gun pivot to the muzzle.
+pub const MUZZLE_LENGTH: f32 = 9.0;
+
+/// Component for the turret of a tank. The turret is a child entity of the tank.
+/// The yaw is relative to the hull and the turret turns towards `target_yaw` with `traverse_speed` radians per second.
+#[derive(Component, Debug)]
+pub struct Turret {
+    pub yaw: f32,
+    pub target_yaw: f32,
+    pub traverse_speed: f32,
+}
+
+impl Turret {
+    pub fn new(traverse_speed: f32) -> Self {
+        Turret {
+            yaw: 0.0,
+            target_yaw: 0.0,
+            traverse_speed,
+        }
+    }
+}
+
+/// Component for the gun of a tank. The gun is a child entity of the turret.
+/// The pitch is relative to the turret and limited to `min_pitch..=max_pitch`.
+/// While the tank fires, the gun fires a projectile with `muzzle_velocity` every `reload_time` seconds.
+/// The projectile can go through `penetration` thick armor and deals `damage` when it does.
+#[derive(Component, Debug)]
+pub struct Gun {
+    pub pitch: f32,
+    pub target_pitch: f32,
+    pub min_pitch: f32,
+    pub max_pitch: f32,
+    pub elevation_speed: f32,
+    pub muzzle_velocity: f32,
+    pub reload_time: f32,
+    pub reload_remaining: f32,
+    pub penetration: f32,
+    pub damage: f32,
+}
+
+impl Gun {
+    pub fn new(min_pitch: f32, max_pitch: f32, elevation_speed: f32) -> Self {
+        Gun {
+            pitch: 0.0,
+            target_pitch: 0.0,
+            min_pitch,
+            max_pitch,
+            elevation_speed,
+            muzzle_velocity: 250.0,
+            reload_time: 3.0,
+            reload_remaining: 0.0,
+            penetration: 120.0,
+            damage: 250.0,
+        }
+    }
+
+    pub fn with_ballistics(mut self, muzzle_velocity: f32, reload_time: f32) -> Self {
+        self.muzzle_velocity = muzzle_velocity;
+        self.reload_time = reload_time;
+        self
+    }
+
+    pub fn with_shell(mut self, penetration: f32, damage: f32) -> Self {
+        self.penetration = penetration;
+        self.damage = damage;
+        self
+    }
+}
+
+/// Component linking a node of the tank model to the turret entity that drives it.
+/// Stores the original rotation of the node.
+#[derive(Component)]
+pub struct TurretModel {
+    pub turret: Entity,
+    pub base_rotation: Quat,
+}
+
+/// Component linking a node of the tank model to the gun entity that drives it.
+/// Stores the original rotation of the node.
+#[derive(Component)]
+pub struct GunModel {
+    pub gun: Entity,
+    pub base_rotation: Quat,
+}
+
+/// Plugin for turrets and guns.
+/// The turrets aim after all controls of the physics tick were produced, so they follow the aim of the same tick.
+pub struct TurretPlugin;
+
+impl Plugin for TurretPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(FixedUpdate, (
+            update_aim_angles,
+            traverse_turret,
+        ).chain().after(apply_tank_control).in_set(ScheduleSet::Control))
+        .add_systems(Update, (
+            bind_turret_models,
+            update_turret_models,
+        ).chain().in_set(ScheduleSet::UpdateWorld));
+    }
+}
+
+/// Position of the muzzle and direction of the barrel in world space
+/// for a tank at `position` with `rotation`.
+pub fn muzzle(position: Vec3, rotation: Quat, turret: &Turret, gun: &Gun) -> (Vec3, Vec3) {
+    let turret_rotation = rotation * Quat::from_rotation_y(turret.yaw);
+    let gun_rotation = turret_rotation * Quat::from_rotation_x(-gun.pitch);
+    let pivot = position + rotation.mul_vec3(TURRET_OFFSET) + turret_rotation.mul_vec3(GUN_OFFSET);
+    let direction = gun_rotation.mul_vec3(Vec3::Z);
+    (pivot + direction * MUZZLE_LENGTH, direction)
+}
+
+/// Spawn a turret entity as a child of a tank.
+pub fn spawn_turret(tank: &mut ChildBuilder, turret: Turret) {
+    tank.spawn((
+        turret,
+        SpatialBundle::from_transform(Transform::from_translation(TURRET_OFFSET)),
+    ));
+}
+
+/// Spawn a gun entity as a child of a turret.
+pub fn spawn_gun(turret: &mut ChildBuilder, gun: Gun) {
+    turret.spawn((
+        gun,
+        SpatialBundle::from_transform(Transform::from_translation(GUN_OFFSET)),
+    ));
+}
+
+/// System to compute the target yaw of the turret and the target pitch of the gun from the aim point of the tank control.
+#[allow(clippy::type_complexity)]
+fn update_aim_angles(
+    tank_query: Query<(&Position, &Rotation, &TankControl, &Children), (With<Tank>, Without<Wreck>)>,
+    mut turret_query: Query<(&mut Turret, &Children)>,
+    mut gun_query: Query<&mut Gun>,
+) {
+    for (position, rotation, control, children) in tank_query.iter() {
+        let local_target = rotation.0.inverse().mul_vec3(control.aim - position.0) - TURRET_OFFSET;
+        for child in children.iter() {
+            let Ok((mut turret, turret_children)) = turret_query.get_mut(*child) else {
+                continue;
+            };
+            turret.target_yaw = local_target.x.atan2(local_target.z);
+            for gun_entity in turret_children.iter() {
+                if let Ok(mut gun) = gun_query.get_mut(*gun_entity) {
+                    let horizontal_distance = Vec2::new(local_target.x, local_target.z).length();
+                    gun.target_pitch = (local_target.y - GUN_OFFSET.y).atan2(horizontal_distance);
+                }
+            }
+        }
+    }
+}
+
+/// System to turn the turrets and elevate the guns towards their target angles.
+/// Turrets turn with their traverse speed, guns are clamped to their pitch limits.
+pub fn traverse_turret(
+    mut turret_query: Query<(&mut Turret, &mut Transform), Without<Gun>>,
+    mut gun_query: Query<(&mut Gun, &mut Transform), Without<Turret>>,
+    time: Res<Time>,
+) {
+    for (mut turret, mut transform) in turret_query.iter_mut() {
+        let difference = wrap_angle(turret.target_yaw - turret.yaw);
+        let step = turret.traverse_speed * time.delta_seconds();
+        turret.yaw = wrap_angle(turret.yaw + difference.clamp(-step, step));
+        transform.rotation = Quat::from_rotation_y(turret.yaw);
+    }
+    for (mut gun, mut transform) in gun_query.iter_mut() {
+        let target_pitch = gun.target_pitch.clamp(gun.min_pitch, gun.max_pitch);
+        let step = gun.elevation_speed * time.delta_seconds();
+        gun.pitch += (target_pitch - gun.pitch).clamp(-step, step);
+        transform.rotation = Quat::from_rotation_x(-gun.pitch);
+    }
+}
+
+/// System to link the turret and gun nodes of newly spawned tank models to the turret and gun entities of their tank.
+#[allow(clippy::type_complexity)]
+fn bind_turret_models(
+    mut commands: Commands,
+    name_query: Query<(Entity, &Name, &Transform), (Added<Name>, Without<Handle<Mesh>>)>,
+    parent_query: Query<&Parent>,
+    tank_query: Query<&Children, With<Tank>>,
+    turret_query: Query<&Children, With<Turret>>,
+    gun_query: Query<Entity, With<Gun>>,
+) {
+    for (entity, name, transform) in name_query.iter() {
+        if name.as_str() != TURRET_NODE_NAME && name.as_str() != GUN_NODE_NAME {
+            continue;
+        }
+        let Some(tank_children) = parent_query.iter_ancestors(entity).find_map(|ancestor| tank_query.get(ancestor).ok()) else {
+            continue;
+        };
+        let Some((turret, turret_children)) = tank_children.iter().find_map(|child| turret_query.get(*child).ok().map(|c| (*child, c))) else {
+            continue;
+        };
+        if name.as_str() == TURRET_NODE_NAME {
+            commands.entity(entity).insert(TurretModel {
+                turret,
+                base_rotation: transform.rotation,
+            });
+        } else if let Some(gun) = turret_children.iter().find_map(|child| gun_query.get(*child).ok()) {
+            commands.entity(entity).insert(GunModel {
+                gun,
+                base_rotation: transform.rotation,
+            });
+        }
+    }
+}
+
+/// System to rotate the turret and gun nodes of the tank models.
+fn update_turret_models(
+    mut turret_model_query: Query<(&TurretModel, &mut Transform), Without<GunModel>>,
+    mut gun_model_query: Query<(&GunModel, &mut Transform), Without<TurretModel>>,
+    turret_query: Query<&Turret>,
+    gun_query: Query<&Gun>,
+) {
+    for (model, mut transform) in turret_model_query.iter_mut() {
+        if let Ok(turret) = turret_query.get(model.turret) {
+            transform.rotation = Quat::from_rotation_y(turret.yaw) * model.base_rotation;
+        }
+    }
+    for (model, mut transform) in gun_model_query.iter_mut() {
+        if let Ok(gun) = gun_query.get(model.gun) {
+            transform.rotation = Quat::from_rotation_x(-gun.pitch) * model.base_rotation;
+        }
+    }
+}
+
@@ -1,28 +1,293 @@
 use bevy::prelude::*;
 use bevy_egui::{EguiContexts, egui};
 
-use crate::physics::{Mass, Position, Rotation, Velocity};
+use crate::asset_loader::FontAssets;
+use crate::camera::{PlayerCamera, Zoom};
+use crate::damage::Health;
+use crate::map::OutOfBounds;
+use crate::physics::{Mass, Position, Rotation, Velocity, GRAVITY};
+use crate::replay::ReplayPlayback;
 use crate::tank::Player;
+use crate::turret::{Gun, Turret};
 use crate::schedule::ScheduleSet;
 
 pub struct UIPlugin;
 
+/// Ranges in meters marked on the reticle of the gunner sight.
+const RANGE_MARKS: [f32; 5] = [200.0, 400.0, 600.0, 800.0, 1000.0];
+
+/// Color of the reticle of the gunner sight.
+const RETICLE_COLOR: Color = Color::rgb(0.9, 0.1, 0.1);
+
+/// Marker component for the warning shown while the player is outside of the playable area.
+#[derive(Component)]
+pub struct OutOfBoundsWarning;
+
+/// Marker component for the reticle shown while looking through the gunner sight.
+#[derive(Component)]
+pub struct SightReticle;
+
+/// Marker component for the progress, speed and state of a replay being played.
+#[derive(Component)]
+pub struct ReplayStatus;
+
+/// Component for a range marking of the reticle.
+/// The marking is placed where a target at `0` meters has to be put to be hit by the gun of the player.
+#[derive(Component)]
+pub struct RangeMark(pub f32);
+
 impl Plugin for UIPlugin {
     fn build(&self, app: &mut App) {
-        app.add_systems(Update, (
+        app.add_systems(Startup, (
+            spawn_out_of_bounds_warning,
+            spawn_sight_reticle,
+            spawn_replay_status.run_if(resource_exists::<ReplayPlayback>()),
+        ))
+        .add_systems(Update, (
+            update_out_of_bounds_warning,
+            update_sight_reticle,
+            update_replay_status.run_if(resource_exists::<ReplayPlayback>()),
+        ).in_set(ScheduleSet::UpdateWorld))
+        .add_systems(Update, (
             ui_example_system,
         ).in_set(ScheduleSet::Debug));
     }
 }
 
+/// System to spawn the hidden out of bounds warning at the top of the screen.
+fn spawn_out_of_bounds_warning(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+) {
+    commands.spawn((
+        OutOfBoundsWarning,
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font: font_assets.menu_font.clone(),
+                font_size: 40.0,
+                color: Color::RED,
+            },
+        ).with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(40.0),
+            width: Val::Percent(100.0),
+            justify_content: JustifyContent::Center,
+            ..default()
+        }).with_text_alignment(TextAlignment::Center),
+    )).insert(Visibility::Hidden);
+}
+
+/// System to show the countdown while the player is outside of the playable area.
+fn update_out_of_bounds_warning(
+    player_query: Query<Option<&OutOfBounds>, With<Player>>,
+    mut warning_query: Query<(&mut Text, &mut Visibility), With<OutOfBoundsWarning>>,
+) {
+    let out_of_bounds = player_query.iter().flatten().next();
+    for (mut text, mut visibility) in warning_query.iter_mut() {
+        match out_of_bounds {
+            Some(out_of_bounds) => {
+                text.sections[0].value = format!("Zurück ins Kampfgebiet! {:.0}", out_of_bounds.remaining.ceil());
+                *visibility = Visibility::Visible;
+            },
+            None => *visibility = Visibility::Hidden,
+        }
+    }
+}
+
+/// System to spawn the status of the replay at the bottom of the screen.
+fn spawn_replay_status(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+) {
+    commands.spawn((
+        ReplayStatus,
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font: font_assets.menu_font.clone(),
+                font_size: 24.0,
+                color: Color::WHITE,
+            },
+        ).with_style(Style {
+            position_type: PositionType::Absolute,
+            bottom: Val::Px(20.0),
+            width: Val::Percent(100.0),
+            justify_content: JustifyContent::Center,
+            ..default()
+        }).with_text_alignment(TextAlignment::Center),
+    ));
+}
+
+/// System to show the played and total time of the replay, its speed and whether it is paused.
+fn update_replay_status(
+    playback: Res<ReplayPlayback>,
+    time: Res<Time<Virtual>>,
+    fixed_time: Res<Time<Fixed>>,
+    mut query: Query<&mut Text, With<ReplayStatus>>,
+) {
+    let clock = |ticks: u32| {
+        let seconds = (ticks as f32 * fixed_time.timestep().as_secs_f32()) as u32;
+        format!("{}:{:02}", seconds / 60, seconds % 60)
+    };
+    let state = match (time.is_paused(), playback.finished()) {
+        (true, true) => " - Ende",
+        (true, false) => " - Angehalten",
+        (false, _) => "",
+    };
+    for mut text in query.iter_mut() {
+        text.sections[0].value = format!(
+            "Wiedergabe {} / {}  {}x{}",
+            clock(playback.tick.min(playback.len())),
+            clock(playback.len()),
+            time.relative_speed(),
+            state,
+        );
+    }
+}
+
+/// System to spawn the hidden reticle of the gunner sight.
+/// The reticle is a cross in the center of the screen with range markings below it.
+fn spawn_sight_reticle(
+    mut commands: Commands,
+    font_assets: Res<FontAssets>,
+) {
+    let line = |left: f32, top: f32, width: f32, height: f32| NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            left: Val::Percent(50.0),
+            top: Val::Percent(50.0),
+            margin: UiRect {
+                left: Val::Px(left),
+                top: Val::Px(top),
+                ..default()
+            },
+            width: Val::Px(width),
+            height: Val::Px(height),
+            ..default()
+        },
+        background_color: RETICLE_COLOR.into(),
+        ..default()
+    };
+    commands.spawn((
+        SightReticle,
+        NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                ..default()
+            },
+            visibility: Visibility::Hidden,
+            ..default()
+        },
+    ))
+    .with_children(|parent| {
+        parent.spawn(line(-200.0, -1.0, 180.0, 2.0));
+        parent.spawn(line(20.0, -1.0, 180.0, 2.0));
+        parent.spawn(line(-1.0, -150.0, 2.0, 130.0));
+        for distance in RANGE_MARKS {
+            parent.spawn((
+                RangeMark(distance),
+                NodeBundle {
+                    style: Style {
+                        position_type: PositionType::Absolute,
+                        left: Val::Percent(50.0),
+                        margin: UiRect::left(Val::Px(-10.0)),
+                        align_items: AlignItems::Center,
+                        ..default()
+                    },
+                    ..default()
+                },
+            ))
+            .with_children(|parent| {
+                parent.spawn(NodeBundle {
+                    style: Style {
+                        width: Val::Px(20.0),
+                        height: Val::Px(2.0),
+                        ..default()
+                    },
+                    background_color: RETICLE_COLOR.into(),
+                    ..default()
+                });
+                parent.spawn(TextBundle::from_section(
+                    format!("{:.0}", distance / 100.0),
+                    TextStyle {
+                        font: font_assets.menu_font.clone(),
+                        font_size: 14.0,
+                        color: RETICLE_COLOR,
+                    },
+                ).with_style(Style {
+                    margin: UiRect::left(Val::Px(4.0)),
+                    ..default()
+                }));
+            });
+        }
+    });
+}
+
+/// System to show the reticle while looking through the gunner sight and to place its range markings.
+/// A marking is as far below the center as the gun has to be elevated above the target to hit it at its range,
+/// depending on the muzzle velocity of the gun of the player and the field of view of the sight.
+/// In split-screen the reticle is placed over the part of the window of the first player looking through their sight.
+#[allow(clippy::type_complexity)]
+fn update_sight_reticle(
+    camera_query: Query<(&Camera, &Projection, &Zoom, &PlayerCamera)>,
+    player_query: Query<(&Player, &Children)>,
+    turret_query: Query<&Children, With<Turret>>,
+    gun_query: Query<&Gun>,
+    mut reticle_query: Query<(&mut Style, &mut Visibility), (With<SightReticle>, Without<RangeMark>)>,
+    mut mark_query: Query<(&RangeMark, &mut Style, &mut Visibility), Without<SightReticle>>,
+) {
+    let sight = camera_query.iter()
+        .filter(|(_, _, zoom, _)| zoom.sight)
+        .min_by_key(|(_, _, _, player_camera)| player_camera.0);
+    let rect = sight.and_then(|(camera, ..)| camera.logical_viewport_rect());
+    for (mut style, mut visibility) in reticle_query.iter_mut() {
+        *visibility = if rect.is_some() { Visibility::Visible } else { Visibility::Hidden };
+        if let Some(rect) = rect {
+            style.left = Val::Px(rect.min.x);
+            style.top = Val::Px(rect.min.y);
+            style.width = Val::Px(rect.width());
+            style.height = Val::Px(rect.height());
+        }
+    }
+    let (Some((_, Projection::Perspective(perspective), _, player_camera)), Some(rect)) = (sight, rect) else {
+        return;
+    };
+    let muzzle_velocity = player_query.iter()
+        .filter(|(player, _)| player.0 == player_camera.0)
+        .flat_map(|(_, children)| children.iter())
+        .filter_map(|child| turret_query.get(*child).ok())
+        .flat_map(|children| children.iter())
+        .find_map(|child| gun_query.get(*child).ok())
+        .map(|gun| gun.muzzle_velocity);
+    let half_height = rect.height() / 2.0;
+    let half_fov = (perspective.fov / 2.0).tan();
+    for (mark, mut style, mut visibility) in mark_query.iter_mut() {
+        let reach = muzzle_velocity.map(|v| GRAVITY.length() * mark.0 / (v * v));
+        let offset = match reach {
+            Some(reach) if reach <= 1.0 => (reach.asin() / 2.0).tan() / half_fov * half_height,
+            _ => f32::INFINITY,
+        };
+        if offset < half_height - 10.0 {
+            style.top = Val::Px(half_height + offset - 8.0);
+            *visibility = Visibility::Inherited;
+        } else {
+            *visibility = Visibility::Hidden;
+        }
+    }
+}
+
 /// System to display debug tank data.
-fn ui_example_system(mut contexts: EguiContexts, query: Query<(&Position, &Mass, &Rotation, &Velocity), With<Player>>) {
+fn ui_example_system(mut contexts: EguiContexts, query: Query<(&Position, &Mass, &Rotation, &Velocity, &Health), With<Player>>) {
     egui::Window::new("Debug Tank Data").show(contexts.ctx_mut(), |ui| {
-        for (position, mass, rotation, velocity) in query.iter() {
+        for (position, mass, rotation, velocity, health) in query.iter() {
             ui.label(format!("Position: {:.2?}", position.0));
             ui.label(format!("Mass: {:.2?}", mass.0));
             ui.label(format!("Rotation: {:.2?}", rotation.0));
             ui.label(format!("Velocity: {:.2?}", velocity.0.length()));
+            ui.label(format!("Health: {:.0} / {:.0}", health.current, health.max));
         }
     });
 }
\ No newline at end of file